@@ -4,28 +4,38 @@ use std::str::FromStr;
 use clap::{Clap, ValueHint};
 use cli_table::ColorChoice;
 
-use hyperqueue::client::commands::jobs::{cancel_job, output_job_detail, output_job_list};
+use hyperqueue::client::commands::attach::attach_job_log;
+use hyperqueue::client::commands::autoalloc::{command_autoalloc, AutoAllocOpts};
+use hyperqueue::client::commands::jobs::{
+    cancel_job, forget_job, get_last_job_id, output_job_detail, output_job_list,
+    output_job_progress,
+};
 use hyperqueue::client::commands::log::{command_log, LogOpts};
 use hyperqueue::client::commands::stats::print_server_stats;
 use hyperqueue::client::commands::stop::stop_server;
 use hyperqueue::client::commands::submit::{
-    resubmit_computation, submit_computation, ResubmitOpts, SubmitOpts,
+    requeue_computation, resubmit_computation, submit_computation, RequeueOpts, ResubmitOpts,
+    SubmitOpts,
 };
-use hyperqueue::client::commands::wait::wait_for_job_with_selector;
+use hyperqueue::client::commands::wait::{wait_for_job_tasks, wait_for_job_with_selector};
 use hyperqueue::client::commands::worker::{get_worker_info, get_worker_list, stop_worker};
 use hyperqueue::client::globalsettings::GlobalSettings;
+use hyperqueue::client::resources::ArgResourceRequest;
 use hyperqueue::client::status::Status;
-use hyperqueue::client::worker::print_worker_info;
+use hyperqueue::client::worker::{print_worker_detail, print_worker_info};
 use hyperqueue::common::arraydef::IntArray;
 use hyperqueue::common::fsutils::absolute_path;
+use hyperqueue::common::logging::LogFormat;
 use hyperqueue::common::setup::setup_logging;
 use hyperqueue::common::timeutils::ArgDuration;
 use hyperqueue::server::bootstrap::{
     get_client_connection, init_hq_server, print_server_info, ServerConfig,
 };
+use hyperqueue::server::history::JobHistoryRetention;
+use hyperqueue::server::snapshot::{SnapshotConfig, DEFAULT_SNAPSHOT_INTERVAL};
 use hyperqueue::transfer::messages::Selector;
+use hyperqueue::transfer::protocol::DEFAULT_MAX_MESSAGE_SIZE;
 use hyperqueue::worker::hwdetect::{detect_resource, print_resource_descriptor};
-use hyperqueue::worker::output::print_worker_configuration;
 use hyperqueue::worker::start::{start_hq_worker, WorkerStartOpts};
 use hyperqueue::WorkerId;
 
@@ -43,6 +53,12 @@ struct CommonOpts {
     /// Console color policy.
     #[clap(long, default_value = "auto", possible_values = & ["auto", "always", "never"])]
     colors: ColorPolicy,
+
+    /// Format of log messages.
+    /// The `json` format prints one JSON object per line, with job, task and worker ids of the
+    /// related task where applicable.
+    #[clap(long, global = true, default_value = "text", possible_values = & ["text", "json"])]
+    log_format: LogFormat,
 }
 
 // Root CLI options
@@ -71,14 +87,26 @@ enum SubCommand {
     Submit(SubmitOpts),
     /// Cancel a specific job
     Cancel(CancelOpts),
+    /// Remove completed (finished, failed or canceled) jobs from the memory of the server
+    Forget(ForgetOpts),
     /// Commands for controlling HyperQueue workers
     Worker(WorkerOpts),
     /// Resubmits all filtered tasks within a job
     Resubmit(ResubmitOpts),
+    /// Cancels a job and submits all of its tasks again as a new job
+    Requeue(RequeueOpts),
     /// Waits until a job is ended
     Wait(WaitOpts),
+    /// Prints the progress of a job as JSON.
+    /// Exits with 0 if the job has finished, 2 if it has not been completed yet
+    /// and 3 if it has failed or was canceled.
+    Progress(ProgressOpts),
+    /// Follows the output of all tasks of a job, each line is prefixed with its task id
+    AttachLog(AttachLogOpts),
     /// Operations with log
     Log(LogOpts),
+    /// Auto allocation management
+    Alloc(AutoAllocOpts),
 }
 
 // Server CLI options
@@ -96,6 +124,43 @@ struct ServerStartOpts {
     /// How often should the auto allocator perform its actions
     #[clap(long)]
     autoalloc_interval: Option<ArgDuration>,
+
+    /// Store completed jobs on disk, so that their details are available
+    /// even when they are no longer held by the server (e.g. after a restart)
+    #[clap(long)]
+    job_history: bool,
+
+    /// Maximum number of completed jobs kept in the job history (implies --job-history)
+    #[clap(long)]
+    job_history_max_count: Option<usize>,
+
+    /// Maximum age of completed jobs kept in the job history (implies --job-history)
+    #[clap(long)]
+    job_history_max_age: Option<ArgDuration>,
+
+    /// Resource request used for tasks that are submitted without `--cpus` or `--resources`,
+    /// e.g. `--default-resources="cpus=2"`
+    #[clap(long)]
+    default_resources: Option<ArgResourceRequest>,
+
+    /// Periodically append snapshots of jobs, workers and allocations into a SQLite database
+    /// at the given path, for offline analysis
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    snapshot_db: Option<PathBuf>,
+
+    /// How often should a snapshot be stored into `--snapshot-db` (default: 1 minute)
+    #[clap(long, requires("snapshot-db"))]
+    snapshot_interval: Option<ArgDuration>,
+
+    /// Maximum time limit of allocations created by allocation queues.
+    /// Queues with a larger time limit are rejected, queues without a time limit use this one.
+    #[clap(long)]
+    max_allocation_duration: Option<ArgDuration>,
+
+    /// Maximum size (in bytes) of a single message received from a client (default: 128 MiB).
+    /// Connections of clients that send a larger message are closed.
+    #[clap(long)]
+    max_message_size: Option<usize>,
 }
 
 #[derive(Clap)]
@@ -160,6 +225,24 @@ impl From<SelectorArg> for Selector {
 pub struct WaitOpts {
     /// Select job(s) to wait for
     selector_arg: SelectorArg,
+
+    /// Wait only for the selected tasks of a single job, e.g. `--task=42` or `--task=1-10`
+    #[clap(long)]
+    task: Option<IntArray>,
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct ProgressOpts {
+    /// Numeric job id or `last` to use the most recently submitted job
+    selector_arg: SelectorArg,
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct AttachLogOpts {
+    /// Numeric job id or `last` to use the most recently submitted job
+    selector_arg: SelectorArg,
 }
 
 // Worker CLI options
@@ -167,7 +250,11 @@ pub struct WaitOpts {
 #[clap(setting = clap::AppSettings::ColoredHelp)]
 struct WorkerStopOpts {
     /// Select worker(s) to stop
-    selector_arg: SelectorArg,
+    selector_arg: Option<SelectorArg>,
+
+    /// Stop all workers that are not running any tasks. Busy workers are not affected.
+    #[clap(long)]
+    all_idle: bool,
 }
 
 #[derive(Clap)]
@@ -180,6 +267,10 @@ struct WorkerListOpts {
     /// shows offline workers
     #[clap(long)]
     offline: bool,
+
+    /// shows all resources of workers (CPU ids of each socket)
+    #[clap(long)]
+    resources: bool,
 }
 
 #[derive(Clap)]
@@ -243,6 +334,17 @@ struct CancelOpts {
     selector_arg: SelectorArg,
 }
 
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct ForgetOpts {
+    /// Select job(s) to forget
+    selector_arg: Option<SelectorArg>,
+
+    /// Forget all completed jobs. Jobs that are waiting or running are not affected.
+    #[clap(long)]
+    all_finished: bool,
+}
+
 // Commands
 async fn command_server_start(
     gsettings: GlobalSettings,
@@ -254,6 +356,27 @@ async fn command_server_start(
             .unwrap_or_else(|| gethostname::gethostname().into_string().unwrap()),
         idle_timeout: opts.idle_timeout.map(|x| x.into_duration()),
         autoalloc_interval: opts.autoalloc_interval.map(|x| x.into_duration()),
+        job_history: if opts.job_history
+            || opts.job_history_max_count.is_some()
+            || opts.job_history_max_age.is_some()
+        {
+            Some(JobHistoryRetention {
+                max_count: opts.job_history_max_count,
+                max_age: opts.job_history_max_age.map(|x| x.into_duration()),
+            })
+        } else {
+            None
+        },
+        default_resources: opts.default_resources.map(|r| r.into_request()),
+        max_allocation_duration: opts.max_allocation_duration.map(|x| x.into_duration()),
+        snapshots: opts.snapshot_db.map(|path| SnapshotConfig {
+            path: absolute_path(path),
+            interval: opts
+                .snapshot_interval
+                .map(|x| x.into_duration())
+                .unwrap_or(DEFAULT_SNAPSHOT_INTERVAL),
+        }),
+        max_message_size: opts.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
     };
     init_hq_server(&gsettings, server_cfg).await
 }
@@ -316,6 +439,22 @@ async fn command_cancel(gsettings: GlobalSettings, opts: CancelOpts) -> anyhow::
         .map_err(|e| e.into())
 }
 
+async fn command_forget(gsettings: GlobalSettings, opts: ForgetOpts) -> anyhow::Result<()> {
+    let selector = match (opts.selector_arg, opts.all_finished) {
+        (Some(selector_arg), false) => selector_arg.into(),
+        (None, true) => Selector::All,
+        (Some(_), true) => {
+            anyhow::bail!("Job selector cannot be used together with --all-finished")
+        }
+        (None, false) => anyhow::bail!("Specify job(s) to forget or use --all-finished"),
+    };
+    let mut connection = get_client_connection(gsettings.server_directory()).await?;
+
+    forget_job(&gsettings, &mut connection, selector)
+        .await
+        .map_err(|e| e.into())
+}
+
 async fn command_worker_start(
     gsettings: GlobalSettings,
     opts: WorkerStartOpts,
@@ -329,7 +468,13 @@ async fn command_worker_stop(
 ) -> anyhow::Result<()> {
     let mut connection = get_client_connection(gsettings.server_directory()).await?;
 
-    stop_worker(&mut connection, opts.selector_arg.into()).await?;
+    let (selector, idle_only) = match (opts.selector_arg, opts.all_idle) {
+        (Some(selector_arg), false) => (selector_arg.into(), false),
+        (None, true) => (Selector::All, true),
+        (Some(_), true) => anyhow::bail!("Worker selector cannot be used together with --all-idle"),
+        (None, false) => anyhow::bail!("Specify worker(s) to stop or use --all-idle"),
+    };
+    stop_worker(&mut connection, selector, idle_only).await?;
     Ok(())
 }
 
@@ -346,7 +491,7 @@ async fn command_worker_list(
         (opts.running, opts.offline)
     };
     let workers = get_worker_list(&mut connection, online, offline).await?;
-    print_worker_info(workers, &gsettings);
+    print_worker_info(workers, &gsettings, opts.resources);
     Ok(())
 }
 
@@ -357,19 +502,29 @@ async fn command_worker_info(
     let mut connection = get_client_connection(gsettings.server_directory()).await?;
     let response = get_worker_info(&mut connection, opts.worker_id).await?;
 
-    if let Some(worker) = response {
-        print_worker_configuration(&gsettings, opts.worker_id, worker.configuration);
+    if let Some(response) = response {
+        print_worker_detail(&gsettings, response);
     } else {
         log::error!("Worker {} not found", opts.worker_id);
     }
     Ok(())
 }
 
+async fn command_alloc(gsettings: GlobalSettings, opts: AutoAllocOpts) -> anyhow::Result<()> {
+    let mut connection = get_client_connection(gsettings.server_directory()).await?;
+    command_autoalloc(&gsettings, &mut connection, opts).await
+}
+
 async fn command_resubmit(gsettings: GlobalSettings, opts: ResubmitOpts) -> anyhow::Result<()> {
     let mut connection = get_client_connection(gsettings.server_directory()).await?;
     resubmit_computation(&gsettings, &mut connection, opts).await
 }
 
+async fn command_requeue(gsettings: GlobalSettings, opts: RequeueOpts) -> anyhow::Result<()> {
+    let mut connection = get_client_connection(gsettings.server_directory()).await?;
+    requeue_computation(&gsettings, &mut connection, opts).await
+}
+
 fn command_worker_hwdetect() -> anyhow::Result<()> {
     let descriptor = detect_resource()?;
     print_resource_descriptor(&descriptor);
@@ -384,7 +539,7 @@ async fn command_worker_address(
     let response = get_worker_info(&mut connection, opts.worker_id).await?;
 
     match response {
-        Some(info) => println!("{}", info.configuration.hostname),
+        Some(response) => println!("{}", response.worker.configuration.hostname),
         None => anyhow::bail!("Worker {} not found", opts.worker_id),
     }
 
@@ -394,7 +549,50 @@ async fn command_worker_address(
 async fn command_wait(gsettings: GlobalSettings, opts: WaitOpts) -> anyhow::Result<()> {
     let mut connection = get_client_connection(gsettings.server_directory()).await?;
 
-    wait_for_job_with_selector(&mut connection, opts.selector_arg.into()).await
+    if let Some(tasks) = opts.task {
+        let job_id = match opts.selector_arg {
+            SelectorArg::Id(id) if id.id_count() == 1 => id.iter().next().unwrap(),
+            SelectorArg::Last => match get_last_job_id(&mut connection).await? {
+                Some(id) => id,
+                None => anyhow::bail!("No jobs were found"),
+            },
+            _ => anyhow::bail!("Option --task can be used only for a single job"),
+        };
+        return wait_for_job_tasks(&mut connection, job_id, tasks).await;
+    }
+    wait_for_job_with_selector(&gsettings, &mut connection, opts.selector_arg.into()).await
+}
+
+async fn command_progress(gsettings: GlobalSettings, opts: ProgressOpts) -> anyhow::Result<()> {
+    let mut connection = get_client_connection(gsettings.server_directory()).await?;
+
+    let job_id = match opts.selector_arg {
+        SelectorArg::Id(id) if id.id_count() == 1 => id.iter().next().unwrap(),
+        SelectorArg::Last => match get_last_job_id(&mut connection).await? {
+            Some(id) => id,
+            None => anyhow::bail!("No jobs were found"),
+        },
+        _ => anyhow::bail!("Progress can be displayed only for a single job"),
+    };
+    let exit_code = output_job_progress(&mut connection, job_id).await?;
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+    Ok(())
+}
+
+async fn command_attach_log(gsettings: GlobalSettings, opts: AttachLogOpts) -> anyhow::Result<()> {
+    let mut connection = get_client_connection(gsettings.server_directory()).await?;
+
+    let job_id = match opts.selector_arg {
+        SelectorArg::Id(id) if id.id_count() == 1 => id.iter().next().unwrap(),
+        SelectorArg::Last => match get_last_job_id(&mut connection).await? {
+            Some(id) => id,
+            None => anyhow::bail!("No jobs were found"),
+        },
+        _ => anyhow::bail!("Output can be followed only for a single job"),
+    };
+    attach_job_log(&mut connection, job_id).await
 }
 
 pub enum ColorPolicy {
@@ -454,7 +652,7 @@ fn set_colored_settings(settings: &GlobalSettings) {
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> hyperqueue::Result<()> {
     let top_opts: Opts = Opts::parse();
-    setup_logging();
+    setup_logging(top_opts.common.log_format);
 
     let gsettings = make_global_settings(top_opts.common);
     set_colored_settings(&gsettings);
@@ -492,9 +690,14 @@ async fn main() -> hyperqueue::Result<()> {
         SubCommand::Job(opts) => command_job_detail(gsettings, opts).await,
         SubCommand::Submit(opts) => command_submit(gsettings, opts).await,
         SubCommand::Cancel(opts) => command_cancel(gsettings, opts).await,
+        SubCommand::Forget(opts) => command_forget(gsettings, opts).await,
         SubCommand::Resubmit(opts) => command_resubmit(gsettings, opts).await,
+        SubCommand::Requeue(opts) => command_requeue(gsettings, opts).await,
         SubCommand::Wait(opts) => command_wait(gsettings, opts).await,
+        SubCommand::Progress(opts) => command_progress(gsettings, opts).await,
+        SubCommand::AttachLog(opts) => command_attach_log(gsettings, opts).await,
         SubCommand::Log(opts) => command_log(gsettings, opts),
+        SubCommand::Alloc(opts) => command_alloc(gsettings, opts).await,
     };
     if let Err(e) = result {
         eprintln!("{:?}", e);
@@ -0,0 +1,477 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use tako::messages::common::StdioDef;
+use tokio::time::sleep;
+
+use crate::client::commands::log::ShowOpts;
+use crate::common::arraydef::IntArray;
+use crate::server::job::JobTaskState;
+use crate::stream::reader::logfile::LogFile;
+use crate::transfer::connection::ClientConnection;
+use crate::transfer::messages::{
+    CancelRequest, FromClientMessage, JobDetail, JobDetailRequest, Selector, ToClientMessage,
+};
+use crate::{rpc_call, JobId, JobTaskId, Map, Set};
+
+const ATTACH_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How often is the state of all tasks of a job fetched when following the output of a job
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const EXIT_CODE_PREFIX: &str = "Program terminated with exit code ";
+
+/// Forwards data that are appended to a (possibly not yet existing) file
+struct FileFollower {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl FileFollower {
+    fn new(path: PathBuf) -> Self {
+        FileFollower { path, file: None }
+    }
+
+    fn forward<W: Write>(&mut self, output: &mut W) -> std::io::Result<()> {
+        if self.file.is_none() {
+            match File::open(&self.path) {
+                Ok(file) => self.file = Some(file),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        std::io::copy(self.file.as_mut().unwrap(), output)?;
+        output.flush()
+    }
+}
+
+/// Forwards lines appended to an output file of a task, each line is prefixed with the task id
+struct TaskOutputFollower {
+    follower: FileFollower,
+    prefix: String,
+    stderr: bool,
+    /// Data of the last line that is not terminated yet
+    pending: Vec<u8>,
+}
+
+impl TaskOutputFollower {
+    fn new(path: PathBuf, task_id: JobTaskId, stderr: bool) -> Self {
+        TaskOutputFollower {
+            follower: FileFollower::new(path),
+            prefix: format!("{}: ", task_id),
+            stderr,
+            pending: Vec::new(),
+        }
+    }
+
+    fn forward<W: Write>(&mut self, output: &mut W) -> std::io::Result<()> {
+        self.follower.forward(&mut self.pending)?;
+        if let Some(end) = self.pending.iter().rposition(|&c| c == b'\n') {
+            let rest = self.pending.split_off(end + 1);
+            let lines = std::mem::replace(&mut self.pending, rest);
+            write_prefixed_lines(output, &self.prefix, &lines)?;
+        }
+        Ok(())
+    }
+
+    /// Forwards the rest of the output, including a last line that is not terminated
+    fn finish<W: Write>(mut self, output: &mut W) -> std::io::Result<()> {
+        self.forward(output)?;
+        if !self.pending.is_empty() {
+            self.pending.push(b'\n');
+            write_prefixed_lines(output, &self.prefix, &self.pending)?;
+        }
+        Ok(())
+    }
+
+    fn forward_to_terminal(&mut self) -> std::io::Result<()> {
+        if self.stderr {
+            self.forward(&mut std::io::stderr())
+        } else {
+            self.forward(&mut std::io::stdout())
+        }
+    }
+
+    fn finish_to_terminal(self) -> std::io::Result<()> {
+        if self.stderr {
+            self.finish(&mut std::io::stderr())
+        } else {
+            self.finish(&mut std::io::stdout())
+        }
+    }
+}
+
+fn write_prefixed_lines<W: Write>(
+    output: &mut W,
+    prefix: &str,
+    data: &[u8],
+) -> std::io::Result<()> {
+    for line in data.split_inclusive(|&c| c == b'\n') {
+        output.write_all(prefix.as_bytes())?;
+        output.write_all(line)?;
+    }
+    output.flush()
+}
+
+/// Resolves placeholders in an output path of a task in the same way as the worker does.
+/// Returns `None` if the path contains placeholders that are known only to the worker.
+fn resolve_task_path(job: &JobDetail, task_id: JobTaskId, path: &Path) -> Option<PathBuf> {
+    let job_id = job.info.id.to_string();
+    let task_id = task_id.to_string();
+    let submit_dir = job.submit_dir.to_string_lossy();
+    let replace = |path: &Path| -> String {
+        path.to_string_lossy()
+            .replace("%{JOB_ID}", &job_id)
+            .replace("%{TASK_ID}", &task_id)
+            .replace("%{SUBMIT_DIR}", &submit_dir)
+    };
+    let cwd = job
+        .submit_dir
+        .join(replace(job.program_def.cwd.as_deref()?));
+    let path = replace(path).replace("%{CWD}", &cwd.to_string_lossy());
+    if path.contains("%{") {
+        return None;
+    }
+    Some(job.submit_dir.join(path))
+}
+
+fn create_task_followers(
+    job: &JobDetail,
+    task_id: JobTaskId,
+) -> anyhow::Result<Vec<TaskOutputFollower>> {
+    let mut outputs = vec![(&job.program_def.stdout, false)];
+    if !job.merge_stderr_into_stdout {
+        outputs.push((&job.program_def.stderr, true));
+    }
+
+    let mut followers = Vec::new();
+    for (stdio, stderr) in outputs {
+        match stdio {
+            StdioDef::File(path) => match resolve_task_path(job, task_id, path) {
+                Some(path) => followers.push(TaskOutputFollower::new(path, task_id, stderr)),
+                None => bail!(
+                    "Output path {:?} of job {} cannot be resolved outside of the worker",
+                    path,
+                    job.info.id
+                ),
+            },
+            StdioDef::Pipe | StdioDef::Null => {}
+        }
+    }
+    Ok(followers)
+}
+
+/// Extracts the exit code of a task from its error message
+fn parse_exit_code(error: &str) -> Option<i32> {
+    error
+        .strip_prefix(EXIT_CODE_PREFIX)
+        .and_then(|code| code.parse().ok())
+}
+
+async fn get_job_detail(
+    connection: &mut ClientConnection,
+    job_id: JobId,
+) -> anyhow::Result<JobDetail> {
+    let response = rpc_call!(
+        connection,
+        FromClientMessage::JobDetail(JobDetailRequest {
+            selector: Selector::Specific(IntArray::from_ids(vec![job_id])),
+            include_tasks: true,
+        }),
+        ToClientMessage::JobDetailResponse(r) => r
+    )
+    .await?;
+
+    match response.into_iter().next().and_then(|(_, detail)| detail) {
+        Some(detail) => Ok(detail),
+        None => bail!("Job {} not found", job_id),
+    }
+}
+
+async fn get_task_state(
+    connection: &mut ClientConnection,
+    job_id: JobId,
+) -> anyhow::Result<JobTaskState> {
+    match get_job_detail(connection, job_id)
+        .await?
+        .tasks
+        .into_iter()
+        .next()
+    {
+        Some(task) => Ok(task.state),
+        None => bail!("Job {} not found", job_id),
+    }
+}
+
+/// Streams the output of a single task job to the terminal until the task ends.
+/// Ctrl-C cancels the job. If the task fails, the process exits with the exit code of the task.
+pub async fn attach_to_job(
+    connection: &mut ClientConnection,
+    job_id: JobId,
+    stdout: Option<PathBuf>,
+    stderr: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let mut stdout = stdout.map(FileFollower::new);
+    let mut stderr = stderr.map(FileFollower::new);
+    let mut forward_outputs = || -> std::io::Result<()> {
+        if let Some(follower) = stdout.as_mut() {
+            follower.forward(&mut std::io::stdout())?;
+        }
+        if let Some(follower) = stderr.as_mut() {
+            follower.forward(&mut std::io::stderr())?;
+        }
+        Ok(())
+    };
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut canceled = false;
+
+    let state = loop {
+        let state = get_task_state(connection, job_id).await?;
+        forward_outputs()?;
+
+        if !matches!(state, JobTaskState::Waiting | JobTaskState::Running { .. }) {
+            break state;
+        }
+
+        tokio::select! {
+            _ = &mut ctrl_c, if !canceled => {
+                canceled = true;
+                log::info!("Canceling job {}", job_id);
+                rpc_call!(
+                    connection,
+                    FromClientMessage::Cancel(CancelRequest {
+                        selector: Selector::Specific(IntArray::from_ids(vec![job_id])),
+                    }),
+                    ToClientMessage::CancelJobResponse(r) => r
+                )
+                .await?;
+            }
+            _ = sleep(ATTACH_POLL_INTERVAL) => {}
+        }
+    };
+
+    match state {
+        JobTaskState::Failed { error, .. } => match parse_exit_code(&error) {
+            Some(code) => {
+                log::error!("Job {} failed: {}", job_id, error);
+                std::process::exit(code);
+            }
+            None => bail!("Job {} failed: {}", job_id, error),
+        },
+        JobTaskState::Canceled => bail!("Job {} was canceled", job_id),
+        _ => Ok(()),
+    }
+}
+
+fn is_job_active(job: &JobDetail) -> bool {
+    job.tasks.iter().any(|task| {
+        matches!(
+            task.state,
+            JobTaskState::Waiting | JobTaskState::Running { .. }
+        )
+    })
+}
+
+/// Streams the output of all tasks of a job to the terminal until all tasks end.
+/// Each line is prefixed with the id of the task that has produced it.
+/// Ctrl-C stops following the output, the job itself is not affected.
+pub async fn attach_job_log(
+    connection: &mut ClientConnection,
+    job_id: JobId,
+) -> anyhow::Result<()> {
+    let job = get_job_detail(connection, job_id).await?;
+    let job = match job.log.clone() {
+        Some(log) => follow_job_log_file(connection, job, log).await?,
+        None => follow_task_outputs(connection, job).await?,
+    };
+    let job = match job {
+        Some(job) => job,
+        None => return Ok(()),
+    };
+
+    let failed_count = job.info.counters.n_failed_tasks;
+    if failed_count > 0 {
+        bail!("{} task(s) of job {} failed", failed_count, job_id);
+    }
+    Ok(())
+}
+
+/// Creates followers for tasks that have started, forwards their output and finishes followers
+/// of tasks that have ended. Returns `false` when all tasks of the job have ended.
+fn update_task_followers(
+    job: &JobDetail,
+    followers: &mut Map<JobTaskId, Vec<TaskOutputFollower>>,
+    ended: &mut Set<JobTaskId>,
+) -> anyhow::Result<bool> {
+    let mut active = false;
+    for task in &job.tasks {
+        if ended.contains(&task.task_id) {
+            continue;
+        }
+        let is_ended = match task.state {
+            JobTaskState::Waiting => {
+                active = true;
+                continue;
+            }
+            JobTaskState::Running { .. } => false,
+            JobTaskState::Finished { .. }
+            | JobTaskState::Failed { .. }
+            | JobTaskState::Canceled => true,
+        };
+        let mut task_followers = match followers.remove(&task.task_id) {
+            Some(task_followers) => task_followers,
+            None => create_task_followers(job, task.task_id)?,
+        };
+        if is_ended {
+            for follower in task_followers {
+                follower.finish_to_terminal()?;
+            }
+            ended.insert(task.task_id);
+        } else {
+            active = true;
+            for follower in task_followers.iter_mut() {
+                follower.forward_to_terminal()?;
+            }
+            followers.insert(task.task_id, task_followers);
+        }
+    }
+    Ok(active)
+}
+
+/// Follows the output files of tasks.
+/// The files are checked every [`ATTACH_POLL_INTERVAL`], while the (larger) state of the job
+/// is fetched from the server only every [`JOB_POLL_INTERVAL`].
+/// Returns the final state of the job or `None` if the following was stopped by Ctrl-C.
+async fn follow_task_outputs(
+    connection: &mut ClientConnection,
+    mut job: JobDetail,
+) -> anyhow::Result<Option<JobDetail>> {
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    let mut followers: Map<JobTaskId, Vec<TaskOutputFollower>> = Map::new();
+    let mut ended: Set<JobTaskId> = Set::new();
+    let mut last_poll = Instant::now();
+
+    while update_task_followers(&job, &mut followers, &mut ended)? {
+        loop {
+            tokio::select! {
+                _ = &mut ctrl_c => return Ok(None),
+                _ = sleep(ATTACH_POLL_INTERVAL) => {}
+            }
+            if last_poll.elapsed() >= JOB_POLL_INTERVAL {
+                break;
+            }
+            for follower in followers.values_mut().flatten() {
+                follower.forward_to_terminal()?;
+            }
+        }
+        job = get_job_detail(connection, job.info.id).await?;
+        last_poll = Instant::now();
+    }
+    Ok(Some(job))
+}
+
+/// Follows the output of tasks that is streamed into a log file.
+/// The log is read in a blocking task until all tasks of the job end and their streams are closed.
+/// Returns the final state of the job or `None` if the following was stopped by Ctrl-C.
+async fn follow_job_log_file(
+    connection: &mut ClientConnection,
+    mut job: JobDetail,
+    log: PathBuf,
+) -> anyhow::Result<Option<JobDetail>> {
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    let opts = ShowOpts {
+        channel: None,
+        show_empty: false,
+        task: Some(IntArray::from_ids(
+            job.tasks.iter().map(|task| task.task_id).collect(),
+        )),
+        follow: true,
+    };
+    let job_active = Arc::new(AtomicBool::new(is_job_active(&job)));
+    let stopped = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let job_active = job_active.clone();
+        let stopped = stopped.clone();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let mut log_file = LogFile::open(&log)?;
+            log_file.show_while(&opts, |has_open_streams| {
+                !stopped.load(Ordering::SeqCst)
+                    && (has_open_streams || job_active.load(Ordering::SeqCst))
+            })
+        })
+    };
+    tokio::pin!(reader);
+
+    while job_active.load(Ordering::SeqCst) {
+        tokio::select! {
+            _ = &mut ctrl_c => {
+                stopped.store(true, Ordering::SeqCst);
+                reader.await??;
+                return Ok(None);
+            }
+            // The reader keeps following the log while the job is active, it ends only on error
+            result = &mut reader => {
+                result??;
+                return Ok(Some(job));
+            }
+            _ = sleep(JOB_POLL_INTERVAL) => {
+                job = get_job_detail(connection, job.info.id).await?;
+                job_active.store(is_job_active(&job), Ordering::SeqCst);
+            }
+        }
+    }
+    reader.await??;
+    Ok(Some(job))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempdir::TempDir;
+
+    use crate::client::commands::attach::{parse_exit_code, TaskOutputFollower};
+
+    #[test]
+    fn test_prefix_task_output_lines() {
+        let dir = TempDir::new("hq").unwrap();
+        let path = dir.path().join("stdout");
+        let mut follower = TaskOutputFollower::new(path.clone(), 3, false);
+        let mut output = Vec::new();
+
+        // The file does not exist yet
+        follower.forward(&mut output).unwrap();
+        assert!(output.is_empty());
+
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(b"a\nb\nc").unwrap();
+        follower.forward(&mut output).unwrap();
+        assert_eq!(output, b"3: a\n3: b\n");
+
+        file.write_all(b"d\ne").unwrap();
+        follower.finish(&mut output).unwrap();
+        assert_eq!(output, b"3: a\n3: b\n3: cd\n3: e\n");
+    }
+
+    #[test]
+    fn test_parse_exit_code() {
+        assert_eq!(
+            parse_exit_code("Program terminated with exit code 3"),
+            Some(3)
+        );
+        assert_eq!(
+            parse_exit_code("Program terminated with exit code -1"),
+            Some(-1)
+        );
+        assert_eq!(parse_exit_code("Time limit reached"), None);
+    }
+}
@@ -0,0 +1,655 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, SubsecRound, Utc};
+use clap::Clap;
+use cli_table::format::Justify;
+use cli_table::{print_stdout, Cell, CellStruct, Color, Style, Table};
+use serde::Serialize;
+
+use crate::client::globalsettings::GlobalSettings;
+use crate::common::manager::info::ManagerType;
+use crate::common::timeutils::{ArgDuration, TimeWindow};
+use crate::rpc_call;
+use crate::transfer::connection::ClientConnection;
+use crate::transfer::messages::{
+    AddQueueParams, AllocationInfo, AllocationStatusInfo, AutoAllocRequest, AutoAllocResponse,
+    FromClientMessage, QueueStateInfo, ToClientMessage, UpdateQueueParams,
+};
+use crate::Map;
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct AutoAllocOpts {
+    #[clap(subcommand)]
+    subcmd: AutoAllocCommand,
+}
+
+#[derive(Clap)]
+enum AutoAllocCommand {
+    /// Display allocations of the specified allocation queue
+    Info(AllocationInfoOpts),
+    /// Create a new allocation queue
+    Add(AddQueueOpts),
+    /// Change the number of workers of an existing allocation queue.
+    /// Existing allocations are not affected.
+    Update(UpdateQueueOpts),
+    /// Stop creating new allocations of an allocation queue.
+    /// Existing allocations are kept and their state is still tracked.
+    Pause(PauseQueueOpts),
+    /// Resume creating new allocations of a paused allocation queue
+    Resume(PauseQueueOpts),
+    /// Cancel a single allocation of an allocation queue
+    Cancel(CancelAllocationOpts),
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct AddQueueOpts {
+    #[clap(subcommand)]
+    subcmd: AddQueueCommand,
+}
+
+#[derive(Clap)]
+enum AddQueueCommand {
+    /// Create an allocation queue that submits allocations into PBS
+    Pbs(SharedQueueOpts),
+    /// Create an allocation queue that submits allocations into Slurm
+    Slurm(SharedQueueOpts),
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct SharedQueueOpts {
+    /// Name of the allocation queue
+    #[clap(long)]
+    name: String,
+
+    /// PBS queue or Slurm partition into which the allocations will be submitted
+    #[clap(long, alias = "partition")]
+    queue: String,
+
+    /// How many workers should be kept active (queued or running)
+    #[clap(long, default_value = "1")]
+    workers: u64,
+
+    /// Maximum number of workers (nodes) requested by a single allocation
+    #[clap(long, default_value = "1")]
+    max_workers_per_alloc: u64,
+
+    /// Minimum number of workers (nodes) requested by a single allocation.
+    /// New allocations are sized by the number of waiting tasks, clamped between
+    /// `--min-workers-per-alloc` and `--max-workers-per-alloc`.
+    /// Defaults to `--max-workers-per-alloc`, i.e. allocations of a fixed size.
+    #[clap(long)]
+    min_workers_per_alloc: Option<u64>,
+
+    /// Time limit (walltime) of each allocation
+    #[clap(long)]
+    time_limit: Option<ArgDuration>,
+
+    /// Create new allocations only during this (local) time window, e.g. `22:00-06:00`
+    #[clap(long)]
+    active_window: Option<TimeWindow>,
+
+    /// Set an environment variable for the workers started by this queue, e.g.
+    /// `--worker-env=LD_LIBRARY_PATH=/opt/gpu/lib:$LD_LIBRARY_PATH`.
+    /// The value is expanded by the shell of the allocation.
+    /// You can pass this flag multiple times to pass multiple variables.
+    #[clap(long, multiple_occurrences(true))]
+    worker_env: Vec<ArgWorkerEnv>,
+
+    /// Resources requested for each node of an allocation, in the syntax of the job manager.
+    /// PBS: appended to the `select` chunk, e.g. `--node-resources=ngpus=4:mem=64gb`.
+    /// Slurm: `sbatch` options, e.g. `--node-resources="--gpus-per-node=4 --mem=64G"`.
+    #[clap(long, allow_hyphen_values = true)]
+    node_resources: Option<String>,
+
+    /// How long can an allocation run without any of its workers connecting to the server.
+    /// Allocations that exceed this timeout are reported in `hq alloc info`.
+    /// Use it for workers that take a long time to start (e.g. because of container pulls).
+    #[clap(long)]
+    start_cmd_timeout: Option<ArgDuration>,
+
+    /// Cancel allocations whose workers have not connected within `--start-cmd-timeout`
+    #[clap(long)]
+    cancel_on_start_timeout: bool,
+
+    /// Workers started by the queue stop after being idle (without tasks) for this duration,
+    /// so that their allocation is released. It has to be shorter than the time limit.
+    #[clap(long)]
+    idle_timeout: Option<ArgDuration>,
+
+    /// Append events of the queue (submitted, finished or failed allocations) into this file,
+    /// one JSON object per line
+    #[clap(long)]
+    event_log: Option<PathBuf>,
+
+    /// Print the command and the script that would be used to submit an allocation of the queue
+    /// and exit without creating the queue
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Additional arguments passed to `qsub`/`sbatch`
+    #[clap(last = true)]
+    additional_args: Vec<String>,
+}
+
+/// Environment variable of workers in the form `KEY=VALUE`
+struct ArgWorkerEnv {
+    key: String,
+    value: String,
+}
+
+impl FromStr for ArgWorkerEnv {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = match s.find('=') {
+            Some(position) => (&s[..position], &s[position + 1..]),
+            None => anyhow::bail!("Worker environment variable has to be in the form KEY=VALUE"),
+        };
+        let valid_key = key.chars().enumerate().all(|(index, c)| {
+            c == '_' || c.is_ascii_alphabetic() || (index > 0 && c.is_ascii_digit())
+        });
+        if key.is_empty() || !valid_key {
+            anyhow::bail!("Invalid name of environment variable: {}", key);
+        }
+        Ok(ArgWorkerEnv {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct UpdateQueueOpts {
+    /// Name of the allocation queue
+    name: String,
+
+    /// How many workers should be kept active (queued or running)
+    #[clap(long)]
+    workers: Option<u64>,
+
+    /// Maximum number of workers (nodes) requested by a single allocation
+    #[clap(long)]
+    max_workers_per_alloc: Option<u64>,
+
+    /// Minimum number of workers (nodes) requested by a single allocation
+    #[clap(long)]
+    min_workers_per_alloc: Option<u64>,
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct PauseQueueOpts {
+    /// Name of the allocation queue
+    name: String,
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct CancelAllocationOpts {
+    /// Name of the allocation queue
+    descriptor: String,
+
+    /// Id of the allocation that should be canceled
+    allocation: String,
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct AllocationInfoOpts {
+    /// Name of the allocation queue
+    descriptor: Option<String>,
+
+    /// Display allocations of all allocation queues
+    #[clap(long)]
+    all: bool,
+
+    /// Periodically refresh the table until interrupted by Ctrl-C.
+    /// Allocations that have changed since the previous refresh are highlighted.
+    #[clap(long)]
+    watch: bool,
+
+    /// How often should the table be refreshed in the watch mode
+    #[clap(long, default_value = "2s")]
+    interval: ArgDuration,
+
+    /// Display only the allocation with the given id
+    #[clap(long)]
+    allocation: Option<String>,
+
+    /// Print the script that was submitted to create the allocation selected by `--allocation`
+    #[clap(long)]
+    show_script: bool,
+
+    /// Print the allocations as a JSON array instead of a table
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Serialize)]
+struct AllocationJson {
+    queue: String,
+    id: String,
+    worker_count: u64,
+    state: &'static str,
+    queued_at: DateTime<Utc>,
+    started_at: Option<DateTime<Utc>>,
+    worker_connected: bool,
+    start_timeout_exceeded: bool,
+    working_dir: PathBuf,
+}
+
+impl From<AllocationInfo> for AllocationJson {
+    fn from(allocation: AllocationInfo) -> Self {
+        let (state, queued_at, started_at, worker_connected, start_timeout_exceeded) =
+            match allocation.status {
+                AllocationStatusInfo::Queued { queued_at } => {
+                    ("queued", queued_at, None, false, false)
+                }
+                AllocationStatusInfo::Running {
+                    queued_at,
+                    started_at,
+                    worker_connected,
+                    start_timeout_exceeded,
+                } => (
+                    "running",
+                    queued_at,
+                    Some(started_at),
+                    worker_connected,
+                    start_timeout_exceeded,
+                ),
+            };
+        AllocationJson {
+            queue: allocation.queue,
+            id: allocation.id,
+            worker_count: allocation.worker_count,
+            state,
+            queued_at,
+            started_at,
+            worker_connected,
+            start_timeout_exceeded,
+            working_dir: allocation.working_dir,
+        }
+    }
+}
+
+pub async fn command_autoalloc(
+    gsettings: &GlobalSettings,
+    connection: &mut ClientConnection,
+    opts: AutoAllocOpts,
+) -> anyhow::Result<()> {
+    match opts.subcmd {
+        AutoAllocCommand::Info(opts) => print_allocations(gsettings, connection, opts).await,
+        AutoAllocCommand::Add(opts) => add_queue(connection, opts).await,
+        AutoAllocCommand::Update(opts) => update_queue(connection, opts).await,
+        AutoAllocCommand::Pause(opts) => pause_queue(connection, opts.name, true).await,
+        AutoAllocCommand::Resume(opts) => pause_queue(connection, opts.name, false).await,
+        AutoAllocCommand::Cancel(opts) => cancel_allocation(connection, opts).await,
+    }
+}
+
+async fn add_queue(connection: &mut ClientConnection, opts: AddQueueOpts) -> anyhow::Result<()> {
+    let (manager, opts) = match opts.subcmd {
+        AddQueueCommand::Pbs(opts) => (ManagerType::Pbs, opts),
+        AddQueueCommand::Slurm(opts) => (ManagerType::Slurm, opts),
+    };
+    if opts.max_workers_per_alloc == 0 {
+        anyhow::bail!("--max-workers-per-alloc has to be at least 1");
+    }
+    let min_workers_per_alloc = opts
+        .min_workers_per_alloc
+        .unwrap_or(opts.max_workers_per_alloc);
+    if min_workers_per_alloc == 0 || min_workers_per_alloc > opts.max_workers_per_alloc {
+        anyhow::bail!("--min-workers-per-alloc has to be between 1 and --max-workers-per-alloc");
+    }
+    if let (ManagerType::Pbs, Some(resources)) = (&manager, &opts.node_resources) {
+        if resources.contains(char::is_whitespace) {
+            anyhow::bail!("PBS node resources cannot contain whitespace, separate them by `:`");
+        }
+    }
+    if opts.cancel_on_start_timeout && opts.start_cmd_timeout.is_none() {
+        anyhow::bail!("--cancel-on-start-timeout requires --start-cmd-timeout");
+    }
+
+    let dry_run = opts.dry_run;
+    let params = AddQueueParams {
+        manager,
+        name: opts.name,
+        queue: opts.queue,
+        target_worker_count: opts.workers,
+        min_workers_per_alloc,
+        max_workers_per_alloc: opts.max_workers_per_alloc,
+        timelimit: opts.time_limit.map(|duration| duration.into_duration()),
+        additional_args: opts.additional_args,
+        active_window: opts.active_window,
+        worker_env: opts
+            .worker_env
+            .into_iter()
+            .map(|env| (env.key, env.value))
+            .collect(),
+        node_resources: opts.node_resources,
+        worker_start_timeout: opts
+            .start_cmd_timeout
+            .map(|duration| duration.into_duration()),
+        cancel_on_start_timeout: opts.cancel_on_start_timeout,
+        idle_timeout: opts.idle_timeout.map(|duration| duration.into_duration()),
+        // The path is resolved by the server, which may run in a different directory
+        event_log: opts
+            .event_log
+            .map(|path| std::env::current_dir().map(|cwd| cwd.join(path)))
+            .transpose()?,
+    };
+    if dry_run {
+        return print_allocation_preview(connection, params).await;
+    }
+
+    let message = FromClientMessage::AutoAlloc(AutoAllocRequest::AddQueue(params));
+    let name = rpc_call!(connection, message,
+        ToClientMessage::AutoAllocResponse(AutoAllocResponse::QueueCreated(name)) => name
+    )
+    .await?;
+    log::info!("Allocation queue {} was created", name);
+    Ok(())
+}
+
+/// Prints the command and the script that would submit the largest allocation of the queue.
+/// The parameters are checked by the server, but the allocation is not submitted and the queue
+/// is not created.
+async fn print_allocation_preview(
+    connection: &mut ClientConnection,
+    params: AddQueueParams,
+) -> anyhow::Result<()> {
+    let message = FromClientMessage::AutoAlloc(AutoAllocRequest::DryRun(params));
+    let (command, script) = rpc_call!(connection, message,
+        ToClientMessage::AutoAllocResponse(AutoAllocResponse::DryRun { command, script }) =>
+            (command, script)
+    )
+    .await?;
+    println!("Submit command:\n{}\n", command);
+    print!("Submit script:\n{}", script);
+    Ok(())
+}
+
+async fn update_queue(
+    connection: &mut ClientConnection,
+    opts: UpdateQueueOpts,
+) -> anyhow::Result<()> {
+    if opts.workers.is_none()
+        && opts.min_workers_per_alloc.is_none()
+        && opts.max_workers_per_alloc.is_none()
+    {
+        anyhow::bail!(
+            "Specify at least one of --workers, --min-workers-per-alloc or --max-workers-per-alloc"
+        );
+    }
+
+    let message = FromClientMessage::AutoAlloc(AutoAllocRequest::UpdateQueue(UpdateQueueParams {
+        name: opts.name,
+        target_worker_count: opts.workers,
+        min_workers_per_alloc: opts.min_workers_per_alloc,
+        max_workers_per_alloc: opts.max_workers_per_alloc,
+    }));
+    let name = rpc_call!(connection, message,
+        ToClientMessage::AutoAllocResponse(AutoAllocResponse::QueueUpdated(name)) => name
+    )
+    .await?;
+    log::info!("Allocation queue {} was updated", name);
+    Ok(())
+}
+
+async fn pause_queue(
+    connection: &mut ClientConnection,
+    name: String,
+    paused: bool,
+) -> anyhow::Result<()> {
+    let message = FromClientMessage::AutoAlloc(AutoAllocRequest::PauseQueue {
+        descriptor: name,
+        paused,
+    });
+    let name = rpc_call!(connection, message,
+        ToClientMessage::AutoAllocResponse(AutoAllocResponse::QueueUpdated(name)) => name
+    )
+    .await?;
+    if paused {
+        log::info!("Allocation queue {} was paused", name);
+    } else {
+        log::info!("Allocation queue {} was resumed", name);
+    }
+    Ok(())
+}
+
+async fn cancel_allocation(
+    connection: &mut ClientConnection,
+    opts: CancelAllocationOpts,
+) -> anyhow::Result<()> {
+    let message = FromClientMessage::AutoAlloc(AutoAllocRequest::CancelAllocation {
+        descriptor: opts.descriptor,
+        allocation_id: opts.allocation.clone(),
+    });
+    rpc_call!(
+        connection,
+        message,
+        ToClientMessage::AutoAllocResponse(AutoAllocResponse::AllocationCanceled)
+    )
+    .await?;
+    log::info!("Allocation {} was canceled", opts.allocation);
+    Ok(())
+}
+
+async fn print_submit_script(
+    connection: &mut ClientConnection,
+    descriptor: String,
+    allocation_id: String,
+) -> anyhow::Result<()> {
+    let message = FromClientMessage::AutoAlloc(AutoAllocRequest::SubmitScript {
+        descriptor,
+        allocation_id,
+    });
+    let script = rpc_call!(connection, message,
+        ToClientMessage::AutoAllocResponse(AutoAllocResponse::SubmitScript(script)) => script
+    )
+    .await?;
+    print!("{}", script);
+    Ok(())
+}
+
+async fn get_allocations(
+    connection: &mut ClientConnection,
+    descriptor: Option<String>,
+) -> crate::Result<(Vec<AllocationInfo>, Vec<QueueStateInfo>)> {
+    let message = FromClientMessage::AutoAlloc(AutoAllocRequest::Info { descriptor });
+    let response = rpc_call!(connection, message,
+        ToClientMessage::AutoAllocResponse(AutoAllocResponse::Info { allocations, queues }) =>
+            (allocations, queues)
+    )
+    .await?;
+    Ok(response)
+}
+
+async fn print_allocations(
+    gsettings: &GlobalSettings,
+    connection: &mut ClientConnection,
+    opts: AllocationInfoOpts,
+) -> anyhow::Result<()> {
+    match (&opts.descriptor, opts.all) {
+        (Some(_), true) => anyhow::bail!("A queue name cannot be combined with --all"),
+        (None, false) => anyhow::bail!("Specify a queue name or use --all"),
+        _ => {}
+    }
+    if opts.show_script {
+        return match (opts.descriptor, opts.allocation) {
+            (Some(descriptor), Some(allocation)) => {
+                print_submit_script(connection, descriptor, allocation).await
+            }
+            (None, _) => anyhow::bail!("--show-script requires a queue name"),
+            (_, None) => anyhow::bail!("--show-script requires --allocation"),
+        };
+    }
+
+    let selected_allocation = opts.allocation;
+    let filter = |mut allocations: Vec<AllocationInfo>| {
+        if let Some(id) = &selected_allocation {
+            allocations.retain(|allocation| &allocation.id == id);
+        }
+        allocations
+    };
+
+    if opts.json {
+        if opts.watch {
+            anyhow::bail!("--json cannot be combined with --watch");
+        }
+        let (allocations, _) = get_allocations(connection, opts.descriptor.clone()).await?;
+        let mut allocations = filter(allocations);
+        allocations.sort_unstable_by(|a, b| (&a.queue, &a.id).cmp(&(&b.queue, &b.id)));
+        let allocations: Vec<AllocationJson> = allocations.into_iter().map(|a| a.into()).collect();
+        println!("{}", serde_json::to_string(&allocations)?);
+        return Ok(());
+    }
+
+    if !opts.watch {
+        let (allocations, queues) = get_allocations(connection, opts.descriptor.clone()).await?;
+        print_allocation_table(gsettings, filter(allocations), None, opts.all);
+        print_queue_states(&queues);
+        return Ok(());
+    }
+
+    let interval: Duration = opts.interval.into_duration();
+    let mut previous: Option<Map<String, AllocationInfo>> = None;
+    loop {
+        let (allocations, queues) = get_allocations(connection, opts.descriptor.clone()).await?;
+        let allocations = filter(allocations);
+
+        // \x1b[2J clears the screen, \x1b[H moves the cursor to the top left corner
+        print!("\x1b[2J\x1b[H");
+        println!(
+            "Allocations of {} (refreshing every {})",
+            opts.descriptor.as_deref().unwrap_or("all queues"),
+            humantime::format_duration(interval)
+        );
+        print_allocation_table(gsettings, allocations.clone(), previous.as_ref(), opts.all);
+        print_queue_states(&queues);
+
+        previous = Some(
+            allocations
+                .into_iter()
+                .map(|allocation| (allocation.id.clone(), allocation))
+                .collect(),
+        );
+        connection.sleep_with_keepalive(interval).await?;
+    }
+}
+
+/// Prints queues that do not submit new allocations because they are paused or because
+/// their previous submissions have failed
+fn print_queue_states(queues: &[QueueStateInfo]) {
+    for queue in queues {
+        if queue.paused {
+            println!("Queue {} is paused", queue.name);
+        }
+        if let Some(next) = queue.next_submission_at {
+            println!(
+                "Queue {}: {} failed submission(s) in a row, next attempt at {}",
+                queue.name,
+                queue.failed_submissions,
+                next.round_subsecs(0)
+            );
+        }
+    }
+}
+
+/// Returns true if the allocation is new or its status has changed since the previous state
+fn allocation_has_changed(
+    allocation: &AllocationInfo,
+    previous: Option<&Map<String, AllocationInfo>>,
+) -> bool {
+    match previous {
+        Some(previous) => match previous.get(&allocation.id) {
+            Some(old) => {
+                std::mem::discriminant(&old.status) != std::mem::discriminant(&allocation.status)
+            }
+            None => true,
+        },
+        None => false,
+    }
+}
+
+fn allocation_status(status: &AllocationStatusInfo) -> (CellStruct, String) {
+    match status {
+        AllocationStatusInfo::Queued { queued_at } => (
+            "QUEUED".cell().foreground_color(Some(Color::Yellow)),
+            queued_at.round_subsecs(0).to_string(),
+        ),
+        AllocationStatusInfo::Running {
+            started_at,
+            worker_connected,
+            start_timeout_exceeded,
+            ..
+        } => {
+            let state = if *start_timeout_exceeded {
+                "RUNNING (NO WORKER CONNECTED)"
+                    .cell()
+                    .foreground_color(Some(Color::Red))
+            } else if !*worker_connected {
+                "RUNNING (WAITING FOR WORKER)"
+                    .cell()
+                    .foreground_color(Some(Color::Green))
+            } else {
+                "RUNNING".cell().foreground_color(Some(Color::Green))
+            };
+            (state, started_at.round_subsecs(0).to_string())
+        }
+    }
+}
+
+fn print_allocation_table(
+    gsettings: &GlobalSettings,
+    mut allocations: Vec<AllocationInfo>,
+    previous: Option<&Map<String, AllocationInfo>>,
+    show_queue: bool,
+) {
+    allocations.sort_unstable_by(|a, b| (&a.queue, &a.id).cmp(&(&b.queue, &b.id)));
+
+    let rows: Vec<_> = allocations
+        .into_iter()
+        .map(|allocation| {
+            let changed = allocation_has_changed(&allocation, previous);
+            let (status, date) = allocation_status(&allocation.status);
+            let mut row = Vec::new();
+            if show_queue {
+                row.push(allocation.queue.cell().bold(changed));
+            }
+            row.extend(vec![
+                allocation.id.cell().bold(changed),
+                status.bold(changed),
+                allocation
+                    .worker_count
+                    .cell()
+                    .justify(Justify::Right)
+                    .bold(changed),
+                date.cell().bold(changed),
+            ]);
+            row
+        })
+        .collect();
+
+    let mut header = Vec::new();
+    if show_queue {
+        header.push("Queue".cell().bold(true));
+    }
+    header.extend(vec![
+        "Id".cell().bold(true),
+        "State".cell().bold(true),
+        "Worker count".cell().bold(true),
+        "Queued/Started at".cell().bold(true),
+    ]);
+    let table = rows
+        .table()
+        .title(header)
+        .color_choice(gsettings.color_policy());
+    assert!(print_stdout(table).is_ok());
+}
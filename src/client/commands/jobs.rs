@@ -1,13 +1,34 @@
 use crate::client::globalsettings::GlobalSettings;
 use crate::client::job::{get_worker_map, print_job_detail, print_job_list};
 use crate::client::status::{job_status, Status};
+use crate::common::arraydef::IntArray;
 use crate::rpc_call;
 use crate::transfer::connection::ClientConnection;
 use crate::transfer::messages::{
-    CancelJobResponse, CancelRequest, FromClientMessage, JobDetailRequest, JobInfoRequest,
-    Selector, ToClientMessage,
+    CancelJobResponse, CancelRequest, ForgetJobRequest, ForgetJobResponse, FromClientMessage,
+    JobDetailRequest, JobInfoRequest, Selector, ToClientMessage,
 };
-use crate::JobId;
+use crate::{JobId, JobTaskCount};
+use serde::Serialize;
+
+/// Exit code of `hq progress` when the job has not been completed yet
+pub const PROGRESS_EXIT_CODE_UNFINISHED: i32 = 2;
+/// Exit code of `hq progress` when the job has failed or was canceled.
+/// It differs from the exit code of `hq` when the command itself fails (1).
+pub const PROGRESS_EXIT_CODE_FAILED: i32 = 3;
+
+#[derive(Serialize)]
+struct JobProgress {
+    id: JobId,
+    name: String,
+    state: &'static str,
+    tasks: JobTaskCount,
+    waiting: JobTaskCount,
+    running: JobTaskCount,
+    finished: JobTaskCount,
+    failed: JobTaskCount,
+    canceled: JobTaskCount,
+}
 
 pub async fn get_last_job_id(connection: &mut ClientConnection) -> crate::Result<Option<JobId>> {
     let message = FromClientMessage::JobInfo(JobInfoRequest {
@@ -127,3 +148,83 @@ pub async fn cancel_job(
     }
     Ok(())
 }
+
+pub async fn forget_job(
+    _gsettings: &GlobalSettings,
+    connection: &mut ClientConnection,
+    selector: Selector,
+) -> crate::Result<()> {
+    let mut responses = rpc_call!(connection, FromClientMessage::Forget(ForgetJobRequest {
+         selector,
+    }), ToClientMessage::ForgetJobResponse(r) => r)
+    .await?;
+    responses.sort_unstable_by_key(|x| x.0);
+
+    let mut forgotten_jobs = 0;
+    let mut forgotten_tasks: JobTaskCount = 0;
+    for (job_id, response) in responses {
+        match response {
+            ForgetJobResponse::Forgotten(n_tasks) => {
+                forgotten_jobs += 1;
+                forgotten_tasks += n_tasks;
+            }
+            ForgetJobResponse::NotTerminated => {
+                log::error!(
+                    "Forgetting job {} failed; the job has not been completed yet",
+                    job_id
+                )
+            }
+            ForgetJobResponse::InvalidJob => {
+                log::error!("Forgetting job {} failed; job not found", job_id)
+            }
+        }
+    }
+    if forgotten_jobs == 0 {
+        log::info!("There is nothing to forget");
+    } else {
+        log::info!(
+            "{} job(s) with {} task(s) were forgotten",
+            forgotten_jobs,
+            forgotten_tasks
+        );
+    }
+    Ok(())
+}
+
+/// Prints the progress of a job as a JSON object.
+/// Returns the exit code that corresponds to the state of the job.
+pub async fn output_job_progress(
+    connection: &mut ClientConnection,
+    job_id: JobId,
+) -> anyhow::Result<i32> {
+    let message = FromClientMessage::JobInfo(JobInfoRequest {
+        selector: Selector::Specific(IntArray::from_ids(vec![job_id])),
+    });
+    let response = rpc_call!(connection, message, ToClientMessage::JobInfoResponse(r) => r).await?;
+    let info = match response.jobs.into_iter().next() {
+        Some(info) => info,
+        None => anyhow::bail!("Job {} not found", job_id),
+    };
+
+    let status = job_status(&info);
+    let (state, exit_code) = match status {
+        Status::Waiting => ("waiting", PROGRESS_EXIT_CODE_UNFINISHED),
+        Status::Running => ("running", PROGRESS_EXIT_CODE_UNFINISHED),
+        Status::Finished => ("finished", 0),
+        Status::Failed => ("failed", PROGRESS_EXIT_CODE_FAILED),
+        Status::Canceled => ("canceled", PROGRESS_EXIT_CODE_FAILED),
+    };
+    let progress = JobProgress {
+        id: info.id,
+        name: info.name,
+        state,
+        tasks: info.n_tasks,
+        waiting: info.counters.n_waiting_tasks(info.n_tasks),
+        running: info.counters.n_running_tasks,
+        finished: info.counters.n_finished_tasks,
+        failed: info.counters.n_failed_tasks,
+        canceled: info.counters.n_canceled_tasks,
+    };
+    println!("{}", serde_json::to_string(&progress)?);
+    Ok(exit_code)
+}
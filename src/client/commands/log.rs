@@ -32,6 +32,14 @@ pub struct ShowOpts {
     /// Show close message even for tasks with empty stream
     #[clap(long)]
     pub show_empty: bool,
+
+    /// Print only the specified task(s) output. You can use the array syntax to specify multiple tasks.
+    #[clap(long)]
+    pub task: Option<IntArray>,
+
+    /// Keep waiting for new output until all open streams of tasks selected by `--task` are closed
+    #[clap(long)]
+    pub follow: bool,
 }
 
 #[derive(Clap)]
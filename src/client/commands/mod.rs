@@ -1,3 +1,5 @@
+pub mod attach;
+pub mod autoalloc;
 pub mod jobs;
 pub mod log;
 pub mod stats;
@@ -1,24 +1,27 @@
 use std::io::BufRead;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use std::{fs, io};
 
 use anyhow::anyhow;
-use bstr::BString;
+use bstr::{BString, ByteSlice};
+use chrono::{Local, NaiveDateTime, NaiveTime};
 use clap::Clap;
 use tako::common::resources::{CpuRequest, ResourceRequest};
 use tako::messages::common::{ProgramDefinition, StdioDef};
 
+use crate::client::commands::attach::attach_to_job;
 use crate::client::commands::wait::wait_for_job_with_info;
 use crate::client::globalsettings::GlobalSettings;
 use crate::client::job::{get_worker_map, print_job_detail};
-use crate::client::resources::parse_cpu_request;
+use crate::client::resources::{parse_cpu_request, ArgResourceRequest};
 use crate::client::status::StatusList;
 use crate::common::arraydef::IntArray;
 use crate::common::timeutils::ArgDuration;
 use crate::transfer::connection::ClientConnection;
 use crate::transfer::messages::{
-    FromClientMessage, JobType, ResubmitRequest, SubmitRequest, ToClientMessage,
+    FromClientMessage, JobType, OutputFlush, ResubmitRequest, SubmitRequest, ToClientMessage,
 };
 use crate::{rpc_call, JobId, JobTaskCount, Map};
 
@@ -63,6 +66,54 @@ impl FromStr for ArgEnvironmentVar {
     }
 }
 
+/// Key/value metadata of a job in the form `KEY=VALUE`
+#[derive(Debug)]
+pub struct ArgMetadata {
+    key: String,
+    value: String,
+}
+
+impl FromStr for ArgMetadata {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.find('=') {
+            Some(position) if position > 0 => Ok(ArgMetadata {
+                key: s[..position].to_string(),
+                value: s[position + 1..].to_string(),
+            }),
+            _ => anyhow::bail!("Metadata have to be in the form KEY=VALUE"),
+        }
+    }
+}
+
+/// Delay of the start of a job, either a duration (e.g. `2h`) or a local time of day (e.g. `22:00`)
+struct ArgStartDelay(Duration);
+
+impl FromStr for ArgStartDelay {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(time) = NaiveTime::parse_from_str(s, "%H:%M") {
+            return Ok(ArgStartDelay(delay_until(Local::now().naive_local(), time)));
+        }
+        humantime::parse_duration(s)
+            .map(ArgStartDelay)
+            .map_err(|_| {
+                anyhow!("Start delay has to be a duration (e.g. 2h) or a time of day (e.g. 22:00)")
+            })
+    }
+}
+
+/// Returns the time that remains from `now` until the nearest occurrence of the given time of day
+fn delay_until(now: NaiveDateTime, time: NaiveTime) -> Duration {
+    let mut start = now.date().and_time(time);
+    if start <= now {
+        start += chrono::Duration::days(1);
+    }
+    (start - now).to_std().unwrap_or_default()
+}
+
 /// Represents a filepath. If "none" is passed to it, it will behave as if no path is needed.
 struct StdioArg(StdioDef);
 
@@ -83,9 +134,17 @@ pub struct SubmitOpts {
     command: String,
     args: Vec<String>,
 
-    /// Number and placement of CPUs for each job
-    #[clap(long, default_value = "1")]
-    cpus: ArgCpuRequest,
+    /// Number and placement of CPUs for each job.
+    /// If no resources are requested, the default resource request of the server is used
+    /// (a single CPU unless configured otherwise).
+    #[clap(long, conflicts_with("resources"))]
+    cpus: Option<ArgCpuRequest>,
+
+    /// Resources requested by each task as a comma-separated list of `<name>=<value>` pairs,
+    /// e.g. `--resources="cpus=4 scatter"`. Only `cpus` can be currently requested.
+    /// Cannot be combined with `--cpus`.
+    #[clap(long)]
+    resources: Option<ArgResourceRequest>,
 
     /// Name of the job
     #[clap(long)]
@@ -110,6 +169,11 @@ pub struct SubmitOpts {
     #[clap(long)]
     stderr: Option<StdioArg>,
 
+    /// Redirect the standard error of the job into its standard output
+    /// The output will be stored in the path specified by `--stdout`
+    #[clap(long)]
+    merge_stderr: bool,
+
     /// Specify additional environment variable for the job
     /// You can pass this flag multiple times to pass multiple variables
     ///
@@ -117,6 +181,13 @@ pub struct SubmitOpts {
     #[clap(long, multiple_occurrences(true))]
     pub env: Vec<ArgEnvironmentVar>,
 
+    /// Attach informational metadata to the job, they are shown in the job detail.
+    /// You can pass this flag multiple times to pass multiple key/value pairs
+    ///
+    /// `--metadata=stage=reduce` - set metadata `stage` to the value `reduce`
+    #[clap(long, multiple_occurrences(true))]
+    metadata: Vec<ArgMetadata>,
+
     // Parameters for creating array jobs
     /// Create a task array where a task will be created for each line of the given file.
     /// The corresponding line will be passed to the task in environment variable `HQ_ENTRY`.
@@ -132,37 +203,104 @@ pub struct SubmitOpts {
     /// `--array=3-5` - create task array with three jobs with task IDs 3, 4, 5
     array: Option<IntArray>,
 
+    /// Pass a distinct standard input to each task of a task array.
+    /// If the path is a directory, the n-th file of the directory (sorted by name) is passed
+    /// to the n-th task. If the path is a file, it is split by `--stdin-delimiter` and
+    /// the n-th chunk is passed to the n-th task.
+    /// Can be used only together with `--array` or `--each-line`.
+    #[clap(long, value_hint = clap::ValueHint::AnyPath)]
+    stdin_per_task: Option<PathBuf>,
+
+    /// Delimiter used to split the file passed to `--stdin-per-task` (default: newline)
+    #[clap(long, requires("stdin-per-task"))]
+    stdin_delimiter: Option<String>,
+
+    /// Run the first task of a task array as a canary.
+    /// The remaining tasks are started only if the canary task finishes successfully,
+    /// otherwise they are canceled.
+    #[clap(long)]
+    canary: bool,
+
+    /// Do not start the tasks of the job before the given delay elapses (e.g. `--after=2h`)
+    /// or before the given local time of day (e.g. `--after=22:00`).
+    /// Until then, the tasks stay waiting.
+    #[clap(long)]
+    after: Option<ArgStartDelay>,
+
+    /// Maximal number of failed tasks of the job.
+    /// When more tasks fail, all remaining non-finished tasks of the job are canceled.
     #[clap(long)]
     max_fails: Option<JobTaskCount>,
 
-    #[clap(long, default_value = "0")]
+    /// Priority of all tasks of the job, any 32b signed integer.
+    /// Tasks with a higher priority are executed sooner.
+    #[clap(long, default_value = "0", allow_hyphen_values = true)]
     priority: tako::Priority,
 
     #[clap(long)]
     /// Time limit per task. E.g. --time-limit=10min
     time_limit: Option<ArgDuration>,
 
+    #[clap(long)]
+    /// CPU time limit per task. A task that consumes more CPU time is killed.
+    /// E.g. --cpu-time-limit=1h
+    cpu_time_limit: Option<ArgDuration>,
+
     /// Wait on the job(s) execution.
     #[clap(long)]
     wait: bool,
 
+    /// Submit a job with a single task and stream its output to the terminal.
+    /// Ctrl-C cancels the job, and the exit code of the task is used as the exit code of `hq`.
+    #[clap(long)]
+    attach: bool,
+
+    /// Fail instead of printing a warning when tasks of the job would write
+    /// their output into the same file
+    #[clap(long)]
+    strict: bool,
+
     #[clap(long)]
     log: Option<PathBuf>,
+
+    /// How the worker forwards the output of tasks into the log (requires --log).
+    /// "chunk" forwards output as soon as it is produced (default), "line" forwards only whole
+    /// lines and "block" forwards output in large blocks, which is faster for tasks that produce
+    /// a lot of output.
+    #[clap(long, default_value = "chunk", requires("log"))]
+    output_flush: OutputFlush,
 }
 
 impl SubmitOpts {
-    fn resource_request(&self) -> ResourceRequest {
-        ResourceRequest::new(self.cpus.0.clone())
+    /// Returns `None` if no resources were requested explicitly, in that case the server
+    /// uses its default resource request.
+    fn resource_request(&mut self) -> Option<ResourceRequest> {
+        match (self.resources.take(), self.cpus.take()) {
+            (Some(resources), _) => Some(resources.into_request()),
+            (None, Some(cpus)) => Some(ResourceRequest::new(cpus.0)),
+            (None, None) => None,
+        }
     }
 }
 
 pub async fn submit_computation(
     gsettings: &GlobalSettings,
     connection: &mut ClientConnection,
-    opts: SubmitOpts,
+    mut opts: SubmitOpts,
 ) -> anyhow::Result<()> {
     let resources = opts.resource_request();
-    resources.validate()?;
+    if let Some(resources) = &resources {
+        resources.validate()?;
+    }
+
+    if opts.attach {
+        if opts.array.is_some() || opts.each_line.is_some() {
+            anyhow::bail!("Option --attach can be used only for jobs with a single task");
+        }
+        if opts.log.is_some() || opts.stdout.is_some() || opts.stderr.is_some() {
+            anyhow::bail!("Option --attach cannot be combined with --log, --stdout or --stderr");
+        }
+    }
 
     let (job_type, entries) = if let Some(filename) = opts.each_line {
         let lines = read_lines(&filename)?;
@@ -175,6 +313,32 @@ pub async fn submit_computation(
         )
     };
 
+    if opts.canary && matches!(job_type, JobType::Simple) {
+        anyhow::bail!("Option --canary can be used only together with --array or --each-line");
+    }
+
+    let task_stdin = match opts.stdin_per_task {
+        Some(path) => {
+            let array = match &job_type {
+                JobType::Array(array) => array,
+                JobType::Simple => anyhow::bail!(
+                    "Option --stdin-per-task can be used only together with --array or --each-line"
+                ),
+            };
+            let delimiter = opts.stdin_delimiter.as_deref().unwrap_or("\n");
+            let chunks = read_stdin_chunks(&path, delimiter)?;
+            if chunks.len() != array.id_count() as usize {
+                anyhow::bail!(
+                    "Number of stdin chunks ({}) does not match the number of tasks ({})",
+                    chunks.len(),
+                    array.id_count()
+                );
+            }
+            Some(chunks)
+        }
+        None => None,
+    };
+
     let log = opts.log;
     let is_dir_some =
         |dir: Option<&StdioArg>| -> bool { dir.map_or(true, |x| !matches!(x.0, StdioDef::Null)) };
@@ -185,7 +349,7 @@ pub async fn submit_computation(
             task_files += array.id_count();
             active_dirs.push_str(" stdout");
         }
-        if is_dir_some(opts.stderr.as_ref()) {
+        if !opts.merge_stderr && is_dir_some(opts.stderr.as_ref()) {
             task_files += array.id_count();
             active_dirs.push_str(" stderr");
         }
@@ -215,7 +379,6 @@ pub async fn submit_computation(
         .collect();
     args.insert(0, opts.command.into());
 
-    let cwd = Some(opts.cwd);
     let stdout = opts.stdout.map(|x| x.0).unwrap_or_else(|| {
         if log.is_none() {
             StdioDef::File(DEFAULT_STDOUT_PATH.into())
@@ -223,14 +386,32 @@ pub async fn submit_computation(
             StdioDef::Pipe
         }
     });
-    let stderr = opts.stderr.map(|x| x.0).unwrap_or_else(|| {
-        if log.is_none() {
-            StdioDef::File(DEFAULT_STDERR_PATH.into())
-        } else {
-            StdioDef::Pipe
+    let stderr = if opts.merge_stderr {
+        if opts.stderr.is_some() {
+            log::warn!("Option --stderr is ignored, because --merge-stderr is used");
         }
-    });
+        StdioDef::Null
+    } else {
+        opts.stderr.map(|x| x.0).unwrap_or_else(|| {
+            if log.is_none() {
+                StdioDef::File(DEFAULT_STDERR_PATH.into())
+            } else {
+                StdioDef::Pipe
+            }
+        })
+    };
+
+    let shared_outputs = find_shared_outputs(&job_type, &opts.cwd, &stdout, &stderr);
+    if !shared_outputs.is_empty() {
+        if opts.strict {
+            anyhow::bail!("{}", shared_outputs.join("\n"));
+        }
+        for message in shared_outputs {
+            log::warn!("{}", message);
+        }
+    }
 
+    let cwd = Some(opts.cwd);
     let env_count = opts.env.len();
     let env: Map<_, _> = opts
         .env
@@ -244,6 +425,12 @@ pub async fn submit_computation(
         )
     }
 
+    let metadata: Map<String, String> = opts
+        .metadata
+        .into_iter()
+        .map(|item| (item.key, item.value))
+        .collect();
+
     let message = FromClientMessage::Submit(SubmitRequest {
         job_type,
         name,
@@ -256,17 +443,45 @@ pub async fn submit_computation(
         },
         resources,
         pin: opts.pin,
+        merge_stderr_into_stdout: opts.merge_stderr,
         entries,
+        task_stdin,
+        canary: opts.canary,
+        start_delay: opts.after.map(|delay| delay.0),
+        metadata,
         max_fails: opts.max_fails,
         submit_dir: std::env::current_dir().unwrap().to_str().unwrap().into(),
         priority: opts.priority,
         time_limit: opts.time_limit.map(|x| x.into()),
+        cpu_time_limit: opts.cpu_time_limit.map(|x| x.into()),
         log,
+        output_flush: opts.output_flush,
     });
 
     let response = rpc_call!(connection, message, ToClientMessage::SubmitResponse(r) => r).await?;
+    if let Some(warning) = &response.resource_warning {
+        log::warn!("{}", warning);
+    }
     let info = response.job.info.clone();
 
+    if opts.attach {
+        log::info!("Job submitted successfully, job ID: {}", info.id);
+        let submit_dir = std::env::current_dir()?;
+        let resolve = |path: &str| {
+            submit_dir.join(
+                path.replace("%{JOB_ID}", &info.id.to_string())
+                    .replace("%{TASK_ID}", "0"),
+            )
+        };
+        let stdout = resolve(DEFAULT_STDOUT_PATH);
+        let stderr = if opts.merge_stderr {
+            None
+        } else {
+            Some(resolve(DEFAULT_STDERR_PATH))
+        };
+        return attach_to_job(connection, info.id, Some(stdout), stderr).await;
+    }
+
     print_job_detail(
         gsettings,
         response.job,
@@ -275,7 +490,7 @@ pub async fn submit_computation(
         get_worker_map(connection).await?,
     );
     if opts.wait {
-        wait_for_job_with_info(connection, info).await?;
+        wait_for_job_with_info(gsettings, connection, info).await?;
     }
     Ok(())
 }
@@ -298,8 +513,40 @@ pub async fn resubmit_computation(
     let message = FromClientMessage::Resubmit(ResubmitRequest {
         job_id: opts.job_id,
         status: opts.status.map(|x| x.to_vec()),
+        cancel: false,
+    });
+    print_resubmitted_job(gsettings, connection, message).await
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct RequeueOpts {
+    job_id: JobId,
+}
+
+/// Cancels a job and submits all of its tasks again as a new job within a single request
+pub async fn requeue_computation(
+    gsettings: &GlobalSettings,
+    connection: &mut ClientConnection,
+    opts: RequeueOpts,
+) -> anyhow::Result<()> {
+    let message = FromClientMessage::Resubmit(ResubmitRequest {
+        job_id: opts.job_id,
+        status: None,
+        cancel: true,
     });
+    print_resubmitted_job(gsettings, connection, message).await
+}
+
+async fn print_resubmitted_job(
+    gsettings: &GlobalSettings,
+    connection: &mut ClientConnection,
+    message: FromClientMessage,
+) -> anyhow::Result<()> {
     let response = rpc_call!(connection, message, ToClientMessage::SubmitResponse(r) => r).await?;
+    if let Some(warning) = &response.resource_warning {
+        log::warn!("{}", warning);
+    }
     print_job_detail(
         gsettings,
         response.job,
@@ -310,6 +557,51 @@ pub async fn resubmit_computation(
     Ok(())
 }
 
+/// Finds output files that would be written by more tasks of the job or by both stdout
+/// and stderr of a task, so that the writers would overwrite each other's output.
+/// Returns a description of each such file.
+fn find_shared_outputs(
+    job_type: &JobType,
+    cwd: &Path,
+    stdout: &StdioDef,
+    stderr: &StdioDef,
+) -> Vec<String> {
+    let task_count = match job_type {
+        JobType::Simple => 1,
+        JobType::Array(array) => array.id_count(),
+    };
+    let depends_on_task = |path: &Path| {
+        let path = path.to_string_lossy();
+        path.contains("%{TASK_ID}")
+            || (path.contains("%{CWD}") && cwd.to_string_lossy().contains("%{TASK_ID}"))
+    };
+
+    let mut messages = Vec::new();
+    if task_count > 1 {
+        for &(name, stdio) in &[("stdout", stdout), ("stderr", stderr)] {
+            if let StdioDef::File(path) = stdio {
+                if !depends_on_task(path) {
+                    messages.push(format!(
+                        "All {} tasks of the job write their {} into the same file {:?}, \
+                         use the %{{TASK_ID}} placeholder in --{} to separate them",
+                        task_count, name, path, name
+                    ));
+                }
+            }
+        }
+    }
+    if let (StdioDef::File(stdout), StdioDef::File(stderr)) = (stdout, stderr) {
+        if stdout == stderr {
+            messages.push(format!(
+                "Stdout and stderr are written into the same file {:?}, \
+                 use --merge-stderr to write both outputs into a single file",
+                stdout
+            ));
+        }
+    }
+    messages
+}
+
 fn validate_name(name: String) -> anyhow::Result<String, anyhow::Error> {
     match name {
         name if name.contains('\n') || name.contains('\t') => {
@@ -334,11 +626,101 @@ fn read_lines(filename: &Path) -> anyhow::Result<Vec<BString>> {
     Ok(results?)
 }
 
+/// Reads standard inputs of individual tasks, either from files of a directory
+/// or from chunks of a single file
+fn read_stdin_chunks(path: &Path, delimiter: &str) -> anyhow::Result<Vec<BString>> {
+    if path.is_dir() {
+        let mut files = fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        files.retain(|path| path.is_file());
+        files.sort_unstable();
+        return files
+            .into_iter()
+            .map(|file| Ok(BString::from(fs::read(file)?)))
+            .collect();
+    }
+    if delimiter.is_empty() {
+        anyhow::bail!("Stdin delimiter cannot be empty");
+    }
+    Ok(split_stdin_chunks(&fs::read(path)?, delimiter.as_bytes()))
+}
+
+fn split_stdin_chunks(data: &[u8], delimiter: &[u8]) -> Vec<BString> {
+    let mut chunks: Vec<BString> = data.split_str(delimiter).map(BString::from).collect();
+    // A trailing delimiter does not start a new chunk
+    if chunks.last().map_or(false, |chunk| chunk.is_empty()) {
+        chunks.pop();
+    }
+    chunks
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
-    use super::ArgEnvironmentVar;
+    use chrono::{NaiveDate, NaiveTime};
+    use std::time::Duration;
+
+    use std::path::Path;
+    use tako::messages::common::StdioDef;
+
+    use crate::common::arraydef::IntArray;
+    use crate::transfer::messages::JobType;
+
+    use super::{
+        delay_until, find_shared_outputs, split_stdin_chunks, ArgEnvironmentVar, ArgMetadata,
+    };
+
+    fn file(path: &str) -> StdioDef {
+        StdioDef::File(path.into())
+    }
+
+    #[test]
+    fn test_shared_outputs_of_array() {
+        let array = JobType::Array(IntArray::from_range(1, 3));
+        let cwd = Path::new("/work");
+        assert!(
+            find_shared_outputs(&array, cwd, &file("%{TASK_ID}.out"), &StdioDef::Null).is_empty()
+        );
+        assert_eq!(
+            find_shared_outputs(&array, cwd, &file("out"), &file("%{TASK_ID}.err")).len(),
+            1
+        );
+        assert_eq!(
+            find_shared_outputs(&array, cwd, &file("out"), &file("err")).len(),
+            2
+        );
+        let cwd = Path::new("/work/%{TASK_ID}");
+        assert!(find_shared_outputs(&array, cwd, &file("%{CWD}/out"), &StdioDef::Pipe).is_empty());
+    }
+
+    #[test]
+    fn test_shared_outputs_of_single_task() {
+        let cwd = Path::new("/work");
+        assert!(find_shared_outputs(&JobType::Simple, cwd, &file("out"), &file("err")).is_empty());
+        assert_eq!(
+            find_shared_outputs(&JobType::Simple, cwd, &file("out"), &file("out")).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_delay_until_time_of_day() {
+        let now = NaiveDate::from_ymd(2021, 10, 11).and_hms(20, 30, 0);
+        assert_eq!(
+            delay_until(now, NaiveTime::from_hms(22, 0, 0)),
+            Duration::from_secs(90 * 60)
+        );
+        assert_eq!(
+            delay_until(now, NaiveTime::from_hms(6, 0, 0)),
+            Duration::from_secs(9 * 3600 + 30 * 60)
+        );
+        assert_eq!(
+            delay_until(now, NaiveTime::from_hms(20, 30, 0)),
+            Duration::from_secs(24 * 3600)
+        );
+    }
 
     #[test]
     fn test_parse_env_empty() {
@@ -373,4 +755,25 @@ mod tests {
         assert_eq!(env.key, "key");
         assert_eq!(env.value, "value=value2");
     }
+
+    #[test]
+    fn test_parse_metadata() {
+        let item: ArgMetadata = FromStr::from_str("stage=reduce=1").unwrap();
+        assert_eq!(item.key, "stage");
+        assert_eq!(item.value, "reduce=1");
+        assert!(ArgMetadata::from_str("stage").is_err());
+        assert!(ArgMetadata::from_str("=reduce").is_err());
+    }
+
+    #[test]
+    fn test_split_stdin_chunks() {
+        assert_eq!(split_stdin_chunks(b"a\nb\nc", b"\n"), vec!["a", "b", "c"]);
+        assert_eq!(split_stdin_chunks(b"a\nb\n", b"\n"), vec!["a", "b"]);
+        assert_eq!(split_stdin_chunks(b"a\n\nb", b"\n"), vec!["a", "", "b"]);
+        assert_eq!(
+            split_stdin_chunks(b"1 2\n---\n3", b"---\n"),
+            vec!["1 2\n", "3"]
+        );
+        assert!(split_stdin_chunks(b"", b"\n").is_empty());
+    }
 }
@@ -1,30 +1,41 @@
 use crate::client::status::is_terminated;
 use crate::transfer::connection::ClientConnection;
 use crate::transfer::messages::{
-    FromClientMessage, JobInfo, JobInfoRequest, Selector, ToClientMessage,
+    FromClientMessage, JobDetailRequest, JobInfo, JobInfoRequest, Selector, ToClientMessage,
 };
-use crate::{rpc_call, JobId, JobTaskCount, Set};
+use crate::{rpc_call, JobId, JobTaskCount, JobTaskId, Set};
 
+use crate::client::globalsettings::GlobalSettings;
 use crate::client::utils::{
     job_progress_bar, TASK_COLOR_CANCELED, TASK_COLOR_FAILED, TASK_COLOR_FINISHED,
     TASK_COLOR_RUNNING,
 };
 use crate::common::arraydef::IntArray;
-use crate::server::job::JobTaskCounters;
+use crate::common::error::HqError;
+use crate::common::serverdir::ServerDir;
+use crate::server::bootstrap::get_client_connection;
+use crate::server::job::{JobTaskCounters, JobTaskState};
 use anyhow::bail;
+use chrono::{DateTime, Utc};
 use colored::Colorize;
 use std::io::Write;
 use std::time::Duration;
 use tokio::time::sleep;
 
+/// How many times should the client try to reconnect to the server when waiting for jobs
+const RECONNECT_ATTEMPTS: u32 = 30;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+
 pub async fn wait_for_job_with_info(
+    gsettings: &GlobalSettings,
     connection: &mut ClientConnection,
     job_info: JobInfo,
 ) -> anyhow::Result<()> {
-    wait_for_jobs(connection, vec![job_info]).await
+    wait_for_jobs(gsettings, connection, vec![job_info]).await
 }
 
 pub async fn wait_for_job_with_selector(
+    gsettings: &GlobalSettings,
     connection: &mut ClientConnection,
     selector: Selector,
 ) -> anyhow::Result<()> {
@@ -37,10 +48,137 @@ pub async fn wait_for_job_with_selector(
     )
     .await?;
 
-    wait_for_jobs(connection, response.jobs).await
+    wait_for_jobs(gsettings, connection, response.jobs).await
+}
+
+/// Identifies a single run of the server, it changes when the server is restarted
+fn server_identity(gsettings: &GlobalSettings) -> crate::Result<(u32, DateTime<Utc>)> {
+    let record =
+        ServerDir::open(gsettings.server_directory()).and_then(|sd| sd.read_access_record())?;
+    Ok((record.pid(), *record.start_date()))
+}
+
+/// Replaces a lost connection with a new connection to the same server.
+/// Fails if the server was restarted in the meantime, because the new server does not know
+/// the jobs of the previous one.
+async fn reconnect(
+    gsettings: &GlobalSettings,
+    connection: &mut ClientConnection,
+    identity: (u32, DateTime<Utc>),
+) -> anyhow::Result<()> {
+    for attempt in 1..=RECONNECT_ATTEMPTS {
+        sleep(RECONNECT_INTERVAL).await;
+        match server_identity(gsettings) {
+            Ok(current) if current != identity => {
+                bail!("The server was restarted, the waited jobs are no longer available")
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::debug!("Reconnection attempt {} failed: {:?}", attempt, e);
+                continue;
+            }
+        }
+        match get_client_connection(gsettings.server_directory()).await {
+            Ok(new_connection) => {
+                log::info!("Reconnected to the server");
+                *connection = new_connection;
+                return Ok(());
+            }
+            Err(e) => log::debug!("Reconnection attempt {} failed: {:?}", attempt, e),
+        }
+    }
+    bail!(
+        "Cannot reconnect to the server after {} attempts",
+        RECONNECT_ATTEMPTS
+    );
+}
+
+/// Waits until the selected tasks of a single job end, other tasks of the job are ignored.
+/// Fails if any of the selected tasks has failed or was canceled.
+pub async fn wait_for_job_tasks(
+    connection: &mut ClientConnection,
+    job_id: JobId,
+    tasks: IntArray,
+) -> anyhow::Result<()> {
+    let task_ids: Set<JobTaskId> = tasks.iter().collect();
+    let mut logged = false;
+
+    let states = loop {
+        let response = rpc_call!(
+            connection,
+            FromClientMessage::JobDetail(JobDetailRequest {
+                selector: Selector::Specific(IntArray::from_ids(vec![job_id])),
+                include_tasks: true,
+            }),
+            ToClientMessage::JobDetailResponse(r) => r
+        )
+        .await?;
+        let job = match response.into_iter().next().and_then(|(_, detail)| detail) {
+            Some(job) => job,
+            None => bail!("Job {} not found", job_id),
+        };
+
+        let states: Vec<_> = job
+            .tasks
+            .into_iter()
+            .filter(|task| task_ids.contains(&task.task_id))
+            .collect();
+        if states.len() != task_ids.len() {
+            bail!("Job {} does not contain all of the selected tasks", job_id);
+        }
+
+        let remaining = states
+            .iter()
+            .filter(|task| {
+                matches!(
+                    task.state,
+                    JobTaskState::Waiting | JobTaskState::Running { .. }
+                )
+            })
+            .count();
+        if remaining == 0 {
+            break states;
+        }
+        if !logged {
+            log::info!("Waiting for {} task(s) of job {}", task_ids.len(), job_id);
+            logged = true;
+        }
+        sleep(Duration::from_secs(1)).await;
+    };
+
+    let mut failed: Vec<JobTaskId> = Vec::new();
+    let mut canceled: Vec<JobTaskId> = Vec::new();
+    for task in states {
+        match task.state {
+            JobTaskState::Failed { .. } => failed.push(task.task_id),
+            JobTaskState::Canceled => canceled.push(task.task_id),
+            _ => {}
+        }
+    }
+    failed.sort_unstable();
+    canceled.sort_unstable();
+    if !failed.is_empty() {
+        bail!("Task(s) {} of job {} failed", format_ids(&failed), job_id);
+    }
+    if !canceled.is_empty() {
+        bail!(
+            "Task(s) {} of job {} were canceled",
+            format_ids(&canceled),
+            job_id
+        );
+    }
+    Ok(())
+}
+
+fn format_ids(ids: &[JobTaskId]) -> String {
+    ids.iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 async fn wait_for_jobs(
+    gsettings: &GlobalSettings,
     connection: &mut ClientConnection,
     mut jobs: Vec<JobInfo>,
 ) -> anyhow::Result<()> {
@@ -61,17 +199,34 @@ async fn wait_for_jobs(
         );
 
         let mut counters = JobTaskCounters::default();
+        let identity = server_identity(gsettings)?;
 
         loop {
             let ids_ref = &mut remaining_job_ids;
-            let response = rpc_call!(
+            let response = match rpc_call!(
                 connection,
                 FromClientMessage::JobInfo(JobInfoRequest {
                     selector: Selector::Specific(IntArray::from_ids(ids_ref.iter().copied().collect())),
                 }),
                 ToClientMessage::JobInfoResponse(r) => r
             )
-            .await?;
+            .await
+            {
+                Ok(response) => response,
+                Err(HqError::IoError(e)) => {
+                    // Counters of already terminated jobs are kept, the state of the remaining
+                    // jobs is queried again after the connection is reestablished
+                    println!();
+                    log::warn!("Connection to the server was lost ({}), reconnecting", e);
+                    reconnect(gsettings, connection, identity).await?;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            if response.jobs.len() != remaining_job_ids.len() {
+                bail!("Some of the waited jobs were not found on the server");
+            }
 
             let mut current_counters = counters;
             for job in &response.jobs {
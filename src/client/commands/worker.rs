@@ -2,7 +2,7 @@ use crate::rpc_call;
 use crate::transfer::connection::ClientConnection;
 use crate::transfer::messages::{
     FromClientMessage, Selector, StopWorkerMessage, StopWorkerResponse, ToClientMessage,
-    WorkerInfo, WorkerInfoRequest,
+    WorkerInfo, WorkerInfoRequest, WorkerInfoResponse,
 };
 use crate::WorkerId;
 
@@ -33,7 +33,7 @@ pub async fn get_worker_list(
 pub async fn get_worker_info(
     connection: &mut ClientConnection,
     worker_id: WorkerId,
-) -> crate::Result<Option<WorkerInfo>> {
+) -> crate::Result<Option<WorkerInfoResponse>> {
     let msg = rpc_call!(
         connection,
         FromClientMessage::WorkerInfo(WorkerInfoRequest {
@@ -49,12 +49,17 @@ pub async fn get_worker_info(
 pub async fn stop_worker(
     connection: &mut ClientConnection,
     selector: Selector,
+    idle_only: bool,
 ) -> crate::Result<()> {
-    let message = FromClientMessage::StopWorker(StopWorkerMessage { selector });
+    let message = FromClientMessage::StopWorker(StopWorkerMessage {
+        selector,
+        idle_only,
+    });
     let mut responses =
         rpc_call!(connection, message, ToClientMessage::StopWorkerResponse(r) => r).await?;
 
     responses.sort_unstable_by_key(|x| x.0);
+    let mut stopped = 0;
     for (id, response) in responses {
         match response {
             StopWorkerResponse::Failed(e) => {
@@ -67,10 +72,14 @@ pub async fn stop_worker(
                 log::warn!("Stopping worker {} failed; worker is already stopped", id);
             }
             StopWorkerResponse::Stopped => {
+                stopped += 1;
                 log::info!("Worker {} stopped", id)
             }
         }
     }
+    if idle_only {
+        log::info!("{} idle worker(s) stopped", stopped);
+    }
 
     Ok(())
 }
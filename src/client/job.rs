@@ -12,8 +12,9 @@ use crate::client::status::{job_status, status_cell, task_status};
 use crate::client::utils;
 use crate::common::env::is_hq_env;
 use crate::common::format::human_duration;
+use crate::common::nametemplate::{expand_name_template, is_name_template};
 use crate::rpc_call;
-use crate::server::job::{JobTaskCounters, JobTaskInfo, JobTaskState};
+use crate::server::job::{JobTaskInfo, JobTaskState};
 use crate::transfer::connection::ClientConnection;
 use crate::transfer::messages::{FromClientMessage, JobDetail, JobInfo, JobType, ToClientMessage};
 use crate::{JobTaskCount, Map, WorkerId};
@@ -177,7 +178,11 @@ pub fn print_job_detail(
     ]);
     rows.push(vec![
         "Stderr".cell().bold(true),
-        stdio_to_cell(&program_def.stderr),
+        if job.merge_stderr_into_stdout {
+            "<Merged into stdout>".cell()
+        } else {
+            stdio_to_cell(&program_def.stderr)
+        },
     ]);
     let mut env_vars: Vec<(_, _)> = program_def
         .env
@@ -217,11 +222,53 @@ pub fn print_job_detail(
             .cell(),
     ]);
 
+    rows.push(vec![
+        "Task CPU time limit".cell().bold(true),
+        job.cpu_time_limit
+            .map(|duration| humantime::format_duration(duration).to_string())
+            .unwrap_or_else(|| "None".to_string())
+            .cell(),
+    ]);
+
     rows.push(vec![
         "Makespan".cell().bold(true),
         human_duration(job.completion_date_or_now - job.submission_date).cell(),
     ]);
 
+    rows.push(vec![
+        "Task wait time".cell().bold(true),
+        job.wait_stats
+            .map(|stats| {
+                let format = |duration| {
+                    human_duration(
+                        chrono::Duration::from_std(duration)
+                            .unwrap_or_else(|_| chrono::Duration::zero()),
+                    )
+                };
+                format!(
+                    "p50: {}, p95: {}, max: {} ({} started task(s))",
+                    format(stats.p50),
+                    format(stats.p95),
+                    format(stats.max),
+                    stats.started_tasks
+                )
+            })
+            .unwrap_or_else(|| "N/A".to_string())
+            .cell(),
+    ]);
+
+    let mut metadata: Vec<_> = job.metadata.iter().collect();
+    metadata.sort_unstable();
+    rows.push(vec![
+        "Metadata".cell().bold(true),
+        metadata
+            .into_iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("\n")
+            .cell(),
+    ]);
+
     let table = rows.table().color_choice(gsettings.color_policy());
     assert!(print_stdout(table).is_ok());
 
@@ -231,7 +278,7 @@ pub fn print_job_detail(
             job.completion_date_or_now,
             job.tasks,
             show_tasks,
-            &job.info.counters,
+            &job.info,
             &worker_map,
         );
     }
@@ -295,10 +342,11 @@ fn print_job_tasks(
     completion_date_or_now: chrono::DateTime<chrono::Utc>,
     mut tasks: Vec<JobTaskInfo>,
     show_tasks: bool,
-    counters: &JobTaskCounters,
+    info: &JobInfo,
     worker_map: &WorkerMap,
 ) {
     tasks.sort_unstable_by_key(|t| t.task_id);
+    let counters = &info.counters;
 
     let make_error_row = |t: &JobTaskInfo| match &t.state {
         JobTaskState::Failed { worker, error, .. } => Some(vec![
@@ -310,11 +358,16 @@ fn print_job_tasks(
     };
 
     if show_tasks {
+        // Names are shown only if they differ between tasks
+        let show_names = is_name_template(&info.name);
         let rows: Vec<_> = tasks
             .iter()
             .map(|t| {
-                vec![
-                    t.task_id.cell(),
+                let mut row = vec![t.task_id.cell()];
+                if show_names {
+                    row.push(expand_name_template(&info.name, info.id, t.task_id).cell());
+                }
+                row.extend(vec![
                     status_cell(task_status(&t.state)),
                     match t.state.get_worker() {
                         Some(worker) => format_worker(worker, worker_map),
@@ -328,19 +381,24 @@ fn print_job_tasks(
                         }
                         _ => "".cell(),
                     },
-                ]
+                ]);
+                row
             })
             .collect();
+        let mut header = vec!["Task Id".cell().bold(true)];
+        if show_names {
+            header.push("Name".cell().bold(true));
+        }
+        header.extend(vec![
+            "State".cell().bold(true),
+            "Worker".cell().bold(true),
+            "Time".cell().bold(true),
+            "Message".cell().bold(true),
+        ]);
         let table = rows
             .table()
             .color_choice(gsettings.color_policy())
-            .title(vec![
-                "Task Id".cell().bold(true),
-                "State".cell().bold(true),
-                "Worker".cell().bold(true),
-                "Time".cell().bold(true),
-                "Message".cell().bold(true),
-            ]);
+            .title(header);
         assert!(print_stdout(table).is_ok());
     } else {
         const SHOWN_TASKS: usize = 5;
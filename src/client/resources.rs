@@ -4,7 +4,8 @@ use nom::bytes::complete::tag;
 use nom::character::complete::multispace1;
 use nom::combinator::{all_consuming, map, map_res, opt};
 use nom::sequence::{preceded, tuple};
-use tako::common::resources::CpuRequest;
+use std::str::FromStr;
+use tako::common::resources::{CpuRequest, ResourceRequest};
 
 fn p_cpu_request(input: &str) -> NomResult<CpuRequest> {
     alt((
@@ -38,6 +39,52 @@ pub fn parse_cpu_request(input: &str) -> anyhow::Result<CpuRequest> {
         .map_err(format_parse_error)
 }
 
+/// Parses a comma-separated list of resources in the form `<name>=<value>`, e.g. `cpus=4 scatter`.
+/// Currently, only `cpus` can be requested; its value uses the same syntax as `--cpus`.
+pub fn parse_resource_request(input: &str) -> anyhow::Result<ResourceRequest> {
+    let mut cpus = None;
+    for token in input.split(',').map(|t| t.trim()) {
+        let (name, value) = match token.split_once('=') {
+            Some(parts) => parts,
+            None => anyhow::bail!("Invalid resource '{}', expected <name>=<value>", token),
+        };
+        match name.trim() {
+            "cpus" => {
+                if cpus.is_some() {
+                    anyhow::bail!("Resource 'cpus' is specified more than once");
+                }
+                cpus = Some(
+                    parse_cpu_request(value.trim())
+                        .map_err(|e| anyhow::anyhow!("Invalid resource '{}': {}", token, e))?,
+                );
+            }
+            name => anyhow::bail!(
+                "Unknown resource '{}' in '{}', only 'cpus' can be requested",
+                name,
+                token
+            ),
+        }
+    }
+    Ok(ResourceRequest::new(cpus.unwrap_or(CpuRequest::Compact(1))))
+}
+
+/// Resource request passed as a command line argument, see [`parse_resource_request`]
+pub struct ArgResourceRequest(ResourceRequest);
+
+impl ArgResourceRequest {
+    pub fn into_request(self) -> ResourceRequest {
+        self.0
+    }
+}
+
+impl FromStr for ArgResourceRequest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_resource_request(s).map(ArgResourceRequest)
+    }
+}
+
 pub fn cpu_request_to_string(cr: &CpuRequest) -> String {
     match cr {
         CpuRequest::Compact(n_cpus) => {
@@ -79,4 +126,25 @@ mod test {
     fn test_parse_zero_cpus() {
         assert!(parse_cpu_request("0").is_err());
     }
+
+    #[test]
+    fn test_parse_resource_request_matches_cpus() {
+        for cpus in &["all", "3", "5 compact", "4 compact!", "10 scatter"] {
+            let request = parse_resource_request(&format!("cpus={}", cpus)).unwrap();
+            assert_eq!(request.cpus(), &parse_cpu_request(cpus).unwrap());
+        }
+        assert_eq!(
+            parse_resource_request(" cpus = 2 scatter ").unwrap().cpus(),
+            &CpuRequest::Scatter(2)
+        );
+    }
+
+    #[test]
+    fn test_parse_resource_request_errors() {
+        let error = |input: &str| parse_resource_request(input).unwrap_err().to_string();
+        assert!(error("cpus").contains("'cpus'"));
+        assert!(error("cpus=2,gpu=1").contains("Unknown resource 'gpu'"));
+        assert!(error("cpus=0").contains("'cpus=0'"));
+        assert!(error("cpus=1,cpus=2").contains("more than once"));
+    }
 }
@@ -1,9 +1,16 @@
 use cli_table::format::Justify;
 use cli_table::{print_stdout, Cell, CellStruct, Color, Style, Table};
 
+use tako::common::resources::ResourceDescriptor;
+
 use crate::client::globalsettings::GlobalSettings;
+use crate::common::arraydef::IntArray;
 use crate::common::manager::info::GetManagerInfo;
-use crate::transfer::messages::{LostWorkerReasonInfo, WorkerExitInfo, WorkerInfo};
+use crate::transfer::messages::{
+    LostWorkerReasonInfo, WorkerExitInfo, WorkerInfo, WorkerInfoResponse,
+};
+use crate::worker::output::worker_configuration_rows;
+use crate::{JobId, JobTaskId};
 
 pub enum WorkerState {
     Running,
@@ -33,7 +40,26 @@ fn worker_state(worker: &WorkerInfo) -> CellStruct {
     }
 }
 
-pub fn print_worker_info(workers: Vec<WorkerInfo>, gsettings: &GlobalSettings) {
+/// Describes all resources of a worker, CPUs of each socket are on a separate line
+fn resources_full_cell(descriptor: &ResourceDescriptor) -> CellStruct {
+    let mut lines = vec![descriptor.summary().to_string()];
+    for (index, cpus) in descriptor.cpus.iter().enumerate() {
+        if !cpus.is_empty() {
+            lines.push(format!(
+                "socket {}: {}",
+                index,
+                IntArray::from_ids(cpus.clone())
+            ));
+        }
+    }
+    lines.join("\n").cell()
+}
+
+pub fn print_worker_info(
+    workers: Vec<WorkerInfo>,
+    gsettings: &GlobalSettings,
+    show_all_resources: bool,
+) {
     let rows: Vec<_> = workers
         .into_iter()
         .map(|w| {
@@ -42,7 +68,11 @@ pub fn print_worker_info(workers: Vec<WorkerInfo>, gsettings: &GlobalSettings) {
                 w.id.cell().justify(Justify::Right),
                 worker_state(&w),
                 w.configuration.hostname.cell(),
-                w.configuration.resources.summary().cell(),
+                if show_all_resources {
+                    resources_full_cell(&w.configuration.resources)
+                } else {
+                    w.configuration.resources.summary().cell()
+                },
                 manager_info
                     .as_ref()
                     .map(|info| info.manager.to_string())
@@ -70,3 +100,35 @@ pub fn print_worker_info(workers: Vec<WorkerInfo>, gsettings: &GlobalSettings) {
         ]);
     assert!(print_stdout(table).is_ok());
 }
+
+/// Prints the configuration of a single worker together with its current state
+pub fn print_worker_detail(gsettings: &GlobalSettings, response: WorkerInfoResponse) {
+    let WorkerInfoResponse {
+        worker,
+        running_tasks,
+    } = response;
+
+    let mut tasks_per_job: Vec<(JobId, Vec<JobTaskId>)> = Vec::new();
+    for (job_id, task_id) in running_tasks {
+        match tasks_per_job.last_mut() {
+            Some((last_job_id, task_ids)) if *last_job_id == job_id => task_ids.push(task_id),
+            _ => tasks_per_job.push((job_id, vec![task_id])),
+        }
+    }
+    let task_count: usize = tasks_per_job.iter().map(|(_, tasks)| tasks.len()).sum();
+    let mut running_lines = vec![task_count.to_string()];
+    running_lines.extend(
+        tasks_per_job
+            .into_iter()
+            .map(|(job_id, task_ids)| format!("job {}: {}", job_id, IntArray::from_ids(task_ids))),
+    );
+
+    let mut rows = worker_configuration_rows(worker.id, &worker.configuration);
+    rows.insert(1, vec!["State".cell().bold(true), worker_state(&worker)]);
+    rows.push(vec![
+        "Running tasks".cell().bold(true),
+        running_lines.join("\n").cell(),
+    ]);
+    let table = rows.table().color_choice(gsettings.color_policy());
+    assert!(print_stdout(table).is_ok());
+}
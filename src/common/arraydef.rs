@@ -21,6 +21,11 @@ impl IntRange {
     pub fn iter(&self) -> impl Iterator<Item = u32> {
         (self.start..self.start + self.count).step_by(self.step as usize)
     }
+
+    /// Number of ids in the range, `count` is the length of the range including skipped ids
+    pub fn id_count(&self) -> u32 {
+        (self.count + self.step - 1) / self.step
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -56,7 +61,7 @@ impl IntArray {
     }
 
     pub fn id_count(&self) -> u32 {
-        self.ranges.iter().map(|x| x.count).sum()
+        self.ranges.iter().map(|x| x.id_count()).sum()
     }
 
     pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
@@ -92,3 +97,25 @@ impl fmt::Display for IntArray {
         write!(f, "{}", &str[0..str.len() - 2])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::common::arraydef::IntArray;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_id_count_step() {
+        assert_eq!(IntArray::from_str("1-100:5").unwrap().id_count(), 20);
+        assert_eq!(IntArray::from_str("0-10:2").unwrap().id_count(), 6);
+        assert_eq!(IntArray::from_str("0-9:3,20").unwrap().id_count(), 5);
+        assert_eq!(IntArray::from_str("3-7").unwrap().id_count(), 5);
+    }
+
+    #[test]
+    fn test_id_count_matches_iter() {
+        for input in &["1-100:5", "0-10:2", "5-6:1", "0-9:3,20,30-40:7"] {
+            let array = IntArray::from_str(input).unwrap();
+            assert_eq!(array.id_count() as usize, array.iter().count());
+        }
+    }
+}
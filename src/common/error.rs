@@ -31,6 +31,12 @@ impl From<rmp_serde::decode::Error> for HqError {
     }
 }
 
+impl From<rusqlite::Error> for HqError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::GenericError(format!("Database error: {}", e))
+    }
+}
+
 impl From<anyhow::Error> for HqError {
     fn from(error: anyhow::Error) -> Self {
         Self::GenericError(error.to_string())
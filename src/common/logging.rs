@@ -0,0 +1,127 @@
+use std::cell::Cell;
+use std::io::Write;
+use std::str::FromStr;
+
+use env_logger::fmt::Formatter;
+use log::Record;
+use serde_json::{json, Value};
+
+use crate::{JobId, JobTaskId, WorkerId};
+
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+    /// Human readable text (default)
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "text" => Self::Text,
+            "json" => Self::Json,
+            _ => anyhow::bail!("Invalid log format"),
+        })
+    }
+}
+
+/// Identifies the job, task and worker that are related to log records.
+/// It is attached to records produced in the JSON log format.
+#[derive(Clone, Copy, Default)]
+pub struct LogContext {
+    pub job_id: Option<JobId>,
+    pub task_id: Option<JobTaskId>,
+    pub worker_id: Option<WorkerId>,
+}
+
+thread_local! {
+    static LOG_CONTEXT: Cell<LogContext> = Cell::new(LogContext::default());
+}
+
+/// Runs `f` with the given log context.
+/// The context must not be held across `await` points, so `f` is a synchronous closure.
+pub fn with_log_context<R>(context: LogContext, f: impl FnOnce() -> R) -> R {
+    let previous = LOG_CONTEXT.with(|ctx| ctx.replace(context));
+    let result = f();
+    LOG_CONTEXT.with(|ctx| ctx.set(previous));
+    result
+}
+
+pub fn format_json_record(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let context = LOG_CONTEXT.with(|ctx| ctx.get());
+    let entry = create_json_entry(
+        &buf.timestamp_millis().to_string(),
+        record.level(),
+        record.target(),
+        &record.args().to_string(),
+        context,
+    );
+    writeln!(buf, "{}", entry)
+}
+
+fn create_json_entry(
+    timestamp: &str,
+    level: log::Level,
+    target: &str,
+    message: &str,
+    context: LogContext,
+) -> Value {
+    let mut entry = json!({
+        "timestamp": timestamp,
+        "level": level.as_str(),
+        "target": target,
+        "message": message,
+    });
+    let fields = entry.as_object_mut().unwrap();
+    if let Some(job_id) = context.job_id {
+        fields.insert("job_id".into(), job_id.into());
+    }
+    if let Some(task_id) = context.task_id {
+        fields.insert("task_id".into(), task_id.into());
+    }
+    if let Some(worker_id) = context.worker_id {
+        fields.insert("worker_id".into(), worker_id.into());
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::logging::{create_json_entry, with_log_context, LogContext, LOG_CONTEXT};
+    use serde_json::json;
+
+    #[test]
+    fn test_json_entry_contains_context() {
+        let context = LogContext {
+            job_id: Some(1),
+            task_id: Some(5),
+            worker_id: None,
+        };
+        let entry = create_json_entry("now", log::Level::Info, "hq", "Task failed", context);
+        assert_eq!(
+            entry,
+            json!({
+                "timestamp": "now",
+                "level": "INFO",
+                "target": "hq",
+                "message": "Task failed",
+                "job_id": 1,
+                "task_id": 5
+            })
+        );
+    }
+
+    #[test]
+    fn test_log_context_is_restored() {
+        let context = LogContext {
+            job_id: Some(3),
+            ..Default::default()
+        };
+        let job_id = with_log_context(context, || LOG_CONTEXT.with(|ctx| ctx.get().job_id));
+        assert_eq!(job_id, Some(3));
+        assert!(LOG_CONTEXT.with(|ctx| ctx.get().job_id).is_none());
+    }
+}
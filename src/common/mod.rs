@@ -6,7 +6,9 @@ pub mod env;
 pub mod error;
 pub mod format;
 pub mod fsutils;
+pub mod logging;
 pub mod manager;
+pub mod nametemplate;
 pub mod parser;
 pub mod serverdir;
 pub mod setup;
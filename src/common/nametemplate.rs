@@ -0,0 +1,98 @@
+use crate::{JobId, JobTaskId};
+
+const PLACEHOLDER_JOB_ID: &str = "%{JOB_ID}";
+const PLACEHOLDER_TASK_ID: &str = "%{TASK_ID}";
+
+/// Part of a job name template
+#[derive(Debug, PartialEq)]
+enum TemplatePart<'a> {
+    Text(&'a str),
+    JobId,
+    TaskId,
+}
+
+/// Splits a name template into literal text and placeholders.
+/// Supported placeholders are `%{JOB_ID}` and `%{TASK_ID}`, any other text (including other
+/// `%` sequences) is kept literally.
+fn parse_template(template: &str) -> Vec<TemplatePart> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    // Position from which the next placeholder is searched in `rest`
+    let mut position = 0;
+    while let Some(offset) = rest[position..].find('%') {
+        let start = position + offset;
+        let (part, length) = if rest[start..].starts_with(PLACEHOLDER_JOB_ID) {
+            (TemplatePart::JobId, PLACEHOLDER_JOB_ID.len())
+        } else if rest[start..].starts_with(PLACEHOLDER_TASK_ID) {
+            (TemplatePart::TaskId, PLACEHOLDER_TASK_ID.len())
+        } else {
+            position = start + 1;
+            continue;
+        };
+        if start > 0 {
+            parts.push(TemplatePart::Text(&rest[..start]));
+        }
+        parts.push(part);
+        rest = &rest[start + length..];
+        position = 0;
+    }
+    if !rest.is_empty() {
+        parts.push(TemplatePart::Text(rest));
+    }
+    parts
+}
+
+/// Returns true if the job name contains placeholders that are expanded per task
+pub fn is_name_template(name: &str) -> bool {
+    parse_template(name)
+        .iter()
+        .any(|part| !matches!(part, TemplatePart::Text(_)))
+}
+
+/// Expands placeholders of the name template for the given task
+pub fn expand_name_template(template: &str, job_id: JobId, task_id: JobTaskId) -> String {
+    parse_template(template)
+        .into_iter()
+        .map(|part| match part {
+            TemplatePart::Text(text) => text.to_string(),
+            TemplatePart::JobId => job_id.to_string(),
+            TemplatePart::TaskId => task_id.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::nametemplate::{expand_name_template, is_name_template};
+
+    #[test]
+    fn test_expand_placeholders() {
+        assert_eq!(expand_name_template("render", 1, 2), "render");
+        assert_eq!(expand_name_template("render-%{TASK_ID}", 1, 2), "render-2");
+        assert_eq!(
+            expand_name_template("%{JOB_ID}/%{TASK_ID}%{TASK_ID}", 3, 4),
+            "3/44"
+        );
+    }
+
+    #[test]
+    fn test_expand_keeps_other_percent_sequences() {
+        assert_eq!(expand_name_template("50%-sample", 1, 2), "50%-sample");
+        assert_eq!(expand_name_template("100%%", 1, 2), "100%%");
+        assert_eq!(expand_name_template("%%{TASK_ID}", 1, 2), "%2");
+        assert_eq!(
+            expand_name_template("%{FOO}-%{TASK_ID}-%{JOB_ID", 1, 2),
+            "%{FOO}-2-%{JOB_ID"
+        );
+    }
+
+    #[test]
+    fn test_is_name_template() {
+        assert!(is_name_template("a-%{TASK_ID}"));
+        assert!(is_name_template("%{JOB_ID}"));
+        assert!(!is_name_template("a"));
+        assert!(!is_name_template("a%%"));
+        assert!(!is_name_template("50%"));
+        assert!(!is_name_template("%{TASKID}"));
+    }
+}
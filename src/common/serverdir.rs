@@ -7,6 +7,7 @@ use chrono::{DateTime, Utc};
 use orion::kdf::SecretKey;
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tako::common::resources::ResourceRequest;
 
 use crate::common::error::error;
 use crate::common::fsutils::{absolute_path, create_symlink};
@@ -32,7 +33,7 @@ impl ServerDir {
     }
 
     pub fn create(directory: &Path, record: &AccessRecord) -> crate::Result<ServerDir> {
-        let dir_path = directory.join(record.start_date().format("%Y-%m-%d-%H-%M-%S").to_string());
+        let dir_path = directory.join(record.run_name());
         std::fs::create_dir_all(&dir_path)?;
 
         let server_dir = ServerDir::open(&dir_path)?;
@@ -128,6 +129,15 @@ pub struct AccessRecord {
     #[serde(serialize_with = "serde_serialize_key")]
     #[serde(deserialize_with = "serde_deserialize_key")]
     tako_secret_key: Arc<SecretKey>,
+
+    /// Resource request used for tasks that do not specify their own request
+    #[serde(default)]
+    default_resources: Option<ResourceRequest>,
+
+    /// Whether the server understands compressed client messages.
+    /// Records of older servers do not contain it, so clients do not compress messages for them.
+    #[serde(default)]
+    compression: bool,
 }
 
 impl AccessRecord {
@@ -147,8 +157,15 @@ impl AccessRecord {
             hq_secret_key,
             tako_secret_key,
             pid: std::process::id(),
+            default_resources: None,
+            compression: true,
         }
     }
+
+    pub fn with_default_resources(mut self, resources: Option<ResourceRequest>) -> Self {
+        self.default_resources = resources;
+        self
+    }
     pub fn version(&self) -> &str {
         &self.version
     }
@@ -167,12 +184,22 @@ impl AccessRecord {
     pub fn start_date(&self) -> &DateTime<Utc> {
         &self.start_date
     }
+    /// Name that identifies the server run (derived from its start date)
+    pub fn run_name(&self) -> String {
+        self.start_date.format("%Y-%m-%d-%H-%M-%S").to_string()
+    }
     pub fn hq_secret_key(&self) -> &Arc<SecretKey> {
         &self.hq_secret_key
     }
     pub fn tako_secret_key(&self) -> &Arc<SecretKey> {
         &self.tako_secret_key
     }
+    pub fn default_resources(&self) -> Option<&ResourceRequest> {
+        self.default_resources.as_ref()
+    }
+    pub fn supports_compression(&self) -> bool {
+        self.compression
+    }
 }
 
 pub fn store_access_record<P: AsRef<Path>>(record: &AccessRecord, path: P) -> crate::Result<()> {
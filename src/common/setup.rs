@@ -2,11 +2,18 @@ use std::sync::Arc;
 
 use tokio::sync::Notify;
 
-pub fn setup_logging() {
+use crate::common::logging::{format_json_record, LogFormat};
+
+pub fn setup_logging(format: LogFormat) {
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info");
     }
-    env_logger::builder().format_timestamp_millis().init();
+    let mut builder = env_logger::builder();
+    match format {
+        LogFormat::Text => builder.format_timestamp_millis(),
+        LogFormat::Json => builder.format(format_json_record),
+    };
+    builder.init();
 }
 
 pub fn setup_interrupt() -> Arc<Notify> {
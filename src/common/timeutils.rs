@@ -1,6 +1,12 @@
+use std::fmt;
 use std::str::FromStr;
 use std::time::Duration;
 
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+
+const TIME_WINDOW_FORMAT: &str = "%H:%M";
+
 pub struct ArgDuration(Duration);
 
 impl ArgDuration {
@@ -22,3 +28,92 @@ impl From<ArgDuration> for Duration {
         x.0
     }
 }
+
+/// Daily time window, e.g. `22:00-06:00`.
+/// The window can span over midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TimeWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = match s.split_once('-') {
+            Some(parts) => parts,
+            None => anyhow::bail!("Time window has to be in the format HH:MM-HH:MM"),
+        };
+        let parse = |time: &str| NaiveTime::parse_from_str(time.trim(), TIME_WINDOW_FORMAT);
+        let window = TimeWindow {
+            start: parse(start)?,
+            end: parse(end)?,
+        };
+        if window.start == window.end {
+            anyhow::bail!("Start and end of a time window have to be different");
+        }
+        Ok(window)
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format(TIME_WINDOW_FORMAT),
+            self.end.format(TIME_WINDOW_FORMAT)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TimeWindow;
+    use chrono::NaiveTime;
+    use std::str::FromStr;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn test_parse_time_window() {
+        let window = TimeWindow::from_str("08:30-17:00").unwrap();
+        assert_eq!(window.to_string(), "08:30-17:00");
+        assert!(TimeWindow::from_str("8:30").is_err());
+        assert!(TimeWindow::from_str("08:30-25:00").is_err());
+        assert!(TimeWindow::from_str("10:00-10:00").is_err());
+    }
+
+    #[test]
+    fn test_time_window_contains() {
+        let window = TimeWindow::from_str("08:30-17:00").unwrap();
+        assert!(window.contains(time(8, 30)));
+        assert!(window.contains(time(12, 0)));
+        assert!(!window.contains(time(17, 0)));
+        assert!(!window.contains(time(3, 0)));
+    }
+
+    #[test]
+    fn test_time_window_over_midnight() {
+        let window = TimeWindow::from_str("22:00-06:00").unwrap();
+        assert!(window.contains(time(22, 0)));
+        assert!(window.contains(time(23, 59)));
+        assert!(window.contains(time(0, 0)));
+        assert!(window.contains(time(5, 59)));
+        assert!(!window.contains(time(6, 0)));
+        assert!(!window.contains(time(12, 0)));
+    }
+}
@@ -0,0 +1,215 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use tokio::process::Command;
+
+use crate::server::autoalloc::{AutoAllocError, AutoAllocResult};
+
+/// Name of the file (inside the working directory of an allocation) that contains
+/// the script that was submitted to the job manager.
+pub const SUBMIT_SCRIPT_NAME: &str = "hq-submit.sh";
+
+const AUTOALLOC_DIRECTORY: &str = "autoalloc";
+
+/// Script and command that submit a single allocation into a job manager.
+pub struct AllocationSubmission {
+    /// Submit program of the job manager (`qsub`/`sbatch`)
+    pub program: &'static str,
+    /// Arguments of the submit program, the path to the script is appended after them
+    pub args: Vec<String>,
+    pub script: String,
+}
+
+impl AllocationSubmission {
+    /// Command line that submits the script stored in the given directory
+    pub fn command_line(&self, directory: &Path) -> String {
+        let mut command = self.program.to_string();
+        for arg in &self.args {
+            write!(command, " {}", arg).unwrap();
+        }
+        write!(command, " {}", directory.join(SUBMIT_SCRIPT_NAME).display()).unwrap();
+        command
+    }
+}
+
+/// Directory that contains the working directories of allocations of the given descriptor.
+pub fn allocation_dir_parent(server_directory: &Path, name: &str) -> PathBuf {
+    server_directory.join(AUTOALLOC_DIRECTORY).join(name)
+}
+
+/// Creates a new unique working directory for an allocation of the given descriptor.
+pub fn create_allocation_dir(server_directory: &Path, name: &str) -> AutoAllocResult<PathBuf> {
+    let parent = allocation_dir_parent(server_directory, name);
+    std::fs::create_dir_all(&parent)
+        .and_then(|_| tempdir::TempDir::new_in(&parent, "allocation"))
+        .map(|dir| dir.into_path())
+        .map_err(|e| {
+            AutoAllocError::Custom(format!(
+                "Cannot create allocation directory in {:?}: {}",
+                parent, e
+            ))
+        })
+}
+
+/// Creates a shell command that starts a HQ worker connected to the server.
+/// The worker process receives the given environment variables, their values are expanded
+/// by the shell. If `idle_timeout` is set, the worker stops after being idle for this duration.
+pub fn create_worker_command(
+    hq_path: &Path,
+    server_directory: &Path,
+    manager: &str,
+    env: &[(String, String)],
+    idle_timeout: Option<Duration>,
+) -> String {
+    let mut command = String::new();
+    if !env.is_empty() {
+        command.push_str("env ");
+        for (key, value) in env {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            write!(command, "{}=\"{}\" ", key, value).unwrap();
+        }
+    }
+    write!(
+        command,
+        "\"{}\" worker start --manager {} --server-dir \"{}\"",
+        hq_path.display(),
+        manager,
+        server_directory.display()
+    )
+    .unwrap();
+    if let Some(idle_timeout) = idle_timeout {
+        write!(
+            command,
+            " --idle-timeout \"{}\"",
+            humantime::format_duration(idle_timeout)
+        )
+        .unwrap();
+    }
+    command
+}
+
+/// Formats the duration in the `HH:MM:SS` format used by job managers.
+pub fn format_walltime(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60
+    )
+}
+
+/// Stores the script into the working directory and submits it with the submit program.
+/// Returns the (trimmed) stdout of the program.
+pub async fn submit_script(
+    submission: AllocationSubmission,
+    directory: &Path,
+) -> AutoAllocResult<String> {
+    let AllocationSubmission {
+        program,
+        args,
+        script,
+    } = submission;
+    let script_path = directory.join(SUBMIT_SCRIPT_NAME);
+    std::fs::write(&script_path, script).map_err(|e| {
+        AutoAllocError::Custom(format!(
+            "Cannot write submit script {:?}: {}",
+            script_path, e
+        ))
+    })?;
+
+    let output = run_command(
+        Command::new(program)
+            .args(&args)
+            .arg(&script_path)
+            .current_dir(directory),
+        program,
+    )
+    .await?;
+    check_command_output(program, output)
+        .map_err(|e| AutoAllocError::Custom(format!("{} (submit script: {:?})", e, script_path)))
+}
+
+pub async fn run_command(command: &mut Command, program: &str) -> AutoAllocResult<Output> {
+    log::debug!("Running command {:?}", command);
+    command
+        .output()
+        .await
+        .map_err(|e| AutoAllocError::Custom(format!("Cannot execute {}: {}", program, e)))
+}
+
+/// Returns the stdout of a successfully finished command
+pub fn check_command_output(program: &str, output: Output) -> AutoAllocResult<String> {
+    if !output.status.success() {
+        return Err(AutoAllocError::Custom(format!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Parses a local date in the given format and converts it to an instant.
+/// Instants cannot be in the future, so future dates are mapped to the current instant.
+pub fn parse_local_time(date: &str, format: &str) -> Option<Instant> {
+    let date: DateTime<Local> = Local
+        .from_local_datetime(&NaiveDateTime::parse_from_str(date, format).ok()?)
+        .earliest()?;
+    let elapsed = (Local::now() - date).to_std().unwrap_or_default();
+    Instant::now().checked_sub(elapsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use std::path::PathBuf;
+
+    use crate::server::autoalloc::descriptor::common::{create_worker_command, format_walltime};
+
+    #[test]
+    fn test_format_walltime() {
+        assert_eq!(format_walltime(Duration::from_secs(0)), "00:00:00");
+        assert_eq!(format_walltime(Duration::from_secs(3661)), "01:01:01");
+        assert_eq!(format_walltime(Duration::from_secs(48 * 3600)), "48:00:00");
+    }
+
+    #[test]
+    fn test_worker_command_env() {
+        let command = create_worker_command(
+            &PathBuf::from("/bin/hq"),
+            &PathBuf::from("/server"),
+            "pbs",
+            &[
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), r#"/lib:$PATH "x\""#.to_string()),
+                ("C".to_string(), "it's".to_string()),
+            ],
+            None,
+        );
+        assert_eq!(
+            command,
+            r#"env A="1" B="/lib:$PATH \"x\\\"" C="it's" "/bin/hq" worker start --manager pbs --server-dir "/server""#
+        );
+    }
+
+    #[test]
+    fn test_worker_command_idle_timeout() {
+        let command = create_worker_command(
+            &PathBuf::from("/bin/hq"),
+            &PathBuf::from("/server"),
+            "slurm",
+            &[],
+            Some(Duration::from_secs(330)),
+        );
+        assert_eq!(
+            command,
+            r#""/bin/hq" worker start --manager slurm --server-dir "/server" --idle-timeout "5m 30s""#
+        );
+    }
+}
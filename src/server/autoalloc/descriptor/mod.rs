@@ -1,28 +1,38 @@
+use std::path::PathBuf;
+
 use async_trait::async_trait;
 
 use crate::server::autoalloc::state::{AllocationId, AllocationStatus};
 use crate::server::autoalloc::AutoAllocResult;
 
+pub mod common;
+pub mod pbs;
+pub mod slurm;
+
+/// Allocation that was successfully submitted into a job manager queue.
+pub struct CreatedAllocation {
+    pub id: AllocationId,
+    /// Directory that contains the submit script and the output of the allocation.
+    pub working_dir: PathBuf,
+}
+
 /// This trait represents a job manager queue into which new allocations can be scheduled.
+/// The descriptor stays borrowed while the job manager is contacted, therefore state that can be
+/// changed by clients (e.g. the scale of the queue) is kept in `DescriptorState` instead.
 ///
 /// TODO: try to remove async_trait and migrate to Pin<Box<dyn Future>>>
 #[async_trait(?Send)]
 pub trait QueueDescriptor {
-    /// How many workers should be ideally active (both running and in allocation queue).
-    fn target_scale(&self) -> u64;
-
-    /// How many workers can be created in a single allocation.
-    fn max_workers_per_alloc(&self) -> u64 {
-        1
-    }
-
     /// Schedule an allocation that will start the corresponding number of workers.
-    /// Returns the string ID of the created allocation.
-    async fn schedule_allocation(&self, worker_count: u64) -> AutoAllocResult<AllocationId>;
+    /// Returns the string ID of the created allocation and its working directory.
+    async fn schedule_allocation(&self, worker_count: u64) -> AutoAllocResult<CreatedAllocation>;
 
     /// Get status of an existing allocation
     async fn get_allocation_status(
         &self,
         allocation_id: &str,
     ) -> AutoAllocResult<Option<AllocationStatus>>;
+
+    /// Remove an allocation from the job manager (whether it is queued or running)
+    async fn remove_allocation(&self, allocation_id: &str) -> AutoAllocResult<()>;
 }
@@ -0,0 +1,266 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use async_trait::async_trait;
+use serde_json::Value;
+use tokio::process::Command;
+
+use crate::server::autoalloc::descriptor::common::{
+    check_command_output, create_allocation_dir, create_worker_command, format_walltime,
+    parse_local_time, run_command, submit_script, AllocationSubmission,
+};
+use crate::server::autoalloc::descriptor::{CreatedAllocation, QueueDescriptor};
+use crate::server::autoalloc::state::AllocationStatus;
+use crate::server::autoalloc::{AutoAllocError, AutoAllocResult};
+use crate::transfer::messages::AddQueueParams;
+
+/// Format of dates printed by `qstat`
+const PBS_DATE_FORMAT: &str = "%a %b %e %H:%M:%S %Y";
+
+pub struct PbsDescriptor {
+    params: AddQueueParams,
+    server_directory: PathBuf,
+    hq_path: PathBuf,
+}
+
+impl PbsDescriptor {
+    pub fn new(params: AddQueueParams, server_directory: PathBuf, hq_path: PathBuf) -> Self {
+        Self {
+            params,
+            server_directory,
+            hq_path,
+        }
+    }
+
+    /// Creates the script and the `qsub` command that submit an allocation with the given number
+    /// of workers. Nothing is submitted, so it can be also used to preview the allocation.
+    pub fn create_submission(&self, worker_count: u64, directory: &Path) -> AllocationSubmission {
+        AllocationSubmission {
+            program: "qsub",
+            args: self.params.additional_args.clone(),
+            script: self.create_script(worker_count, directory),
+        }
+    }
+
+    fn create_script(&self, worker_count: u64, directory: &Path) -> String {
+        let mut script = String::from("#!/bin/bash\n");
+        writeln!(script, "#PBS -N hq-alloc-{}", self.params.name).unwrap();
+        writeln!(script, "#PBS -q {}", self.params.queue).unwrap();
+        match &self.params.node_resources {
+            Some(resources) => {
+                writeln!(script, "#PBS -l select={}:{}", worker_count, resources).unwrap()
+            }
+            None => writeln!(script, "#PBS -l select={}", worker_count).unwrap(),
+        }
+        if let Some(timelimit) = self.params.timelimit {
+            writeln!(script, "#PBS -l walltime={}", format_walltime(timelimit)).unwrap();
+        }
+        writeln!(script, "#PBS -o {}", directory.join("stdout").display()).unwrap();
+        writeln!(script, "#PBS -e {}", directory.join("stderr").display()).unwrap();
+        script.push('\n');
+
+        let worker = create_worker_command(
+            &self.hq_path,
+            &self.server_directory,
+            "pbs",
+            &self.params.worker_env,
+            self.params.idle_timeout,
+        );
+        if worker_count > 1 {
+            // The command is passed to bash in single quotes, quotes inside it have to be escaped
+            let worker = worker.replace('\'', "'\\''");
+            writeln!(script, "pbsdsh -- bash -l -c '{}'", worker).unwrap();
+        } else {
+            writeln!(script, "{}", worker).unwrap();
+        }
+        script
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueDescriptor for PbsDescriptor {
+    async fn schedule_allocation(&self, worker_count: u64) -> AutoAllocResult<CreatedAllocation> {
+        let directory = create_allocation_dir(&self.server_directory, &self.params.name)?;
+        let submission = self.create_submission(worker_count, &directory);
+        let id = submit_script(submission, &directory).await?;
+        Ok(CreatedAllocation {
+            id,
+            working_dir: directory,
+        })
+    }
+
+    async fn get_allocation_status(
+        &self,
+        allocation_id: &str,
+    ) -> AutoAllocResult<Option<AllocationStatus>> {
+        let output = run_command(
+            Command::new("qstat").args(&["-f", "-F", "json", allocation_id]),
+            "qstat",
+        )
+        .await?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success()
+            && (stderr.contains("Job has finished") || stderr.contains("Unknown Job Id"))
+        {
+            return Ok(None);
+        }
+        let output = check_command_output("qstat", output)?;
+        parse_allocation_status(allocation_id, &output)
+    }
+
+    async fn remove_allocation(&self, allocation_id: &str) -> AutoAllocResult<()> {
+        let output = run_command(Command::new("qdel").arg(allocation_id), "qdel").await?;
+        check_command_output("qdel", output).map(|_| ())
+    }
+}
+
+fn parse_allocation_status(
+    allocation_id: &str,
+    output: &str,
+) -> AutoAllocResult<Option<AllocationStatus>> {
+    let data: Value = serde_json::from_str(output)
+        .map_err(|e| AutoAllocError::Custom(format!("Cannot parse qstat output: {}", e)))?;
+    let job = &data["Jobs"][allocation_id];
+    let time = |key: &str| {
+        job[key]
+            .as_str()
+            .and_then(|date| parse_local_time(date, PBS_DATE_FORMAT))
+            .unwrap_or_else(Instant::now)
+    };
+
+    let status = match job["job_state"].as_str() {
+        Some("Q") | Some("H") | Some("W") | Some("T") => Some(AllocationStatus::Queued {
+            queued_at: time("qtime"),
+        }),
+        Some("R") | Some("E") => Some(AllocationStatus::Running {
+            queued_at: time("qtime"),
+            started_at: time("stime"),
+        }),
+        Some(_) => None,
+        None => {
+            return Err(AutoAllocError::Custom(format!(
+                "Cannot find state of allocation {} in qstat output",
+                allocation_id
+            )))
+        }
+    };
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use crate::common::manager::info::ManagerType;
+    use crate::server::autoalloc::descriptor::pbs::{parse_allocation_status, PbsDescriptor};
+    use crate::server::autoalloc::state::AllocationStatus;
+    use crate::transfer::messages::AddQueueParams;
+
+    fn descriptor(timelimit: Option<Duration>) -> PbsDescriptor {
+        PbsDescriptor::new(
+            AddQueueParams {
+                manager: ManagerType::Pbs,
+                name: "foo".to_string(),
+                queue: "qexp".to_string(),
+                target_worker_count: 4,
+                min_workers_per_alloc: 2,
+                max_workers_per_alloc: 2,
+                timelimit,
+                additional_args: vec![],
+                active_window: None,
+                worker_env: vec![],
+                node_resources: None,
+                worker_start_timeout: None,
+                cancel_on_start_timeout: false,
+                idle_timeout: None,
+                event_log: None,
+            },
+            PathBuf::from("/server"),
+            PathBuf::from("/bin/hq"),
+        )
+    }
+
+    #[test]
+    fn test_create_script() {
+        let script = descriptor(Some(Duration::from_secs(3600)))
+            .create_script(2, &PathBuf::from("/server/autoalloc/foo/1"));
+        assert_eq!(
+            script,
+            "#!/bin/bash
+#PBS -N hq-alloc-foo
+#PBS -q qexp
+#PBS -l select=2
+#PBS -l walltime=01:00:00
+#PBS -o /server/autoalloc/foo/1/stdout
+#PBS -e /server/autoalloc/foo/1/stderr
+
+pbsdsh -- bash -l -c '\"/bin/hq\" worker start --manager pbs --server-dir \"/server\"'
+"
+        );
+    }
+
+    #[test]
+    fn test_create_script_escape_quotes() {
+        let mut descriptor = descriptor(None);
+        descriptor.params.worker_env = vec![("A".to_string(), "it's".to_string())];
+        let script = descriptor.create_script(2, &PathBuf::from("/dir"));
+        assert!(script.ends_with(
+            "\npbsdsh -- bash -l -c 'env A=\"it'\\''s\" \"/bin/hq\" worker start --manager pbs \
+             --server-dir \"/server\"'\n"
+        ));
+    }
+
+    #[test]
+    fn test_create_script_node_resources() {
+        let mut descriptor = descriptor(None);
+        descriptor.params.node_resources = Some("ngpus=4:mem=64gb".to_string());
+        let script = descriptor.create_script(2, &PathBuf::from("/dir"));
+        assert!(script.contains("\n#PBS -l select=2:ngpus=4:mem=64gb\n"));
+    }
+
+    #[test]
+    fn test_create_script_single_worker() {
+        let script = descriptor(None).create_script(1, &PathBuf::from("/dir"));
+        assert!(!script.contains("walltime"));
+        assert!(
+            script.ends_with("\n\"/bin/hq\" worker start --manager pbs --server-dir \"/server\"\n")
+        );
+    }
+
+    #[test]
+    fn test_create_submission() {
+        let mut descriptor = descriptor(None);
+        descriptor.params.additional_args = vec!["-A".to_string(), "project".to_string()];
+        let directory = PathBuf::from("/dir");
+        let submission = descriptor.create_submission(2, &directory);
+        assert_eq!(
+            submission.command_line(&directory),
+            "qsub -A project /dir/hq-submit.sh"
+        );
+        assert_eq!(submission.script, descriptor.create_script(2, &directory));
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let output = |state: &str| {
+            format!(
+                r#"{{"Jobs": {{"1.pbs": {{"job_state": "{}", "qtime": "Mon Oct 11 10:00:00 2021"}}}}}}"#,
+                state
+            )
+        };
+        assert!(matches!(
+            parse_allocation_status("1.pbs", &output("Q")).unwrap(),
+            Some(AllocationStatus::Queued { .. })
+        ));
+        assert!(matches!(
+            parse_allocation_status("1.pbs", &output("R")).unwrap(),
+            Some(AllocationStatus::Running { .. })
+        ));
+        assert!(parse_allocation_status("1.pbs", &output("F"))
+            .unwrap()
+            .is_none());
+        assert!(parse_allocation_status("2.pbs", &output("Q")).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use async_trait::async_trait;
+use tokio::process::Command;
+
+use crate::server::autoalloc::descriptor::common::{
+    check_command_output, create_allocation_dir, create_worker_command, format_walltime,
+    parse_local_time, run_command, submit_script, AllocationSubmission,
+};
+use crate::server::autoalloc::descriptor::{CreatedAllocation, QueueDescriptor};
+use crate::server::autoalloc::state::AllocationStatus;
+use crate::server::autoalloc::{AutoAllocError, AutoAllocResult};
+use crate::transfer::messages::AddQueueParams;
+use crate::Map;
+
+/// Format of dates printed by `scontrol`
+const SLURM_DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+pub struct SlurmDescriptor {
+    params: AddQueueParams,
+    server_directory: PathBuf,
+    hq_path: PathBuf,
+}
+
+impl SlurmDescriptor {
+    pub fn new(params: AddQueueParams, server_directory: PathBuf, hq_path: PathBuf) -> Self {
+        Self {
+            params,
+            server_directory,
+            hq_path,
+        }
+    }
+
+    /// Creates the script and the `sbatch` command that submit an allocation with the given
+    /// number of workers. Nothing is submitted, so it can be also used to preview the allocation.
+    pub fn create_submission(&self, worker_count: u64, directory: &Path) -> AllocationSubmission {
+        let mut args = vec!["--parsable".to_string()];
+        args.extend(self.params.additional_args.iter().cloned());
+        AllocationSubmission {
+            program: "sbatch",
+            args,
+            script: self.create_script(worker_count, directory),
+        }
+    }
+
+    fn create_script(&self, worker_count: u64, directory: &Path) -> String {
+        let mut script = String::from("#!/bin/bash\n");
+        writeln!(script, "#SBATCH --job-name=hq-alloc-{}", self.params.name).unwrap();
+        writeln!(script, "#SBATCH --partition={}", self.params.queue).unwrap();
+        writeln!(script, "#SBATCH --nodes={}", worker_count).unwrap();
+        writeln!(script, "#SBATCH --ntasks-per-node=1").unwrap();
+        if let Some(resources) = &self.params.node_resources {
+            for resource in resources.split_whitespace() {
+                writeln!(script, "#SBATCH {}", resource).unwrap();
+            }
+        }
+        if let Some(timelimit) = self.params.timelimit {
+            writeln!(script, "#SBATCH --time={}", format_walltime(timelimit)).unwrap();
+        }
+        writeln!(
+            script,
+            "#SBATCH --output={}",
+            directory.join("stdout").display()
+        )
+        .unwrap();
+        writeln!(
+            script,
+            "#SBATCH --error={}",
+            directory.join("stderr").display()
+        )
+        .unwrap();
+        script.push('\n');
+
+        let worker = create_worker_command(
+            &self.hq_path,
+            &self.server_directory,
+            "slurm",
+            &self.params.worker_env,
+            self.params.idle_timeout,
+        );
+        writeln!(script, "srun {}", worker).unwrap();
+        script
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueDescriptor for SlurmDescriptor {
+    async fn schedule_allocation(&self, worker_count: u64) -> AutoAllocResult<CreatedAllocation> {
+        let directory = create_allocation_dir(&self.server_directory, &self.params.name)?;
+        let submission = self.create_submission(worker_count, &directory);
+        let output = submit_script(submission, &directory).await?;
+
+        // The output has the form `<job-id>[;<cluster>]`
+        let id = output
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        if id.is_empty() {
+            return Err(AutoAllocError::Custom(format!(
+                "Cannot parse allocation id from sbatch output: {}",
+                output
+            )));
+        }
+        Ok(CreatedAllocation {
+            id,
+            working_dir: directory,
+        })
+    }
+
+    async fn get_allocation_status(
+        &self,
+        allocation_id: &str,
+    ) -> AutoAllocResult<Option<AllocationStatus>> {
+        let output = run_command(
+            Command::new("scontrol").args(&["show", "job", allocation_id]),
+            "scontrol",
+        )
+        .await?;
+        if !output.status.success()
+            && String::from_utf8_lossy(&output.stderr).contains("Invalid job id")
+        {
+            return Ok(None);
+        }
+        let output = check_command_output("scontrol", output)?;
+        parse_allocation_status(allocation_id, &output)
+    }
+
+    async fn remove_allocation(&self, allocation_id: &str) -> AutoAllocResult<()> {
+        let output = run_command(Command::new("scancel").arg(allocation_id), "scancel").await?;
+        check_command_output("scancel", output).map(|_| ())
+    }
+}
+
+fn parse_allocation_status(
+    allocation_id: &str,
+    output: &str,
+) -> AutoAllocResult<Option<AllocationStatus>> {
+    let items: Map<&str, &str> = output
+        .split_whitespace()
+        .filter_map(|item| item.split_once('='))
+        .collect();
+    let time = |key: &str| {
+        items
+            .get(key)
+            .and_then(|date| parse_local_time(date, SLURM_DATE_FORMAT))
+            .unwrap_or_else(Instant::now)
+    };
+
+    let status = match items.get("JobState") {
+        Some(&"PENDING") | Some(&"CONFIGURING") | Some(&"REQUEUED") => {
+            Some(AllocationStatus::Queued {
+                queued_at: time("SubmitTime"),
+            })
+        }
+        Some(&"RUNNING") | Some(&"COMPLETING") => Some(AllocationStatus::Running {
+            queued_at: time("SubmitTime"),
+            started_at: time("StartTime"),
+        }),
+        Some(_) => None,
+        None => {
+            return Err(AutoAllocError::Custom(format!(
+                "Cannot find state of allocation {} in scontrol output",
+                allocation_id
+            )))
+        }
+    };
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use crate::common::manager::info::ManagerType;
+    use crate::server::autoalloc::descriptor::slurm::{parse_allocation_status, SlurmDescriptor};
+    use crate::server::autoalloc::state::AllocationStatus;
+    use crate::transfer::messages::AddQueueParams;
+
+    #[test]
+    fn test_create_script() {
+        let descriptor = SlurmDescriptor::new(
+            AddQueueParams {
+                manager: ManagerType::Slurm,
+                name: "foo".to_string(),
+                queue: "qexp".to_string(),
+                target_worker_count: 4,
+                min_workers_per_alloc: 2,
+                max_workers_per_alloc: 2,
+                timelimit: Some(Duration::from_secs(90)),
+                additional_args: vec![],
+                active_window: None,
+                worker_env: vec![],
+                node_resources: Some("--gpus-per-node=4 --mem=64G".to_string()),
+                worker_start_timeout: None,
+                cancel_on_start_timeout: false,
+                idle_timeout: None,
+                event_log: None,
+            },
+            PathBuf::from("/server"),
+            PathBuf::from("/bin/hq"),
+        );
+        assert_eq!(
+            descriptor.create_script(2, &PathBuf::from("/dir")),
+            "#!/bin/bash
+#SBATCH --job-name=hq-alloc-foo
+#SBATCH --partition=qexp
+#SBATCH --nodes=2
+#SBATCH --ntasks-per-node=1
+#SBATCH --gpus-per-node=4
+#SBATCH --mem=64G
+#SBATCH --time=00:01:30
+#SBATCH --output=/dir/stdout
+#SBATCH --error=/dir/stderr
+
+srun \"/bin/hq\" worker start --manager slurm --server-dir \"/server\"
+"
+        );
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let output = |state: &str| {
+            format!(
+                "JobId=1 JobName=hq-alloc-foo\n   JobState={} Reason=None\n   SubmitTime=2021-10-11T10:00:00 StartTime=Unknown",
+                state
+            )
+        };
+        assert!(matches!(
+            parse_allocation_status("1", &output("PENDING")).unwrap(),
+            Some(AllocationStatus::Queued { .. })
+        ));
+        assert!(matches!(
+            parse_allocation_status("1", &output("RUNNING")).unwrap(),
+            Some(AllocationStatus::Running { .. })
+        ));
+        assert!(parse_allocation_status("1", &output("COMPLETED"))
+            .unwrap()
+            .is_none());
+        assert!(parse_allocation_status("1", "JobId=1").is_err());
+    }
+}
@@ -6,8 +6,12 @@
 //! HQ jobs.
 use thiserror::Error;
 
-pub use process::autoalloc_process;
-pub use state::AutoAllocState;
+pub use descriptor::common::{allocation_dir_parent, SUBMIT_SCRIPT_NAME};
+pub use descriptor::pbs::PbsDescriptor;
+pub use descriptor::slurm::SlurmDescriptor;
+pub use descriptor::QueueDescriptor;
+pub use process::{autoalloc_process, cancel_allocation};
+pub use state::{AllocationStatus, AutoAllocState, EventLog, QueueScale};
 
 mod descriptor;
 mod process;
@@ -1,30 +1,52 @@
+use crate::common::manager::info::GetManagerInfo;
 use crate::common::WrappedRcRefCell;
-use crate::server::autoalloc::state::{Allocation, AllocationEvent, AllocationStatus};
-use crate::server::autoalloc::AutoAllocState;
+use crate::server::autoalloc::state::{Allocation, AllocationEvent, AllocationStatus, QueueScale};
+use crate::server::autoalloc::{AutoAllocError, AutoAllocResult, AutoAllocState};
 use crate::server::state::StateRef;
-use std::time::Instant;
+use crate::Set;
+use chrono::Local;
+use std::time::{Duration, Instant};
 
 macro_rules! get_or_return {
     ($e:expr) => {
+        get_or_return!($e, ())
+    };
+    ($e:expr, $default:expr) => {
         match $e {
             Some(v) => v,
-            _ => return,
+            _ => return $default,
         }
     };
 }
 
+/// Minimal delay between two autoalloc invocations when the process is nudged
+/// (e.g. by job submissions).
+const NUDGE_DEBOUNCE: Duration = Duration::from_secs(1);
+
 /// The main entrypoint of the autoalloc background process.
-/// It invokes the autoalloc logic in fixed time intervals.
+/// It invokes the autoalloc logic in fixed time intervals, or sooner when it is nudged.
 pub async fn autoalloc_process(state_ref: StateRef) {
-    let duration = state_ref
-        .get()
-        .get_autoalloc_state()
-        .get()
-        .refresh_interval();
+    let (duration, nudge) = {
+        let state = state_ref.get();
+        let autoalloc = state.get_autoalloc_state().get();
+        (autoalloc.refresh_interval(), autoalloc.nudge_notifier())
+    };
     let mut interval = tokio::time::interval(duration);
+    let mut last_tick: Option<Instant> = None;
     loop {
-        interval.tick().await;
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = nudge.notified() => {
+                if let Some(elapsed) = last_tick.map(|tick| tick.elapsed()) {
+                    if elapsed < NUDGE_DEBOUNCE {
+                        tokio::time::sleep(NUDGE_DEBOUNCE - elapsed).await;
+                    }
+                }
+                log::debug!("Autoalloc was nudged");
+            }
+        }
         autoalloc_tick(&state_ref).await;
+        last_tick = Some(Instant::now());
     }
 }
 
@@ -38,23 +60,61 @@ async fn autoalloc_tick(state_ref: &StateRef) {
         .descriptor_names()
         .map(|v| v.to_string())
         .collect();
+    let connected = connected_allocations(state_ref);
+    let mut waiting_tasks = waiting_task_count(state_ref);
 
+    // The waiting tasks are shared by all descriptors, each descriptor only receives the demand
+    // that was not already covered by the descriptors processed before it.
     for name in descriptors {
-        process_descriptor(&name, &autoalloc_ref).await;
+        let covered = process_descriptor(&name, &autoalloc_ref, &connected, waiting_tasks).await;
+        waiting_tasks = waiting_tasks.saturating_sub(covered);
     }
 }
 
-async fn process_descriptor(name: &str, state: &WrappedRcRefCell<AutoAllocState>) {
+/// Returns the number of tasks that are waiting to be executed.
+/// It is used as an estimate of the number of workers that are needed.
+fn waiting_task_count(state_ref: &StateRef) -> u64 {
+    state_ref
+        .get()
+        .jobs()
+        .map(|job| job.counters.n_waiting_tasks(job.n_tasks()) as u64)
+        .sum()
+}
+
+/// Returns the IDs of allocations from which at least one worker has connected to the server.
+fn connected_allocations(state_ref: &StateRef) -> Set<String> {
+    state_ref
+        .get()
+        .get_workers()
+        .values()
+        .filter_map(|worker| worker.configuration().get_manager_info())
+        .map(|info| info.job_id)
+        .collect()
+}
+
+async fn process_descriptor(
+    name: &str,
+    state: &WrappedRcRefCell<AutoAllocState>,
+    connected: &Set<String>,
+    waiting_tasks: u64,
+) -> u64 {
     // TODO: check only once in a while
-    refresh_allocations(name, state).await;
-    schedule_new_allocations(name, state).await
+    refresh_allocations(name, state, connected).await;
+    schedule_new_allocations(name, state, waiting_tasks).await
 }
 
 /// Go through the allocations of descriptor with the given name and refresh their status.
 /// Queue allocations might become running or finished, running allocations might become finished,
 /// etc.
+///
+/// Running allocations from which no worker has connected within the start timeout of the
+/// descriptor are reported and, if the descriptor requests it, canceled.
 #[allow(clippy::await_holding_refcell_ref)]
-async fn refresh_allocations(name: &str, state_ref: &WrappedRcRefCell<AutoAllocState>) {
+async fn refresh_allocations(
+    name: &str,
+    state_ref: &WrappedRcRefCell<AutoAllocState>,
+    connected: &Set<String>,
+) {
     let allocations = {
         let mut state = state_ref.get_mut();
         let descriptor_state = get_or_return!(state.get_descriptor_mut(name));
@@ -62,7 +122,8 @@ async fn refresh_allocations(name: &str, state_ref: &WrappedRcRefCell<AutoAllocS
         let next_allocations = Vec::with_capacity(descriptor_state.allocations.len());
         std::mem::replace(&mut descriptor_state.allocations, next_allocations)
     };
-    for allocation in allocations {
+    let mut timed_out = vec![];
+    for mut allocation in allocations {
         let descriptor = get_or_return!(state_ref.get().get_descriptor(name))
             .descriptor
             .clone();
@@ -74,11 +135,31 @@ async fn refresh_allocations(name: &str, state_ref: &WrappedRcRefCell<AutoAllocS
         match result {
             Ok(status) => {
                 if let Some(status) = status {
-                    descriptor.allocations.push(Allocation {
-                        id: allocation.id,
-                        worker_count: allocation.worker_count,
-                        status,
-                    });
+                    allocation.status = status;
+                    allocation.worker_connected |= connected.contains(&allocation.id);
+                    if let (AllocationStatus::Running { started_at, .. }, Some(timeout)) =
+                        (&allocation.status, descriptor.worker_start_timeout())
+                    {
+                        if !allocation.worker_connected
+                            && !allocation.start_timeout_exceeded
+                            && started_at.elapsed() >= timeout
+                        {
+                            log::warn!(
+                                "No worker of allocation {} from {} has connected within {}",
+                                allocation.id,
+                                name,
+                                humantime::format_duration(timeout)
+                            );
+                            allocation.start_timeout_exceeded = true;
+                            descriptor.add_event(AllocationEvent::WorkerStartTimeout(
+                                allocation.id.clone(),
+                            ));
+                            if descriptor.cancel_on_start_timeout() {
+                                timed_out.push(allocation.id.clone());
+                            }
+                        }
+                    }
+                    descriptor.allocations.push(allocation);
                 } else {
                     descriptor.add_event(AllocationEvent::Finished(allocation.id));
                 }
@@ -94,57 +175,177 @@ async fn refresh_allocations(name: &str, state_ref: &WrappedRcRefCell<AutoAllocS
             }
         }
     }
+    for allocation_id in timed_out {
+        if let Err(err) = cancel_allocation(state_ref, name, &allocation_id).await {
+            log::error!(
+                "Failed to cancel allocation {} from {}: {}",
+                allocation_id,
+                name,
+                err
+            );
+        }
+    }
+}
+
+/// Removes a single allocation of the descriptor with the given name from the job manager.
+/// The allocation stops being counted towards the target scale of the descriptor, so a
+/// replacement can be scheduled during the next autoalloc tick.
+#[allow(clippy::await_holding_refcell_ref)]
+pub async fn cancel_allocation(
+    state_ref: &WrappedRcRefCell<AutoAllocState>,
+    name: &str,
+    allocation_id: &str,
+) -> AutoAllocResult<()> {
+    let descriptor = {
+        let state = state_ref.get();
+        let descriptor = state
+            .get_descriptor(name)
+            .ok_or_else(|| AutoAllocError::Custom(format!("Descriptor {} not found", name)))?;
+        if !descriptor
+            .allocations
+            .iter()
+            .any(|allocation| allocation.id == allocation_id)
+        {
+            return Err(AutoAllocError::Custom(format!(
+                "Allocation {} not found",
+                allocation_id
+            )));
+        }
+        descriptor.descriptor.clone()
+    };
+
+    descriptor.get().remove_allocation(allocation_id).await?;
+
+    let mut state = state_ref.get_mut();
+    if let Some(descriptor) = state.get_descriptor_mut(name) {
+        descriptor
+            .allocations
+            .retain(|allocation| allocation.id != allocation_id);
+        descriptor.add_event(AllocationEvent::Canceled(allocation_id.to_string()));
+    }
+    log::info!("Allocation {} of {} was canceled", allocation_id, name);
+    Ok(())
+}
+
+/// Computes the number of workers of a new allocation.
+/// The allocation is sized by the number of workers that are demanded (clamped to the
+/// `[min, max]` range), but it never exceeds the number of workers that remain to be scheduled.
+fn allocation_size(remaining: u64, demand: u64, min: u64, max: u64) -> u64 {
+    std::cmp::min(remaining, demand.max(min).min(max))
+}
+
+/// Returns the number of workers of allocations that will be able to pick up waiting tasks,
+/// but have not connected to the server yet.
+fn pending_workers(allocations: &[Allocation]) -> u64 {
+    allocations
+        .iter()
+        .filter(|allocation| match allocation.status {
+            AllocationStatus::Queued { .. } => true,
+            AllocationStatus::Running { .. } => !allocation.worker_connected,
+        })
+        .map(|allocation| allocation.worker_count)
+        .sum()
 }
 
 /// Schedule new allocations for the descriptor with the given name.
+/// Nothing is scheduled outside of the active time window of the descriptor, when the
+/// descriptor is paused or when it waits before the next attempt after a failed submission.
+///
+/// Descriptors with a fixed allocation size are filled up to their target scale. Descriptors
+/// whose allocations are sized by demand only schedule workers for the waiting tasks that are not
+/// already covered by their pending allocations.
+///
+/// Returns the number of workers of this descriptor that cover the given demand.
 #[allow(clippy::await_holding_refcell_ref)]
-async fn schedule_new_allocations(name: &str, state_ref: &WrappedRcRefCell<AutoAllocState>) {
-    let (mut remaining, max_workers_per_alloc) = {
+async fn schedule_new_allocations(
+    name: &str,
+    state_ref: &WrappedRcRefCell<AutoAllocState>,
+    demand: u64,
+) -> u64 {
+    let (mut remaining, mut demand, min_workers_per_alloc, max_workers_per_alloc, mut covered) = {
         let state = state_ref.get();
-        let descriptor = get_or_return!(state.get_descriptor(name));
+        let descriptor = get_or_return!(state.get_descriptor(name), 0);
+        let pending = pending_workers(&descriptor.allocations);
+        if descriptor.is_paused() {
+            log::debug!(
+                "Descriptor {} is paused, no allocations will be created",
+                name
+            );
+            return pending;
+        }
+        if descriptor.is_backing_off(Instant::now()) {
+            log::debug!(
+                "Descriptor {} waits after a failed submission, no allocations will be created",
+                name
+            );
+            return pending;
+        }
+        if !descriptor.is_active_at(Local::now().time()) {
+            log::debug!(
+                "Descriptor {} is outside of its active time window, no allocations will be created",
+                name
+            );
+            return pending;
+        }
         let active_workers = descriptor
             .allocations
             .iter()
             .map(|alloc| alloc.worker_count)
             .sum();
 
-        let descriptor_impl = descriptor.descriptor.get();
-        let scale = descriptor_impl.target_scale();
-        (
-            scale.saturating_sub(active_workers),
-            descriptor_impl.max_workers_per_alloc(),
-        )
+        let QueueScale {
+            target_worker_count: scale,
+            min_workers_per_alloc: min,
+            max_workers_per_alloc: max,
+        } = descriptor.scale();
+        let mut remaining = scale.saturating_sub(active_workers);
+        let demand = demand.saturating_sub(pending);
+        if min < max {
+            let needed = if demand > 0 { demand.max(min) } else { 0 };
+            remaining = remaining.min(needed);
+        }
+        (remaining, demand, min, max, pending)
     };
     while remaining > 0 {
-        let to_schedule = std::cmp::min(remaining, max_workers_per_alloc);
-        let descriptor = get_or_return!(state_ref.get().get_descriptor(name))
+        let to_schedule = allocation_size(
+            remaining,
+            demand,
+            min_workers_per_alloc,
+            max_workers_per_alloc,
+        );
+        let descriptor = get_or_return!(state_ref.get().get_descriptor(name), covered)
             .descriptor
             .clone();
 
         let result = descriptor.get().schedule_allocation(to_schedule).await;
 
         let mut state = state_ref.get_mut();
-        let descriptor = get_or_return!(state.get_descriptor_mut(name));
+        let refresh_interval = state.refresh_interval();
+        let descriptor = get_or_return!(state.get_descriptor_mut(name), covered);
         match result {
-            Ok(id) => {
+            Ok(allocation) => {
                 log::info!("Queued {} workers into {}", to_schedule, name);
-                descriptor.add_event(AllocationEvent::QueueSuccess(id.clone()));
-                descriptor.allocations.push(Allocation {
-                    id,
-                    worker_count: to_schedule,
-                    status: AllocationStatus::Queued {
-                        queued_at: Instant::now(),
-                    },
-                });
+                descriptor.on_submission_success();
+                descriptor.add_event(AllocationEvent::QueueSuccess(allocation.id.clone()));
+                descriptor.allocations.push(Allocation::new(
+                    allocation.id,
+                    to_schedule,
+                    allocation.working_dir,
+                ));
             }
             Err(err) => {
                 log::error!("Failed to queue allocation into {}: {}", name, err);
                 descriptor.add_event(AllocationEvent::QueueFail(err));
+                descriptor.on_submission_failure(refresh_interval);
+                return covered;
             }
         }
 
         remaining -= to_schedule;
+        demand = demand.saturating_sub(to_schedule);
+        covered += to_schedule;
     }
+    covered
 }
 
 #[cfg(test)]
@@ -153,15 +354,25 @@ mod tests {
     use std::time::{Duration, Instant};
 
     use async_trait::async_trait;
+    use tako::common::resources::ResourceRequest;
+    use tako::messages::common::{ProgramDefinition, StdioDef};
 
+    use crate::common::arraydef::IntArray;
+    use crate::common::timeutils::TimeWindow;
     use crate::common::WrappedRcRefCell;
-    use crate::server::autoalloc::descriptor::QueueDescriptor;
-    use crate::server::autoalloc::process::autoalloc_tick;
-    use crate::server::autoalloc::state::{AllocationEvent, AllocationId, AllocationStatus};
+    use crate::server::autoalloc::descriptor::{CreatedAllocation, QueueDescriptor};
+    use crate::server::autoalloc::process::{allocation_size, autoalloc_tick, cancel_allocation};
+    use crate::server::autoalloc::state::{
+        AllocationEvent, AllocationId, AllocationStatus, QueueScale,
+    };
     use crate::server::autoalloc::{AutoAllocError, AutoAllocResult};
+    use crate::server::job::Job;
     use crate::server::state::StateRef;
+    use crate::transfer::messages::JobType;
+    use chrono::Local;
     use std::cell::RefCell;
     use std::rc::Rc;
+    use std::str::FromStr;
 
     #[tokio::test]
     async fn test_do_not_overallocate_queue() {
@@ -227,6 +438,71 @@ mod tests {
         assert_eq!(call_count.get().requests.len(), 0);
     }
 
+    #[tokio::test]
+    async fn test_update_queue_scale() {
+        let state = create_state();
+
+        let requests = WrappedRcRefCell::wrap(Vec::new());
+        add_descriptor(
+            &state,
+            requests.clone(),
+            move |s, worker_count| async move {
+                let mut requests = s.get_mut();
+                requests.push(worker_count);
+                Ok(requests.len().to_string())
+            },
+            move |_, _| async move {
+                Ok(Some(AllocationStatus::Queued {
+                    queued_at: Instant::now(),
+                }))
+            },
+            1,
+            1,
+        )
+        .await;
+
+        autoalloc_tick(&state).await;
+        set_scale(&state, 5, 2, 2);
+        autoalloc_tick(&state).await;
+
+        // The existing allocation is kept, new allocations use the updated scale
+        assert_eq!(*requests.get(), vec![1, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_update_queue_scale_during_submission() {
+        let state = create_state();
+
+        let requests = WrappedRcRefCell::wrap(Vec::new());
+        add_descriptor(
+            &state,
+            requests.clone(),
+            move |s, worker_count| async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let mut requests = s.get_mut();
+                requests.push(worker_count);
+                Ok(requests.len().to_string())
+            },
+            move |_, _| async move {
+                Ok(Some(AllocationStatus::Queued {
+                    queued_at: Instant::now(),
+                }))
+            },
+            1,
+            1,
+        )
+        .await;
+
+        // The queue is updated while the allocation is being submitted
+        tokio::join!(autoalloc_tick(&state), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            set_scale(&state, 3, 1, 1);
+        });
+        autoalloc_tick(&state).await;
+
+        assert_eq!(*requests.get(), vec![1, 1, 1]);
+    }
+
     #[tokio::test]
     async fn test_log_failed_allocation_attempt() {
         let state = create_state();
@@ -254,6 +530,47 @@ mod tests {
         matches!(event.event, AllocationEvent::QueueFail(_));
     }
 
+    #[tokio::test]
+    async fn test_backoff_after_failed_allocation_attempt() {
+        let state = create_state();
+        let call_count = WrappedRcRefCell::wrap(0);
+
+        add_descriptor(
+            &state,
+            call_count.clone(),
+            move |s, _| async move {
+                *s.get_mut() += 1;
+                Err(AutoAllocError::Custom("foo".to_string()))
+            },
+            move |_, _| async move {
+                Ok(Some(AllocationStatus::Queued {
+                    queued_at: Instant::now(),
+                }))
+            },
+            1,
+            1,
+        )
+        .await;
+
+        autoalloc_tick(&state).await;
+        autoalloc_tick(&state).await;
+        assert_eq!(*call_count.get(), 1);
+
+        // The first retry happens after the refresh interval of the state
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        autoalloc_tick(&state).await;
+        assert_eq!(*call_count.get(), 2);
+
+        let state = state.get();
+        let autoalloc = state.get_autoalloc_state().get();
+        let info = autoalloc
+            .get_descriptor("foo")
+            .unwrap()
+            .make_queue_info("foo");
+        assert_eq!(info.failed_submissions, 2);
+        assert!(info.next_submission_at.is_some());
+    }
+
     #[tokio::test]
     async fn test_reschedule_after_job_ends() {
         let state = create_state();
@@ -290,6 +607,299 @@ mod tests {
         assert_eq!(custom_state.get().job_id, 2);
     }
 
+    #[tokio::test]
+    async fn test_do_not_schedule_outside_active_window() {
+        let state = create_state();
+        let call_count = WrappedRcRefCell::wrap(0);
+
+        add_descriptor(
+            &state,
+            call_count.clone(),
+            move |s, _| async move {
+                *s.get_mut() += 1;
+                Ok("1".to_string())
+            },
+            move |_, _| async move {
+                Ok(Some(AllocationStatus::Queued {
+                    queued_at: Instant::now(),
+                }))
+            },
+            1,
+            1,
+        )
+        .await;
+
+        let now = Local::now().time();
+        let window = format!(
+            "{}-{}",
+            (now + chrono::Duration::hours(1)).format("%H:%M"),
+            (now + chrono::Duration::hours(2)).format("%H:%M")
+        );
+        set_active_window(&state, &window);
+        autoalloc_tick(&state).await;
+        assert_eq!(*call_count.get(), 0);
+
+        let window = format!(
+            "{}-{}",
+            (now - chrono::Duration::hours(1)).format("%H:%M"),
+            (now + chrono::Duration::hours(1)).format("%H:%M")
+        );
+        set_active_window(&state, &window);
+        autoalloc_tick(&state).await;
+        assert_eq!(*call_count.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_do_not_schedule_when_paused() {
+        let state = create_state();
+        let call_count = WrappedRcRefCell::wrap(0);
+
+        add_descriptor(
+            &state,
+            call_count.clone(),
+            move |s, _| async move {
+                *s.get_mut() += 1;
+                Ok("1".to_string())
+            },
+            move |_, _| async move {
+                Ok(Some(AllocationStatus::Queued {
+                    queued_at: Instant::now(),
+                }))
+            },
+            1,
+            1,
+        )
+        .await;
+
+        set_paused(&state, true);
+        autoalloc_tick(&state).await;
+        assert_eq!(*call_count.get(), 0);
+
+        set_paused(&state, false);
+        autoalloc_tick(&state).await;
+        assert_eq!(*call_count.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reschedule_after_cancel() {
+        let state = create_state();
+        let call_count = WrappedRcRefCell::wrap(0);
+
+        add_descriptor(
+            &state,
+            call_count.clone(),
+            move |s, _| async move {
+                *s.get_mut() += 1;
+                Ok(s.get().to_string())
+            },
+            move |_, _| async move {
+                Ok(Some(AllocationStatus::Queued {
+                    queued_at: Instant::now(),
+                }))
+            },
+            1,
+            1,
+        )
+        .await;
+
+        autoalloc_tick(&state).await;
+        let autoalloc = state.get().get_autoalloc_state().clone();
+        assert!(cancel_allocation(&autoalloc, "foo", "2").await.is_err());
+        cancel_allocation(&autoalloc, "foo", "1").await.unwrap();
+        autoalloc_tick(&state).await;
+
+        assert_eq!(*call_count.get(), 2);
+        let autoalloc = autoalloc.get();
+        let descriptor = autoalloc.get_descriptor("foo").unwrap();
+        assert!(descriptor
+            .get_events()
+            .iter()
+            .any(|event| matches!(&event.event, AllocationEvent::Canceled(id) if id == "1")));
+        assert_eq!(descriptor.allocations.len(), 1);
+        assert_eq!(descriptor.allocations[0].id, "2");
+    }
+
+    #[test]
+    fn test_allocation_size_follows_demand() {
+        assert_eq!(allocation_size(10, 2, 1, 16), 2);
+        assert_eq!(allocation_size(10, 0, 1, 16), 1);
+        assert_eq!(allocation_size(10, 0, 4, 16), 4);
+        assert_eq!(allocation_size(32, 100, 1, 16), 16);
+        assert_eq!(allocation_size(3, 100, 1, 16), 3);
+        // Fixed size allocations
+        assert_eq!(allocation_size(10, 2, 4, 4), 4);
+    }
+
+    #[tokio::test]
+    async fn test_size_allocations_by_waiting_tasks() {
+        let state = create_state();
+        add_waiting_tasks(&state, 2);
+
+        let requests = WrappedRcRefCell::wrap(Vec::new());
+        add_descriptor(
+            &state,
+            requests.clone(),
+            move |s, worker_count| async move {
+                let mut requests = s.get_mut();
+                requests.push(worker_count);
+                Ok(requests.len().to_string())
+            },
+            move |_, _| async move {
+                Ok(Some(AllocationStatus::Queued {
+                    queued_at: Instant::now(),
+                }))
+            },
+            16,
+            16,
+        )
+        .await;
+        set_scale(&state, 16, 1, 16);
+
+        autoalloc_tick(&state).await;
+        assert_eq!(*requests.get(), vec![2]);
+
+        // The queued allocation already covers the waiting tasks
+        autoalloc_tick(&state).await;
+        assert_eq!(*requests.get(), vec![2]);
+
+        // Only the new demand is allocated
+        add_waiting_tasks(&state, 3);
+        autoalloc_tick(&state).await;
+        assert_eq!(*requests.get(), vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_report_worker_start_timeout() {
+        let state = create_state();
+        add_running_descriptor(&state).await;
+        set_worker_start_timeout(&state, false);
+
+        autoalloc_tick(&state).await;
+        autoalloc_tick(&state).await;
+
+        let autoalloc = state.get().get_autoalloc_state().clone();
+        let autoalloc = autoalloc.get();
+        let descriptor = autoalloc.get_descriptor("foo").unwrap();
+        assert!(descriptor.get_events().iter().any(
+            |event| matches!(&event.event, AllocationEvent::WorkerStartTimeout(id) if id == "1")
+        ));
+        assert_eq!(descriptor.allocations.len(), 1);
+        assert!(descriptor.allocations[0].start_timeout_exceeded);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_on_worker_start_timeout() {
+        let state = create_state();
+        add_running_descriptor(&state).await;
+        set_worker_start_timeout(&state, true);
+
+        autoalloc_tick(&state).await;
+        autoalloc_tick(&state).await;
+
+        let autoalloc = state.get().get_autoalloc_state().clone();
+        let autoalloc = autoalloc.get();
+        let descriptor = autoalloc.get_descriptor("foo").unwrap();
+        assert!(descriptor
+            .get_events()
+            .iter()
+            .any(|event| matches!(&event.event, AllocationEvent::Canceled(id) if id == "1")));
+        assert_eq!(descriptor.allocations.len(), 1);
+        assert_eq!(descriptor.allocations[0].id, "2");
+    }
+
+    async fn add_running_descriptor(state: &StateRef) {
+        add_descriptor(
+            state,
+            WrappedRcRefCell::wrap(0),
+            move |s, _| async move {
+                *s.get_mut() += 1;
+                Ok(s.get().to_string())
+            },
+            move |_, _| async move {
+                Ok(Some(AllocationStatus::Running {
+                    queued_at: Instant::now(),
+                    started_at: Instant::now(),
+                }))
+            },
+            1,
+            1,
+        )
+        .await;
+    }
+
+    fn set_worker_start_timeout(state_ref: &StateRef, cancel: bool) {
+        state_ref
+            .get()
+            .get_autoalloc_state()
+            .get_mut()
+            .get_descriptor_mut("foo")
+            .unwrap()
+            .set_worker_start_timeout(Some(Duration::from_secs(0)), cancel);
+    }
+
+    fn set_scale(state_ref: &StateRef, target: u64, min: u64, max: u64) {
+        state_ref
+            .get()
+            .get_autoalloc_state()
+            .get_mut()
+            .get_descriptor_mut("foo")
+            .unwrap()
+            .set_scale(QueueScale {
+                target_worker_count: target,
+                min_workers_per_alloc: min,
+                max_workers_per_alloc: max,
+            });
+    }
+
+    fn add_waiting_tasks(state_ref: &StateRef, count: u32) {
+        let mut state = state_ref.get_mut();
+        let job_id = state.new_job_id();
+        let base_task_id = state.new_task_id(count);
+        state.add_job(Job::new(
+            JobType::Array(IntArray::from_range(0, count)),
+            job_id,
+            base_task_id,
+            "".to_string(),
+            ProgramDefinition {
+                args: vec![],
+                env: Default::default(),
+                stdout: StdioDef::Null,
+                stderr: StdioDef::Null,
+                cwd: None,
+            },
+            ResourceRequest::default(),
+            false,
+            false,
+            None,
+            None,
+            None,
+            0,
+            None,
+            None,
+            Default::default(),
+        ));
+    }
+
+    fn set_paused(state_ref: &StateRef, paused: bool) {
+        state_ref
+            .get()
+            .get_autoalloc_state()
+            .get_mut()
+            .get_descriptor_mut("foo")
+            .unwrap()
+            .set_paused(paused);
+    }
+
+    fn set_active_window(state_ref: &StateRef, window: &str) {
+        state_ref
+            .get()
+            .get_autoalloc_state()
+            .get_mut()
+            .get_descriptor_mut("foo")
+            .unwrap()
+            .set_active_window(Some(TimeWindow::from_str(window).unwrap()));
+    }
+
     async fn add_descriptor<
         State: 'static,
         ScheduleFn: 'static + Fn(WrappedRcRefCell<State>, u64) -> ScheduleFnFut,
@@ -305,8 +915,6 @@ mod tests {
         max_workers_per_alloc: u64,
     ) {
         struct Queue<ScheduleFn, StatusFn, State> {
-            target_scale: u64,
-            max_workers_per_alloc: u64,
             schedule_fn: ScheduleFn,
             status_fn: StatusFn,
             custom_state: WrappedRcRefCell<State>,
@@ -321,19 +929,16 @@ mod tests {
                 StatusFnFut: Future<Output = AutoAllocResult<Option<AllocationStatus>>>,
             > QueueDescriptor for Queue<ScheduleFn, StatusFn, State>
         {
-            fn target_scale(&self) -> u64 {
-                self.target_scale
-            }
-
-            fn max_workers_per_alloc(&self) -> u64 {
-                self.max_workers_per_alloc
-            }
-
             async fn schedule_allocation(
                 &self,
                 worker_count: u64,
-            ) -> AutoAllocResult<AllocationId> {
-                (self.schedule_fn)(self.custom_state.clone(), worker_count).await
+            ) -> AutoAllocResult<CreatedAllocation> {
+                (self.schedule_fn)(self.custom_state.clone(), worker_count)
+                    .await
+                    .map(|id| CreatedAllocation {
+                        id,
+                        working_dir: Default::default(),
+                    })
             }
 
             async fn get_allocation_status(
@@ -342,11 +947,13 @@ mod tests {
             ) -> AutoAllocResult<Option<AllocationStatus>> {
                 (self.status_fn)(self.custom_state.clone(), allocation_id).await
             }
+
+            async fn remove_allocation(&self, _allocation_id: &str) -> AutoAllocResult<()> {
+                Ok(())
+            }
         }
 
         let queue = Queue {
-            target_scale,
-            max_workers_per_alloc,
             schedule_fn,
             status_fn,
             custom_state,
@@ -359,6 +966,11 @@ mod tests {
             .add_descriptor(
                 "foo".to_string(),
                 WrappedRcRefCell::new_wrapped(Rc::new(RefCell::new(queue))),
+                QueueScale {
+                    target_worker_count: target_scale,
+                    min_workers_per_alloc: max_workers_per_alloc,
+                    max_workers_per_alloc,
+                },
             )
             .unwrap();
     }
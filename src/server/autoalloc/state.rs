@@ -1,41 +1,82 @@
+use crate::common::timeutils::TimeWindow;
 use crate::common::WrappedRcRefCell;
 use crate::server::autoalloc::descriptor::QueueDescriptor;
 use crate::server::autoalloc::{AutoAllocError, AutoAllocResult};
+use crate::transfer::messages::{AllocationInfo, AllocationStatusInfo, QueueStateInfo};
 use crate::Map;
+use chrono::{DateTime, NaiveTime, Utc};
+use serde_json::{json, Value};
 use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 
 const MAX_EVENT_QUEUE_LENGTH: usize = 20;
 
+/// Upper bound of the delay between submission attempts of a failing queue
+const MAX_SUBMISSION_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
 pub type DescriptorName = String;
 
 pub struct AutoAllocState {
     /// How often should the auto alloc process be invoked?
     refresh_interval: Duration,
+    /// Maximum time limit of allocations that can be requested by allocation queues
+    max_allocation_duration: Option<Duration>,
     descriptors: Map<DescriptorName, DescriptorState>,
+    /// Wakes up the autoalloc process before its next regular refresh
+    nudge: Rc<Notify>,
 }
 
 impl AutoAllocState {
     pub fn new(refresh_interval: Duration) -> AutoAllocState {
         Self {
             refresh_interval,
+            max_allocation_duration: None,
             descriptors: Default::default(),
+            nudge: Default::default(),
+        }
+    }
+
+    /// Asks the autoalloc process to refresh the allocation queues as soon as possible,
+    /// instead of waiting for the next refresh interval.
+    /// Multiple nudges that happen before the autoalloc process wakes up are merged.
+    pub fn nudge(&self) {
+        if !self.descriptors.is_empty() {
+            self.nudge.notify_one();
         }
     }
 
+    pub fn nudge_notifier(&self) -> Rc<Notify> {
+        self.nudge.clone()
+    }
+
     pub fn refresh_interval(&self) -> Duration {
         self.refresh_interval
     }
 
+    pub fn set_max_allocation_duration(&mut self, duration: Option<Duration>) {
+        self.max_allocation_duration = duration;
+    }
+
+    pub fn max_allocation_duration(&self) -> Option<Duration> {
+        self.max_allocation_duration
+    }
+
     pub fn add_descriptor(
         &mut self,
         name: DescriptorName,
         descriptor: WrappedRcRefCell<dyn QueueDescriptor>,
+        scale: QueueScale,
     ) -> AutoAllocResult<()> {
         if self.descriptors.contains_key(&name) {
             return Result::Err(AutoAllocError::DescriptorAlreadyExists(name));
         }
-        self.descriptors.insert(name, descriptor.into());
+        self.descriptors
+            .insert(name, DescriptorState::new(descriptor, scale));
         Ok(())
     }
 
@@ -52,28 +93,76 @@ impl AutoAllocState {
     }
 }
 
+/// Number of workers of an allocation queue and the size of its allocations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueScale {
+    /// How many workers should be ideally active (both running and in allocation queue).
+    pub target_worker_count: u64,
+    /// How many workers should be created at least in a single allocation, even if there are
+    /// not enough waiting tasks for them.
+    pub min_workers_per_alloc: u64,
+    /// How many workers can be created in a single allocation.
+    pub max_workers_per_alloc: u64,
+}
+
 /// Represents the state of a single allocation queue.
 pub struct DescriptorState {
     pub descriptor: WrappedRcRefCell<dyn QueueDescriptor>,
+    /// Number of workers of the queue, it can be changed while the descriptor is borrowed.
+    scale: QueueScale,
     /// Allocations that are currently running or are in the queue.
     pub allocations: Vec<Allocation>,
     /// Records events that have occurred on this queue.
     events: VecDeque<AllocationEventHolder>,
+    /// If set, new allocations are only created during this (local) time window.
+    active_window: Option<TimeWindow>,
+    /// How long can a running allocation stay without a connected worker.
+    worker_start_timeout: Option<Duration>,
+    /// If true, allocations that exceed `worker_start_timeout` are canceled.
+    cancel_on_start_timeout: bool,
+    /// If true, no new allocations are created, existing allocations are still refreshed.
+    paused: bool,
+    /// If set, events of the queue are also appended into this file.
+    event_log: Option<EventLog>,
+    /// Number of consecutive failed attempts to submit an allocation.
+    failed_submissions: u32,
+    /// After a failed submission, no new allocations are submitted before this instant.
+    next_submission: Option<Instant>,
 }
 
-impl From<WrappedRcRefCell<dyn QueueDescriptor>> for DescriptorState {
-    fn from(descriptor: WrappedRcRefCell<dyn QueueDescriptor>) -> Self {
+impl DescriptorState {
+    pub fn new(descriptor: WrappedRcRefCell<dyn QueueDescriptor>, scale: QueueScale) -> Self {
         Self {
             descriptor,
+            scale,
             allocations: Default::default(),
             events: Default::default(),
+            active_window: None,
+            worker_start_timeout: None,
+            cancel_on_start_timeout: false,
+            paused: false,
+            event_log: None,
+            failed_submissions: 0,
+            next_submission: None,
         }
     }
-}
 
-impl DescriptorState {
+    pub fn scale(&self) -> QueueScale {
+        self.scale
+    }
+
+    /// Change the number of workers of the queue and the size of its allocations.
+    /// Only allocations scheduled afterwards are affected.
+    pub fn set_scale(&mut self, scale: QueueScale) {
+        self.scale = scale;
+    }
+
     pub fn add_event<T: Into<AllocationEventHolder>>(&mut self, event: T) {
-        self.events.push_back(event.into());
+        let event = event.into();
+        if let Some(event_log) = &self.event_log {
+            event_log.append(&event.event);
+        }
+        self.events.push_back(event);
         if self.events.len() > MAX_EVENT_QUEUE_LENGTH {
             self.events.pop_front();
         }
@@ -82,6 +171,78 @@ impl DescriptorState {
     pub fn get_events(&self) -> &VecDeque<AllocationEventHolder> {
         &self.events
     }
+
+    pub fn set_active_window(&mut self, window: Option<TimeWindow>) {
+        self.active_window = window;
+    }
+
+    pub fn active_window(&self) -> Option<&TimeWindow> {
+        self.active_window.as_ref()
+    }
+
+    pub fn set_worker_start_timeout(&mut self, timeout: Option<Duration>, cancel: bool) {
+        self.worker_start_timeout = timeout;
+        self.cancel_on_start_timeout = cancel;
+    }
+
+    pub fn worker_start_timeout(&self) -> Option<Duration> {
+        self.worker_start_timeout
+    }
+
+    pub fn cancel_on_start_timeout(&self) -> bool {
+        self.cancel_on_start_timeout
+    }
+
+    pub fn set_event_log(&mut self, event_log: Option<EventLog>) {
+        self.event_log = event_log;
+    }
+
+    /// Postpones the next submission exponentially with the number of consecutive failures,
+    /// starting at `base_delay` and capped at `MAX_SUBMISSION_BACKOFF`.
+    pub fn on_submission_failure(&mut self, base_delay: Duration) {
+        self.failed_submissions += 1;
+        let exponent = std::cmp::min(self.failed_submissions - 1, 16);
+        let delay = std::cmp::min(base_delay * 2u32.pow(exponent), MAX_SUBMISSION_BACKOFF);
+        self.next_submission = Some(Instant::now() + delay);
+    }
+
+    pub fn on_submission_success(&mut self) {
+        self.failed_submissions = 0;
+        self.next_submission = None;
+    }
+
+    /// Returns true if new allocations cannot be submitted yet because of previous failures.
+    pub fn is_backing_off(&self, now: Instant) -> bool {
+        self.next_submission.map_or(false, |next| now < next)
+    }
+
+    pub fn make_queue_info(&self, name: &str) -> QueueStateInfo {
+        QueueStateInfo {
+            name: name.to_string(),
+            paused: self.paused,
+            failed_submissions: self.failed_submissions,
+            next_submission_at: self.next_submission.map(|next| {
+                Utc::now()
+                    + chrono::Duration::from_std(next.saturating_duration_since(Instant::now()))
+                        .unwrap_or_else(|_| chrono::Duration::zero())
+            }),
+        }
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Returns true if new allocations can be created at the given (local) time.
+    pub fn is_active_at(&self, time: NaiveTime) -> bool {
+        self.active_window
+            .as_ref()
+            .map_or(true, |window| window.contains(time))
+    }
 }
 
 pub type AllocationId = String;
@@ -90,12 +251,66 @@ pub struct Allocation {
     pub id: AllocationId,
     pub worker_count: u64,
     pub status: AllocationStatus,
+    /// Directory containing the submit script and the output of the allocation
+    pub working_dir: PathBuf,
+    /// At least one worker started by this allocation has connected to the server
+    pub worker_connected: bool,
+    /// The allocation is running, but no worker has connected within the start timeout
+    pub start_timeout_exceeded: bool,
+}
+
+impl Allocation {
+    pub fn new(id: AllocationId, worker_count: u64, working_dir: PathBuf) -> Self {
+        Self {
+            id,
+            worker_count,
+            status: AllocationStatus::Queued {
+                queued_at: Instant::now(),
+            },
+            working_dir,
+            worker_connected: false,
+            start_timeout_exceeded: false,
+        }
+    }
+
+    pub fn make_info(&self, queue: &str) -> AllocationInfo {
+        let to_date = |instant: &Instant| -> DateTime<Utc> {
+            Utc::now()
+                - chrono::Duration::from_std(instant.elapsed())
+                    .unwrap_or_else(|_| chrono::Duration::zero())
+        };
+        AllocationInfo {
+            queue: queue.to_string(),
+            id: self.id.clone(),
+            worker_count: self.worker_count,
+            working_dir: self.working_dir.clone(),
+            status: match &self.status {
+                AllocationStatus::Queued { queued_at } => AllocationStatusInfo::Queued {
+                    queued_at: to_date(queued_at),
+                },
+                AllocationStatus::Running {
+                    queued_at,
+                    started_at,
+                } => AllocationStatusInfo::Running {
+                    queued_at: to_date(queued_at),
+                    started_at: to_date(started_at),
+                    worker_connected: self.worker_connected,
+                    start_timeout_exceeded: self.start_timeout_exceeded,
+                },
+            },
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum AllocationStatus {
-    Queued { queued_at: Instant },
-    Running { started_at: Instant },
+    Queued {
+        queued_at: Instant,
+    },
+    Running {
+        queued_at: Instant,
+        started_at: Instant,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -110,6 +325,75 @@ pub enum AllocationEvent {
     QueueFail(AutoAllocError),
     StatusFail(AutoAllocError),
     Finished(AllocationId),
+    /// The allocation was canceled by the user
+    Canceled(AllocationId),
+    /// The allocation is running, but none of its workers has connected to the server within
+    /// the start timeout of the queue
+    WorkerStartTimeout(AllocationId),
+}
+
+impl AllocationEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            AllocationEvent::QueueSuccess(_) => "queue-success",
+            AllocationEvent::QueueFail(_) => "queue-fail",
+            AllocationEvent::StatusFail(_) => "status-fail",
+            AllocationEvent::Finished(_) => "finished",
+            AllocationEvent::Canceled(_) => "canceled",
+            AllocationEvent::WorkerStartTimeout(_) => "worker-start-timeout",
+        }
+    }
+}
+
+/// File into which events of an allocation queue are appended, one JSON object per line.
+/// Unlike the in-memory events of the queue, the file keeps the whole history of the queue.
+pub struct EventLog {
+    path: PathBuf,
+    queue: DescriptorName,
+}
+
+impl EventLog {
+    pub fn new(path: PathBuf, queue: DescriptorName) -> Self {
+        Self { path, queue }
+    }
+
+    fn append(&self, event: &AllocationEvent) {
+        let entry = create_event_entry(&self.queue, Utc::now(), event);
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", entry));
+        if let Err(e) = result {
+            log::warn!(
+                "Cannot write event of queue {} into {:?}: {}",
+                self.queue,
+                self.path,
+                e
+            );
+        }
+    }
+}
+
+fn create_event_entry(queue: &str, time: DateTime<Utc>, event: &AllocationEvent) -> Value {
+    let mut entry = json!({
+        "timestamp": time.to_rfc3339(),
+        "queue": queue,
+        "event": event.name(),
+    });
+    let fields = entry.as_object_mut().unwrap();
+    match event {
+        AllocationEvent::QueueSuccess(id)
+        | AllocationEvent::Finished(id)
+        | AllocationEvent::Canceled(id)
+        | AllocationEvent::WorkerStartTimeout(id) => {
+            fields.insert("allocation_id".into(), id.as_str().into());
+        }
+        AllocationEvent::QueueFail(error) | AllocationEvent::StatusFail(error) => {
+            fields.insert("error".into(), error.to_string().into());
+        }
+    }
+    entry
 }
 
 impl From<AllocationEvent> for AllocationEventHolder {
@@ -124,52 +408,115 @@ impl From<AllocationEvent> for AllocationEventHolder {
 #[cfg(test)]
 mod tests {
     use crate::common::WrappedRcRefCell;
-    use crate::server::autoalloc::descriptor::QueueDescriptor;
-    use crate::server::autoalloc::state::{AllocationId, AllocationStatus};
+    use crate::server::autoalloc::descriptor::{CreatedAllocation, QueueDescriptor};
+    use crate::server::autoalloc::state::{
+        create_event_entry, AllocationEvent, AllocationStatus, DescriptorState, QueueScale,
+    };
     use crate::server::autoalloc::{AutoAllocError, AutoAllocResult, AutoAllocState};
     use async_trait::async_trait;
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
     use std::cell::RefCell;
     use std::rc::Rc;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
 
     #[test]
-    fn test_add_descriptor_with_same_name_twice() {
-        let mut state = AutoAllocState::new(Duration::from_secs(1));
-
-        #[async_trait(?Send)]
-        impl QueueDescriptor for () {
-            fn target_scale(&self) -> u64 {
-                0
-            }
+    fn test_submission_backoff() {
+        let queue: WrappedRcRefCell<dyn QueueDescriptor> =
+            WrappedRcRefCell::new_wrapped(Rc::new(RefCell::new(())));
+        let mut descriptor = DescriptorState::new(queue, scale());
+        let now = Instant::now();
+        descriptor.on_submission_failure(Duration::from_secs(60));
+        assert!(descriptor.is_backing_off(now + Duration::from_secs(59)));
+        assert!(!descriptor.is_backing_off(now + Duration::from_secs(61)));
 
-            async fn schedule_allocation(
-                &self,
-                _worker_count: u64,
-            ) -> AutoAllocResult<AllocationId> {
-                todo!()
-            }
+        descriptor.on_submission_failure(Duration::from_secs(60));
+        assert!(descriptor.is_backing_off(now + Duration::from_secs(119)));
 
-            async fn get_allocation_status(
-                &self,
-                _allocation_id: &str,
-            ) -> AutoAllocResult<Option<AllocationStatus>> {
-                todo!()
-            }
+        for _ in 0..20 {
+            descriptor.on_submission_failure(Duration::from_secs(60));
         }
+        assert!(!descriptor.is_backing_off(now + Duration::from_secs(10 * 60 + 1)));
+
+        descriptor.on_submission_success();
+        assert!(!descriptor.is_backing_off(now));
+    }
+
+    #[test]
+    fn test_event_entry() {
+        let time = Utc.ymd(2021, 10, 11).and_hms(10, 0, 0);
+        assert_eq!(
+            create_event_entry("foo", time, &AllocationEvent::QueueSuccess("1.pbs".into())),
+            json!({
+                "timestamp": "2021-10-11T10:00:00+00:00",
+                "queue": "foo",
+                "event": "queue-success",
+                "allocation_id": "1.pbs"
+            })
+        );
+        assert_eq!(
+            create_event_entry(
+                "foo",
+                time,
+                &AllocationEvent::QueueFail(AutoAllocError::Custom("qsub failed".into()))
+            ),
+            json!({
+                "timestamp": "2021-10-11T10:00:00+00:00",
+                "queue": "foo",
+                "event": "queue-fail",
+                "error": "qsub failed"
+            })
+        );
+    }
+
+    #[test]
+    fn test_add_descriptor_with_same_name_twice() {
+        let mut state = AutoAllocState::new(Duration::from_secs(1));
 
         let name = "foo".to_string();
         assert!(state
             .add_descriptor(
                 name.clone(),
-                WrappedRcRefCell::new_wrapped(Rc::new(RefCell::new(())))
+                WrappedRcRefCell::new_wrapped(Rc::new(RefCell::new(()))),
+                scale()
             )
             .is_ok());
         assert!(matches!(
             state.add_descriptor(
                 name,
-                WrappedRcRefCell::new_wrapped(Rc::new(RefCell::new(())))
+                WrappedRcRefCell::new_wrapped(Rc::new(RefCell::new(()))),
+                scale()
             ),
             Err(AutoAllocError::DescriptorAlreadyExists(_))
         ));
     }
+
+    #[async_trait(?Send)]
+    impl QueueDescriptor for () {
+        async fn schedule_allocation(
+            &self,
+            _worker_count: u64,
+        ) -> AutoAllocResult<CreatedAllocation> {
+            todo!()
+        }
+
+        async fn get_allocation_status(
+            &self,
+            _allocation_id: &str,
+        ) -> AutoAllocResult<Option<AllocationStatus>> {
+            todo!()
+        }
+
+        async fn remove_allocation(&self, _allocation_id: &str) -> AutoAllocResult<()> {
+            todo!()
+        }
+    }
+
+    fn scale() -> QueueScale {
+        QueueScale {
+            target_worker_count: 1,
+            min_workers_per_alloc: 1,
+            max_workers_per_alloc: 1,
+        }
+    }
 }
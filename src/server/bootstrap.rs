@@ -5,20 +5,25 @@ use std::sync::Arc;
 
 use anyhow::Context;
 use cli_table::{print_stdout, Cell, Style, Table};
+use tako::common::resources::ResourceRequest;
 use tokio::net::TcpListener;
 use tokio::sync::Notify;
 use tokio::task::LocalSet;
 
 use crate::client::globalsettings::GlobalSettings;
+use crate::client::resources::cpu_request_to_string;
 use crate::common::serverdir::{AccessRecord, ServerDir, SYMLINK_PATH};
 use crate::common::setup::setup_interrupt;
+use crate::server::history::{JobHistory, JobHistoryRetention};
 use crate::server::rpc::Backend;
+use crate::server::snapshot::{snapshot_process, SnapshotConfig, SnapshotDatabase};
 use crate::server::state::StateRef;
 use crate::transfer::auth::generate_key;
 use crate::transfer::connection::{ClientConnection, HqConnection};
 use std::time::Duration;
 
 const DEFAULT_AUTOALLOC_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+const JOB_HISTORY_DIRECTORY: &str = "job-history";
 
 enum ServerStatus {
     Offline(AccessRecord),
@@ -29,6 +34,16 @@ pub struct ServerConfig {
     pub host: String,
     pub idle_timeout: Option<Duration>,
     pub autoalloc_interval: Option<Duration>,
+    /// If set, completed jobs will be persisted into a job history with the given retention
+    pub job_history: Option<JobHistoryRetention>,
+    /// Resource request used for tasks submitted without an explicit resource request
+    pub default_resources: Option<ResourceRequest>,
+    /// Maximum time limit of allocations created by allocation queues
+    pub max_allocation_duration: Option<Duration>,
+    /// If set, snapshots of the server state are periodically appended into a SQLite database
+    pub snapshots: Option<SnapshotConfig>,
+    /// Messages from clients that are larger than this size are rejected
+    pub max_message_size: usize,
 }
 
 /// This function initializes the HQ server.
@@ -106,6 +121,14 @@ async fn initialize_server(
             .autoalloc_interval
             .unwrap_or(DEFAULT_AUTOALLOC_REFRESH_INTERVAL),
     );
+    state_ref
+        .get()
+        .get_autoalloc_state()
+        .get_mut()
+        .set_max_allocation_duration(server_cfg.max_allocation_duration);
+    if let Some(resources) = &server_cfg.default_resources {
+        state_ref.get_mut().set_default_resources(resources.clone());
+    }
     let (tako_server, tako_future) = Backend::start(
         state_ref.clone(),
         tako_secret_key.clone(),
@@ -119,15 +142,42 @@ async fn initialize_server(
         tako_server.worker_port(),
         hq_secret_key.clone(),
         tako_secret_key.clone(),
-    );
+    )
+    .with_default_resources(server_cfg.default_resources);
 
-    ServerDir::create(server_directory, &record)?;
+    if let Some(retention) = server_cfg.job_history {
+        let history_path = server_directory.join(JOB_HISTORY_DIRECTORY);
+        let history = JobHistory::open(history_path.clone(), &record.run_name(), retention)
+            .with_context(|| format!("Cannot open job history at {:?}", history_path))?;
+        state_ref.get_mut().set_job_history(history);
+    }
+
+    let snapshot_database = match &server_cfg.snapshots {
+        Some(config) => Some((
+            SnapshotDatabase::open(&config.path, &record)
+                .with_context(|| format!("Cannot open snapshot database {:?}", config.path))?,
+            config.interval,
+        )),
+        None => None,
+    };
+
+    let server_dir = ServerDir::create(server_directory, &record)?;
     print_access_record(gsettings, server_directory, &record);
 
     let stop_notify = Rc::new(Notify::new());
     let stop_cloned = stop_notify.clone();
 
     let key = hq_secret_key;
+    let max_message_size = server_cfg.max_message_size;
+    let snapshot_future = {
+        let state_ref = state_ref.clone();
+        async move {
+            match snapshot_database {
+                Some((database, interval)) => snapshot_process(state_ref, database, interval).await,
+                None => futures::future::pending().await,
+            }
+        }
+    };
     let fut = async move {
         tokio::select! {
             _ = end_flag.notified() => {
@@ -143,9 +193,12 @@ async fn initialize_server(
                 tako_server,
                 client_listener,
                 stop_cloned,
-                key
+                key,
+                server_dir,
+                max_message_size
             ) => { Ok(()) }
             _ = crate::server::autoalloc::autoalloc_process(state_ref) => { Ok(()) }
+            _ = snapshot_future => { Ok(()) }
             r = tako_future => { r.map_err(|e| e.into()) }
         }
     };
@@ -185,6 +238,14 @@ pub fn print_access_record(gsettings: &GlobalSettings, server_dir: &Path, record
             record.start_date().format("%F %T %Z").cell(),
         ],
         vec!["Version".cell().bold(true), record.version().cell()],
+        vec![
+            "Default resources".cell().bold(true),
+            record
+                .default_resources()
+                .map(|r| format!("cpus={}", cpu_request_to_string(r.cpus())))
+                .unwrap_or_else(|| "cpus=1 compact".to_string())
+                .cell(),
+        ],
     ];
     let table = rows.table().color_choice(gsettings.color_policy());
     assert!(print_stdout(table).is_ok());
@@ -216,6 +277,7 @@ mod tests {
 
     use super::ServerStatus;
     use crate::client::globalsettings::GlobalSettings;
+    use crate::transfer::protocol::DEFAULT_MAX_MESSAGE_SIZE;
     use cli_table::ColorChoice;
     use std::future::Future;
     use std::path::Path;
@@ -228,6 +290,11 @@ mod tests {
             host: "localhost".to_string(),
             idle_timeout: None,
             autoalloc_interval: None,
+            job_history: None,
+            default_resources: None,
+            max_allocation_duration: None,
+            snapshots: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
         };
         let notify = Arc::new(Notify::new());
         (
@@ -1,8 +1,10 @@
+use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::Arc;
 
 use futures::{Sink, SinkExt, Stream, StreamExt};
 use orion::kdf::SecretKey;
+use tako::common::resources::{CpuRequest, ResourceRequest};
 use tako::messages::common::{ProgramDefinition, TaskConfiguration};
 use tako::messages::gateway::{
     CancelTasks, FromGatewayMessage, NewTasksMessage, StopWorkerRequest, TaskDef, ToGatewayMessage,
@@ -13,19 +15,28 @@ use tokio::sync::{oneshot, Notify};
 use crate::client::status::{job_status, task_status, Status};
 use crate::common::arraydef::IntArray;
 use crate::common::env::{HQ_ENTRY, HQ_JOB_ID, HQ_SUBMIT_DIR, HQ_TASK_ID};
+use crate::common::manager::info::ManagerType;
+use crate::common::serverdir::ServerDir;
+use crate::common::WrappedRcRefCell;
+use crate::server::autoalloc::{
+    allocation_dir_parent, cancel_allocation, EventLog, PbsDescriptor, QueueDescriptor, QueueScale,
+    SlurmDescriptor, SUBMIT_SCRIPT_NAME,
+};
 use crate::server::job::{Job, JobState};
 use crate::server::rpc::Backend;
-use crate::server::state::StateRef;
+use crate::server::state::{submit_deferred_tasks_after, State, StateRef};
 use crate::stream::server::control::StreamServerControlMessage;
-use crate::transfer::connection::ServerConnection;
+use crate::transfer::connection::{ServerConnection, KEEPALIVE_TIMEOUT};
 use crate::transfer::messages::{
-    CancelJobResponse, FromClientMessage, JobDetail, JobInfoResponse, JobType, ResubmitRequest,
-    Selector, StatsResponse, StopWorkerResponse, SubmitRequest, SubmitResponse, TaskBody,
-    ToClientMessage, WorkerListResponse,
+    AddQueueParams, AutoAllocRequest, AutoAllocResponse, CancelJobResponse, ForgetJobResponse,
+    FromClientMessage, JobDetail, JobInfoResponse, JobType, OutputFlush, ResubmitRequest, Selector,
+    StatsResponse, StopWorkerResponse, SubmitRequest, SubmitResponse, TaskBody, ToClientMessage,
+    UpdateQueueParams, WorkerInfoResponse, WorkerListResponse,
 };
-use crate::{JobId, JobTaskCount, JobTaskId, WorkerId};
+use crate::{JobId, JobTaskCount, JobTaskId, Map, TakoTaskId, WorkerId};
 use bstr::BString;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 pub async fn handle_client_connections(
     state_ref: StateRef,
@@ -33,14 +44,27 @@ pub async fn handle_client_connections(
     listener: TcpListener,
     end_flag: Rc<Notify>,
     key: Arc<SecretKey>,
+    server_dir: ServerDir,
+    max_message_size: usize,
 ) {
     while let Ok((connection, _)) = listener.accept().await {
         let state_ref = state_ref.clone();
         let tako_ref = tako_ref.clone();
         let end_flag = end_flag.clone();
         let key = key.clone();
+        let server_dir = server_dir.clone();
         tokio::task::spawn_local(async move {
-            if let Err(e) = handle_client(connection, state_ref, tako_ref, end_flag, key).await {
+            if let Err(e) = handle_client(
+                connection,
+                state_ref,
+                tako_ref,
+                end_flag,
+                key,
+                server_dir,
+                max_message_size,
+            )
+            .await
+            {
                 log::error!("Client error: {}", e);
             }
         });
@@ -53,12 +77,14 @@ async fn handle_client(
     tako_ref: Backend,
     end_flag: Rc<Notify>,
     key: Arc<SecretKey>,
+    server_dir: ServerDir,
+    max_message_size: usize,
 ) -> crate::Result<()> {
     log::debug!("New client connection");
-    let socket = ServerConnection::accept_client(socket, key).await?;
+    let socket = ServerConnection::accept_client(socket, key, max_message_size).await?;
     let (tx, rx) = socket.split();
 
-    client_rpc_loop(tx, rx, state_ref, tako_ref, end_flag).await;
+    client_rpc_loop(tx, rx, state_ref, tako_ref, end_flag, server_dir).await;
     log::debug!("Client connection ended");
     Ok(())
 }
@@ -72,8 +98,20 @@ pub async fn client_rpc_loop<
     state_ref: StateRef,
     tako_ref: Backend,
     end_flag: Rc<Notify>,
+    server_dir: ServerDir,
 ) {
-    while let Some(message_result) = rx.next().await {
+    loop {
+        let message_result = match tokio::time::timeout(KEEPALIVE_TIMEOUT, rx.next()).await {
+            Ok(Some(message_result)) => message_result,
+            Ok(None) => break,
+            Err(_) => {
+                log::debug!(
+                    "No message received from client within {:?}, closing the connection",
+                    KEEPALIVE_TIMEOUT
+                );
+                break;
+            }
+        };
         match message_result {
             Ok(message) => {
                 let response = match message {
@@ -93,27 +131,39 @@ pub async fn client_rpc_loop<
                         handle_worker_info(&state_ref, msg.worker_id).await
                     }
                     FromClientMessage::StopWorker(msg) => {
-                        handle_worker_stop(&state_ref, &tako_ref, msg.selector).await
+                        handle_worker_stop(&state_ref, &tako_ref, msg.selector, msg.idle_only).await
                     }
                     FromClientMessage::Cancel(msg) => {
                         handle_job_cancel(&state_ref, &tako_ref, msg.selector).await
                     }
+                    FromClientMessage::Forget(msg) => handle_job_forget(&state_ref, msg.selector),
                     FromClientMessage::JobDetail(msg) => {
                         compute_job_detail(&state_ref, msg.selector, msg.include_tasks)
                     }
                     FromClientMessage::Stats => compose_server_stats(&state_ref, &tako_ref).await,
+                    FromClientMessage::AutoAlloc(msg) => {
+                        handle_autoalloc_message(&state_ref, &server_dir, msg).await
+                    }
+                    FromClientMessage::Ping => ToClientMessage::Pong,
+                    FromClientMessage::EnableCompression | FromClientMessage::Compressed(_) => {
+                        // These messages are consumed by the connection
+                        ToClientMessage::Error("Unexpected connection message".to_string())
+                    }
                 };
-                assert!(tx.send(response).await.is_ok());
+                if tx.send(response).await.is_err() {
+                    log::debug!("Cannot send a response to client, closing the connection");
+                    break;
+                }
             }
             Err(e) => {
                 log::error!("Cannot parse client message: {}", e);
-                assert!(tx
+                // The client may already be gone, the connection is closed in any case
+                let _ = tx
                     .send(ToClientMessage::Error(format!(
                         "Cannot parse message: {}",
                         e
                     )))
-                    .await
-                    .is_ok());
+                    .await;
                 return;
             }
         }
@@ -124,8 +174,13 @@ async fn handle_worker_stop(
     state_ref: &StateRef,
     tako_ref: &Backend,
     selector: Selector,
+    idle_only: bool,
 ) -> ToClientMessage {
-    log::debug!("Client asked for worker termination {:?}", selector);
+    log::debug!(
+        "Client asked for worker termination {:?} (idle only: {})",
+        selector,
+        idle_only
+    );
     let mut responses: Vec<(WorkerId, StopWorkerResponse)> = Vec::new();
 
     let worker_ids: Vec<WorkerId> = match selector {
@@ -139,6 +194,15 @@ async fn handle_worker_stop(
             .collect(),
         _ => return ToClientMessage::Error("Invalid command was provided".parse().unwrap()),
     };
+    let worker_ids: Vec<WorkerId> = if idle_only {
+        let idle_workers = state_ref.get().idle_workers();
+        worker_ids
+            .into_iter()
+            .filter(|worker_id| idle_workers.contains(worker_id))
+            .collect()
+    } else {
+        worker_ids
+    };
 
     for worker_id in worker_ids {
         if let Some(worker) = state_ref.get().get_worker(worker_id) {
@@ -196,7 +260,14 @@ fn compute_job_detail(
     for job_id in job_ids {
         let opt_detail = state
             .get_job(job_id)
-            .map(|j| j.make_job_detail(include_tasks));
+            .map(|j| j.make_job_detail(include_tasks))
+            .or_else(|| {
+                let mut detail = state.get_job_history()?.load(job_id)?;
+                if !include_tasks {
+                    detail.tasks.clear();
+                }
+                Some(detail)
+            });
 
         if let Some(detail) = opt_detail {
             responses.push((job_id, Some(detail)));
@@ -207,6 +278,253 @@ fn compute_job_detail(
     ToClientMessage::JobDetailResponse(responses)
 }
 
+async fn handle_autoalloc_message(
+    state_ref: &StateRef,
+    server_dir: &ServerDir,
+    request: AutoAllocRequest,
+) -> ToClientMessage {
+    match request {
+        AutoAllocRequest::Info { descriptor } => {
+            let state = state_ref.get();
+            let autoalloc = state.get_autoalloc_state().get();
+            let names: Vec<&str> = match &descriptor {
+                Some(name) if autoalloc.get_descriptor(name).is_none() => {
+                    return ToClientMessage::Error(format!("Descriptor {} not found", name));
+                }
+                Some(name) => vec![name.as_str()],
+                None => autoalloc.descriptor_names().collect(),
+            };
+            let allocations = names
+                .iter()
+                .flat_map(|&name| {
+                    autoalloc
+                        .get_descriptor(name)
+                        .unwrap()
+                        .allocations
+                        .iter()
+                        .map(move |allocation| allocation.make_info(name))
+                })
+                .collect();
+            let queues = names
+                .iter()
+                .map(|&name| {
+                    autoalloc
+                        .get_descriptor(name)
+                        .unwrap()
+                        .make_queue_info(name)
+                })
+                .collect();
+            ToClientMessage::AutoAllocResponse(AutoAllocResponse::Info {
+                allocations,
+                queues,
+            })
+        }
+        AutoAllocRequest::AddQueue(params) => create_queue(state_ref, server_dir, params),
+        AutoAllocRequest::DryRun(params) => preview_queue(state_ref, server_dir, params),
+        AutoAllocRequest::UpdateQueue(params) => update_queue(state_ref, params),
+        AutoAllocRequest::PauseQueue { descriptor, paused } => {
+            let state = state_ref.get();
+            let mut autoalloc = state.get_autoalloc_state().get_mut();
+            match autoalloc.get_descriptor_mut(&descriptor) {
+                Some(state) => {
+                    state.set_paused(paused);
+                    ToClientMessage::AutoAllocResponse(AutoAllocResponse::QueueUpdated(descriptor))
+                }
+                None => ToClientMessage::Error(format!("Descriptor {} not found", descriptor)),
+            }
+        }
+        AutoAllocRequest::CancelAllocation {
+            descriptor,
+            allocation_id,
+        } => {
+            let autoalloc_ref = state_ref.get().get_autoalloc_state().clone();
+            match cancel_allocation(&autoalloc_ref, &descriptor, &allocation_id).await {
+                Ok(()) => ToClientMessage::AutoAllocResponse(AutoAllocResponse::AllocationCanceled),
+                Err(e) => ToClientMessage::Error(e.to_string()),
+            }
+        }
+        AutoAllocRequest::SubmitScript {
+            descriptor,
+            allocation_id,
+        } => {
+            let state = state_ref.get();
+            let autoalloc = state.get_autoalloc_state().get();
+            let descriptor = match autoalloc.get_descriptor(&descriptor) {
+                Some(descriptor) => descriptor,
+                None => {
+                    return ToClientMessage::Error(format!("Descriptor {} not found", descriptor))
+                }
+            };
+            match descriptor
+                .allocations
+                .iter()
+                .find(|allocation| allocation.id == allocation_id)
+            {
+                Some(allocation) => {
+                    let path = allocation.working_dir.join(SUBMIT_SCRIPT_NAME);
+                    match std::fs::read_to_string(&path) {
+                        Ok(script) => ToClientMessage::AutoAllocResponse(
+                            AutoAllocResponse::SubmitScript(script),
+                        ),
+                        Err(e) => ToClientMessage::Error(format!(
+                            "Cannot read submit script {:?}: {}",
+                            path, e
+                        )),
+                    }
+                }
+                None => ToClientMessage::Error(format!("Allocation {} not found", allocation_id)),
+            }
+        }
+    }
+}
+
+/// Checks the parameters of a new queue against the limits of the server.
+/// The time limit of allocations defaults to the maximum allocation duration of the server.
+fn check_queue_params(state_ref: &StateRef, params: &mut AddQueueParams) -> Result<(), String> {
+    let max_duration = state_ref
+        .get()
+        .get_autoalloc_state()
+        .get()
+        .max_allocation_duration();
+    if let Some(max_duration) = max_duration {
+        match params.timelimit {
+            Some(timelimit) if timelimit > max_duration => {
+                return Err(format!(
+                    "Time limit {} exceeds the maximum allocation duration {} of the server",
+                    humantime::format_duration(timelimit),
+                    humantime::format_duration(max_duration)
+                ));
+            }
+            Some(_) => {}
+            None => params.timelimit = Some(max_duration),
+        }
+    }
+    if let (Some(idle_timeout), Some(timelimit)) = (params.idle_timeout, params.timelimit) {
+        if idle_timeout >= timelimit {
+            return Err(format!(
+                "Idle timeout {} has to be shorter than the time limit {} of allocations",
+                humantime::format_duration(idle_timeout),
+                humantime::format_duration(timelimit)
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn preview_queue(
+    state_ref: &StateRef,
+    server_dir: &ServerDir,
+    mut params: AddQueueParams,
+) -> ToClientMessage {
+    if let Err(e) = check_queue_params(state_ref, &mut params) {
+        return ToClientMessage::Error(e);
+    }
+    let hq_path = match std::env::current_exe() {
+        Ok(path) => path,
+        Err(e) => return ToClientMessage::Error(format!("Cannot find HQ binary: {}", e)),
+    };
+    let server_directory = server_dir.directory().clone();
+    let directory = allocation_dir_parent(&server_directory, &params.name).join("<allocation-dir>");
+    let worker_count = params.max_workers_per_alloc;
+    let submission = match params.manager {
+        ManagerType::Pbs => PbsDescriptor::new(params, server_directory, hq_path)
+            .create_submission(worker_count, &directory),
+        ManagerType::Slurm => SlurmDescriptor::new(params, server_directory, hq_path)
+            .create_submission(worker_count, &directory),
+    };
+    ToClientMessage::AutoAllocResponse(AutoAllocResponse::DryRun {
+        command: submission.command_line(&directory),
+        script: submission.script,
+    })
+}
+
+fn create_queue(
+    state_ref: &StateRef,
+    server_dir: &ServerDir,
+    mut params: AddQueueParams,
+) -> ToClientMessage {
+    if let Err(e) = check_queue_params(state_ref, &mut params) {
+        return ToClientMessage::Error(e);
+    }
+
+    let hq_path = match std::env::current_exe() {
+        Ok(path) => path,
+        Err(e) => return ToClientMessage::Error(format!("Cannot find HQ binary: {}", e)),
+    };
+    let server_directory = server_dir.directory().clone();
+    let name = params.name.clone();
+    let active_window = params.active_window;
+    let worker_start_timeout = params.worker_start_timeout;
+    let cancel_on_start_timeout = params.cancel_on_start_timeout;
+    let scale = QueueScale {
+        target_worker_count: params.target_worker_count,
+        min_workers_per_alloc: params.min_workers_per_alloc,
+        max_workers_per_alloc: params.max_workers_per_alloc,
+    };
+    let event_log = params
+        .event_log
+        .clone()
+        .map(|path| EventLog::new(path, name.clone()));
+    let descriptor: WrappedRcRefCell<dyn QueueDescriptor> = match params.manager {
+        ManagerType::Pbs => WrappedRcRefCell::new_wrapped(Rc::new(RefCell::new(
+            PbsDescriptor::new(params, server_directory, hq_path),
+        ))),
+        ManagerType::Slurm => WrappedRcRefCell::new_wrapped(Rc::new(RefCell::new(
+            SlurmDescriptor::new(params, server_directory, hq_path),
+        ))),
+    };
+
+    let state = state_ref.get();
+    let mut autoalloc = state.get_autoalloc_state().get_mut();
+    match autoalloc.add_descriptor(name.clone(), descriptor, scale) {
+        Ok(()) => {
+            let descriptor = autoalloc.get_descriptor_mut(&name).unwrap();
+            descriptor.set_active_window(active_window);
+            descriptor.set_worker_start_timeout(worker_start_timeout, cancel_on_start_timeout);
+            descriptor.set_event_log(event_log);
+            ToClientMessage::AutoAllocResponse(AutoAllocResponse::QueueCreated(name))
+        }
+        Err(e) => ToClientMessage::Error(e.to_string()),
+    }
+}
+
+fn update_queue(state_ref: &StateRef, params: UpdateQueueParams) -> ToClientMessage {
+    let state = state_ref.get();
+    let mut autoalloc = state.get_autoalloc_state().get_mut();
+    let descriptor = match autoalloc.get_descriptor_mut(&params.name) {
+        Some(descriptor) => descriptor,
+        None => return ToClientMessage::Error(format!("Descriptor {} not found", params.name)),
+    };
+    let scale = descriptor.scale();
+    let target_worker_count = params
+        .target_worker_count
+        .unwrap_or(scale.target_worker_count);
+    let min_workers_per_alloc = params
+        .min_workers_per_alloc
+        .unwrap_or(scale.min_workers_per_alloc);
+    let max_workers_per_alloc = params
+        .max_workers_per_alloc
+        .unwrap_or(scale.max_workers_per_alloc);
+    if max_workers_per_alloc == 0 {
+        return ToClientMessage::Error(
+            "Maximum workers per allocation has to be at least 1".into(),
+        );
+    }
+    if min_workers_per_alloc == 0 || min_workers_per_alloc > max_workers_per_alloc {
+        return ToClientMessage::Error(format!(
+            "Minimum workers per allocation ({}) has to be between 1 and the maximum workers \
+             per allocation ({})",
+            min_workers_per_alloc, max_workers_per_alloc
+        ));
+    }
+    descriptor.set_scale(QueueScale {
+        target_worker_count,
+        min_workers_per_alloc,
+        max_workers_per_alloc,
+    });
+    ToClientMessage::AutoAllocResponse(AutoAllocResponse::QueueUpdated(params.name))
+}
+
 async fn compose_server_stats(_state_ref: &StateRef, backend: &Backend) -> ToClientMessage {
     let stream_stats = {
         let (sender, receiver) = oneshot::channel();
@@ -235,6 +553,36 @@ fn compute_job_info(state_ref: &StateRef, selector: Selector) -> ToClientMessage
     ToClientMessage::JobInfoResponse(JobInfoResponse { jobs })
 }
 
+/// Removes terminated jobs from the server.
+/// Jobs that are still waiting or running are kept.
+fn handle_job_forget(state_ref: &StateRef, selector: Selector) -> ToClientMessage {
+    let mut state = state_ref.get_mut();
+    let job_ids: Vec<JobId> = match selector {
+        Selector::All => state
+            .jobs()
+            .filter(|job| job.is_terminated())
+            .map(|job| job.job_id)
+            .collect(),
+        Selector::LastN(n) => state.last_n_ids(n).collect(),
+        Selector::Specific(array) => array.iter().collect(),
+    };
+
+    let mut responses: Vec<(JobId, ForgetJobResponse)> = Vec::with_capacity(job_ids.len());
+    for job_id in job_ids {
+        let response = match state.get_job(job_id) {
+            None => ForgetJobResponse::InvalidJob,
+            Some(job) if !job.is_terminated() => ForgetJobResponse::NotTerminated,
+            Some(_) => {
+                let job = state.forget_job(job_id).unwrap();
+                log::debug!("Job {} was forgotten", job_id);
+                ForgetJobResponse::Forgotten(job.n_tasks())
+            }
+        };
+        responses.push((job_id, response));
+    }
+    ToClientMessage::ForgetJobResponse(responses)
+}
+
 async fn handle_job_cancel(
     state_ref: &StateRef,
     tako_ref: &Backend,
@@ -254,55 +602,82 @@ async fn handle_job_cancel(
 
     let mut responses: Vec<(JobId, CancelJobResponse)> = Vec::new();
     for job_id in job_ids {
-        let tako_task_ids;
-        {
-            let n_tasks = match state_ref.get().get_job(job_id) {
-                None => {
-                    responses.push((job_id, CancelJobResponse::InvalidJob));
-                    continue;
-                }
-                Some(job) => {
-                    tako_task_ids = job.non_finished_task_ids();
-                    job.n_tasks()
-                }
-            };
-            if tako_task_ids.is_empty() {
-                responses.push((job_id, CancelJobResponse::Canceled(Vec::new(), n_tasks)));
-                continue;
-            }
-        }
-
-        let canceled_tasks = match tako_ref
-            .send_tako_message(FromGatewayMessage::CancelTasks(CancelTasks {
-                tasks: tako_task_ids,
-            }))
-            .await
-            .unwrap()
-        {
-            ToGatewayMessage::CancelTasksResponse(msg) => msg.cancelled_tasks,
-            ToGatewayMessage::Error(msg) => {
-                responses.push((job_id, CancelJobResponse::Failed(msg.message)));
-                continue;
+        let response = match start_job_cancel(state_ref, tako_ref, job_id) {
+            JobCancel::Done(response) => response,
+            JobCancel::Pending {
+                canceled_ids,
+                tako_task_ids,
+            } => {
+                let response = tako_ref
+                    .send_tako_message(FromGatewayMessage::CancelTasks(CancelTasks {
+                        tasks: tako_task_ids,
+                    }))
+                    .await
+                    .unwrap();
+                finish_job_cancel(state_ref, tako_ref, job_id, canceled_ids, response)
             }
-            _ => panic!("Invalid message"),
         };
-
-        let mut state = state_ref.get_mut();
-        let job = state.get_job_mut(job_id).unwrap();
-        let canceled_ids: Vec<_> = canceled_tasks
-            .iter()
-            .map(|tako_id| job.set_cancel_state(*tako_id, tako_ref))
-            .collect();
-        let already_finished = job.n_tasks() - canceled_ids.len() as JobTaskCount;
-        responses.push((
-            job_id,
-            CancelJobResponse::Canceled(canceled_ids, already_finished),
-        ));
+        responses.push((job_id, response));
     }
 
     ToClientMessage::CancelJobResponse(responses)
 }
 
+enum JobCancel {
+    /// The job was canceled without asking tako
+    Done(CancelJobResponse),
+    /// The given tasks have to be canceled in tako
+    Pending {
+        canceled_ids: Vec<JobTaskId>,
+        tako_task_ids: Vec<TakoTaskId>,
+    },
+}
+
+/// Cancels the tasks of a job that are not known to tako (e.g. held tasks) and returns the tasks
+/// that have to be canceled in tako.
+fn start_job_cancel(state_ref: &StateRef, tako_ref: &Backend, job_id: JobId) -> JobCancel {
+    let mut state = state_ref.get_mut();
+    let (canceled_ids, tako_task_ids, n_tasks) = match state.get_job_mut(job_id) {
+        None => return JobCancel::Done(CancelJobResponse::InvalidJob),
+        Some(job) => {
+            // Held tasks are not known to tako, they are canceled directly
+            let canceled_ids = job.cancel_held_tasks(tako_ref);
+            (canceled_ids, job.non_finished_task_ids(), job.n_tasks())
+        }
+    };
+    if tako_task_ids.is_empty() {
+        state.store_job_if_terminated(job_id);
+        let already_finished = n_tasks - canceled_ids.len() as JobTaskCount;
+        return JobCancel::Done(CancelJobResponse::Canceled(canceled_ids, already_finished));
+    }
+    JobCancel::Pending {
+        canceled_ids,
+        tako_task_ids,
+    }
+}
+
+/// Marks the tasks canceled by tako as canceled in the job.
+fn finish_job_cancel(
+    state_ref: &StateRef,
+    tako_ref: &Backend,
+    job_id: JobId,
+    mut canceled_ids: Vec<JobTaskId>,
+    response: ToGatewayMessage,
+) -> CancelJobResponse {
+    let canceled_tasks = match response {
+        ToGatewayMessage::CancelTasksResponse(msg) => msg.cancelled_tasks,
+        ToGatewayMessage::Error(msg) => return CancelJobResponse::Failed(msg.message),
+        _ => panic!("Invalid message"),
+    };
+
+    let mut state = state_ref.get_mut();
+    canceled_ids.extend(state.cancel_tasks(job_id, &canceled_tasks, tako_ref));
+    let already_finished =
+        state.get_job(job_id).unwrap().n_tasks() - canceled_ids.len() as JobTaskCount;
+    state.store_job_if_terminated(job_id);
+    CancelJobResponse::Canceled(canceled_ids, already_finished)
+}
+
 fn make_program_def_for_task(
     program_def: &ProgramDefinition,
     job_id: JobId,
@@ -320,22 +695,107 @@ fn make_program_def_for_task(
     def
 }
 
+/// Job that was added to the server state, but whose tasks were not yet sent to tako.
+struct PreparedJob {
+    job_id: JobId,
+    task_defs: Vec<TaskDef>,
+    job_detail: JobDetail,
+    resource_warning: Option<String>,
+    log: Option<PathBuf>,
+    start_delay: Option<Duration>,
+}
+
 async fn handle_submit(
     state_ref: &StateRef,
     tako_ref: &Backend,
     message: SubmitRequest,
 ) -> ToClientMessage {
-    if message.resources.validate().is_err() {
-        return ToClientMessage::Error("Invalid resource request".to_string());
+    let job = match prepare_job(state_ref, message) {
+        Ok(job) => job,
+        Err(error) => return ToClientMessage::Error(error),
+    };
+
+    if let Some(path) = job.log {
+        let (sender, receiver) = oneshot::channel();
+        tako_ref.send_stream_control(StreamServerControlMessage::RegisterStream {
+            job_id: job.job_id,
+            path,
+            response: sender,
+        });
+        assert!(receiver.await.is_ok());
+    }
+
+    if let Some(delay) = job.start_delay {
+        submit_deferred_tasks_after(state_ref, tako_ref, job.job_id, delay);
+    } else {
+        let response = tako_ref
+            .send_tako_message(FromGatewayMessage::NewTasks(NewTasksMessage {
+                tasks: job.task_defs,
+            }))
+            .await
+            .unwrap();
+        check_new_tasks_response(response);
+    }
+
+    ToClientMessage::SubmitResponse(SubmitResponse {
+        job: job.job_detail,
+        resource_warning: job.resource_warning,
+    })
+}
+
+fn check_new_tasks_response(response: ToGatewayMessage) {
+    match response {
+        ToGatewayMessage::NewTasksResponse(_) => { /* Ok */ }
+        _ => {
+            panic!("Invalid response");
+        }
+    };
+}
+
+/// Validates the submit request and adds the new job to the server state.
+/// The tasks of the job are not sent to tako.
+fn prepare_job(state_ref: &StateRef, message: SubmitRequest) -> Result<PreparedJob, String> {
+    if let (JobType::Array(array), Some(entries)) = (&message.job_type, &message.entries) {
+        if array.id_count() as usize != entries.len() {
+            return Err(format!(
+                "Number of entries ({}) does not match the number of task ids ({})",
+                entries.len(),
+                array.id_count()
+            ));
+        }
+    }
+    if let Some(task_stdin) = &message.task_stdin {
+        match &message.job_type {
+            JobType::Array(array) if array.id_count() as usize == task_stdin.len() => {}
+            JobType::Array(array) => {
+                return Err(format!(
+                    "Number of stdin chunks ({}) does not match the number of task ids ({})",
+                    task_stdin.len(),
+                    array.id_count()
+                ));
+            }
+            JobType::Simple => {
+                return Err("Per-task stdin can be used only with task arrays".to_string());
+            }
+        }
+    }
+    let resources = match message.resources {
+        Some(resources) => resources,
+        None => state_ref.get().default_resources().clone(),
+    };
+    if resources.validate().is_err() {
+        return Err("Invalid resource request".to_string());
     }
-    let resources = message.resources;
     let spec = message.spec;
     let pin = message.pin;
+    let merge_stderr_into_stdout = message.merge_stderr_into_stdout;
     let submit_dir = message.submit_dir;
     let priority = message.priority;
     let time_limit = message.time_limit;
+    let cpu_time_limit = message.cpu_time_limit;
+    let output_flush = message.output_flush;
 
-    let make_task = |job_id, task_id, tako_id, entry: Option<BString>| {
+    let make_task = |job_id, task_id, tako_id, entry: Option<BString>, stdin: Option<BString>| {
         let mut program = make_program_def_for_task(&spec, job_id, task_id, &submit_dir);
         if let Some(e) = entry {
             program.env.insert(HQ_ENTRY.into(), e);
@@ -343,8 +803,12 @@ async fn handle_submit(
         let body_msg = TaskBody {
             program,
             pin,
+            merge_stderr_into_stdout,
             job_id,
             task_id,
+            stdin,
+            output_flush,
+            cpu_time_limit,
         };
         let body = tako::transfer::auth::serialize(&body_msg).unwrap();
         TaskDef {
@@ -361,29 +825,29 @@ async fn handle_submit(
             priority,
         }
     };
-    let (task_defs, job_detail, job_id) = {
+    let (task_defs, job_detail, job_id, resource_warning) = {
         let mut state = state_ref.get_mut();
         let job_id = state.new_job_id();
+        let resource_warning = check_unsatisfiable_resources(&state, &resources);
         let task_count = match &message.job_type {
             JobType::Simple => 1,
             JobType::Array(a) => a.id_count(),
         };
         let tako_base_id = state.new_task_id(task_count);
-        let task_defs = match (&message.job_type, message.entries.clone()) {
-            (JobType::Simple, _) => vec![make_task(job_id, 0, tako_base_id, None)],
-            (JobType::Array(a), None) => a
-                .iter()
-                .zip(tako_base_id..)
-                .map(|(task_id, tako_id)| make_task(job_id, task_id, tako_id, None))
-                .collect(),
-            (JobType::Array(a), Some(entries)) => a
+        let mut task_defs = match &message.job_type {
+            JobType::Simple => vec![make_task(job_id, 0, tako_base_id, None, None)],
+            JobType::Array(a) => a
                 .iter()
                 .zip(tako_base_id..)
-                .zip(entries.into_iter())
-                .map(|((task_id, tako_id), entry)| make_task(job_id, task_id, tako_id, Some(entry)))
+                .enumerate()
+                .map(|(index, (task_id, tako_id))| {
+                    let entry = message.entries.as_ref().map(|e| e[index].clone());
+                    let stdin = message.task_stdin.as_ref().map(|s| s[index].clone());
+                    make_task(job_id, task_id, tako_id, entry, stdin)
+                })
                 .collect(),
         };
-        let job = Job::new(
+        let mut job = Job::new(
             message.job_type,
             job_id,
             tako_base_id,
@@ -391,42 +855,69 @@ async fn handle_submit(
             spec,
             resources,
             pin,
+            merge_stderr_into_stdout,
             message.max_fails,
             message.entries.clone(),
+            message.task_stdin.clone(),
             priority,
             time_limit,
             message.log.clone(),
+            message.metadata,
         );
+        job.submit_dir = submit_dir.clone();
+        job.cpu_time_limit = cpu_time_limit;
+        if message.canary && task_defs.len() > 1 {
+            // Only the first task is submitted, the rest waits until it finishes
+            job.held_tasks = task_defs.split_off(1);
+        }
+        if message.start_delay.is_some() {
+            // The tasks are submitted once the start delay elapses
+            job.deferred_tasks = std::mem::take(&mut task_defs);
+        }
+        job.set_tasks_released(&task_defs);
         let job_detail = job.make_job_detail(false);
+
+        // Do not wait for the next autoalloc refresh if no worker can start the tasks right away.
+        // Idle workers are checked before the job is added, otherwise its own waiting tasks
+        // would make all workers that can run them look busy.
+        if !state.has_idle_worker() {
+            state.get_autoalloc_state().get().nudge();
+        }
         state.add_job(job);
 
-        (task_defs, job_detail, job_id)
+        (task_defs, job_detail, job_id, resource_warning)
     };
-
-    if let Some(log) = message.log {
-        let (sender, receiver) = oneshot::channel();
-        tako_ref.send_stream_control(StreamServerControlMessage::RegisterStream {
-            job_id,
-            path: submit_dir.join(log),
-            response: sender,
-        });
-        assert!(receiver.await.is_ok());
+    if let Some(warning) = &resource_warning {
+        log::warn!("Job {}: {}", job_id, warning);
     }
 
-    match tako_ref
-        .send_tako_message(FromGatewayMessage::NewTasks(NewTasksMessage {
-            tasks: task_defs,
-        }))
-        .await
-        .unwrap()
-    {
-        ToGatewayMessage::NewTasksResponse(_) => { /* Ok */ }
-        _ => {
-            panic!("Invalid response");
-        }
-    };
+    Ok(PreparedJob {
+        job_id,
+        task_defs,
+        job_detail,
+        resource_warning,
+        log: message.log.map(|log| submit_dir.join(log)),
+        start_delay: message.start_delay,
+    })
+}
 
-    ToClientMessage::SubmitResponse(SubmitResponse { job: job_detail })
+/// Checks if the resource request can be satisfied by at least one online worker.
+/// Returns a warning message if there are online workers, but none of them is large enough.
+fn check_unsatisfiable_resources(state: &State, resources: &ResourceRequest) -> Option<String> {
+    let requested = match resources.cpus() {
+        CpuRequest::Compact(n) | CpuRequest::ForceCompact(n) | CpuRequest::Scatter(n) => *n,
+        CpuRequest::All => return None,
+    };
+    let max_cpus = state.max_worker_cpus()?;
+    if requested > max_cpus {
+        Some(format!(
+            "Tasks request {} CPUs, but the largest connected worker has only {} CPUs. \
+             The tasks will not start until a large enough worker connects.",
+            requested, max_cpus
+        ))
+    } else {
+        None
+    }
 }
 
 async fn handle_resubmit(
@@ -442,12 +933,12 @@ async fn handle_resubmit(
                 JobState::SingleTask(s) => {
                     if let Some(filter) = &message.status {
                         if filter.contains(&task_status(s)) {
-                            Some(JobType::Simple)
+                            Some(job.job_type.clone())
                         } else {
                             None
                         }
                     } else {
-                        Some(JobType::Simple)
+                        Some(job.job_type.clone())
                     }
                 }
                 JobState::ManyTasks(s) => {
@@ -476,8 +967,9 @@ async fn handle_resubmit(
             if let Some(job_type) = job_type {
                 let spec = job.program_def.clone();
                 let name = job.name.clone();
-                let resources = job.resources.clone();
-                let entries = job.entries.clone();
+                let resources = Some(job.resources.clone());
+                let entries = select_task_values(&job.job_type, &job_type, &job.entries);
+                let task_stdin = select_task_values(&job.job_type, &job_type, &job.task_stdin);
 
                 SubmitRequest {
                     job_type,
@@ -486,11 +978,18 @@ async fn handle_resubmit(
                     spec,
                     resources,
                     pin: job.pin,
+                    merge_stderr_into_stdout: job.merge_stderr_into_stdout,
                     entries,
-                    submit_dir: std::env::current_dir().unwrap().to_str().unwrap().into(),
+                    task_stdin,
+                    canary: false,
+                    start_delay: None,
+                    metadata: job.metadata.clone(),
+                    submit_dir: job.submit_dir.clone(),
                     priority: job.priority,
                     time_limit: job.time_limit,
+                    cpu_time_limit: job.cpu_time_limit,
                     log: None, // TODO: Reuse log configuration
+                    output_flush: OutputFlush::default(),
                 }
             } else {
                 return ToClientMessage::Error("Nothing was resubmitted".to_string());
@@ -499,7 +998,74 @@ async fn handle_resubmit(
             return ToClientMessage::Error("Invalid job_id".to_string());
         }
     };
-    handle_submit(&state_ref.clone(), &tako_ref.clone(), msg_submit).await
+    if !message.cancel {
+        return handle_submit(&state_ref.clone(), &tako_ref.clone(), msg_submit).await;
+    }
+
+    // The new job is created and both the new tasks and the cancellation of the original job are
+    // sent to tako without handling anything else in between
+    let job = match prepare_job(state_ref, msg_submit) {
+        Ok(job) => job,
+        Err(error) => return ToClientMessage::Error(error),
+    };
+    let new_tasks = tako_ref.queue_tako_message(FromGatewayMessage::NewTasks(NewTasksMessage {
+        tasks: job.task_defs,
+    }));
+    let cancel = start_job_cancel(state_ref, tako_ref, message.job_id);
+    let cancel_tasks = match &cancel {
+        JobCancel::Done(_) => None,
+        JobCancel::Pending { tako_task_ids, .. } => Some(tako_ref.queue_tako_message(
+            FromGatewayMessage::CancelTasks(CancelTasks {
+                tasks: tako_task_ids.clone(),
+            }),
+        )),
+    };
+
+    check_new_tasks_response(new_tasks.await.unwrap());
+    let cancel_response = match cancel {
+        JobCancel::Done(response) => response,
+        JobCancel::Pending { canceled_ids, .. } => {
+            let response = cancel_tasks.unwrap().await.unwrap();
+            finish_job_cancel(state_ref, tako_ref, message.job_id, canceled_ids, response)
+        }
+    };
+    if let CancelJobResponse::Failed(error) = cancel_response {
+        return ToClientMessage::Error(format!(
+            "Job {} was submitted, but job {} cannot be canceled: {}",
+            job.job_id, message.job_id, error
+        ));
+    }
+
+    ToClientMessage::SubmitResponse(SubmitResponse {
+        job: job.job_detail,
+        resource_warning: job.resource_warning,
+    })
+}
+
+/// Selects per-task values (entries, stdin) of the original job that belong
+/// to the tasks of the resubmitted job
+fn select_task_values(
+    original: &JobType,
+    selected: &JobType,
+    values: &Option<Vec<BString>>,
+) -> Option<Vec<BString>> {
+    let values = values.as_ref()?;
+    match (original, selected) {
+        (JobType::Array(original), JobType::Array(selected)) => {
+            let positions: Map<JobTaskId, usize> = original
+                .iter()
+                .enumerate()
+                .map(|(index, task_id)| (task_id, index))
+                .collect();
+            Some(
+                selected
+                    .iter()
+                    .map(|task_id| values[positions[&task_id]].clone())
+                    .collect(),
+            )
+        }
+        _ => Some(values.clone()),
+    }
 }
 
 async fn handle_worker_list(state_ref: &StateRef) -> ToClientMessage {
@@ -517,5 +1083,8 @@ async fn handle_worker_list(state_ref: &StateRef) -> ToClientMessage {
 async fn handle_worker_info(state_ref: &StateRef, worker_id: WorkerId) -> ToClientMessage {
     let state = state_ref.get();
 
-    ToClientMessage::WorkerInfoResponse(state.get_worker(worker_id).map(|w| w.make_info()))
+    ToClientMessage::WorkerInfoResponse(state.get_worker(worker_id).map(|w| WorkerInfoResponse {
+        worker: w.make_info(),
+        running_tasks: state.get_running_tasks(worker_id),
+    }))
 }
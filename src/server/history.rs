@@ -0,0 +1,350 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use tokio::task::JoinHandle;
+
+use crate::transfer::messages::JobDetail;
+use crate::JobId;
+
+const HISTORY_FILE_EXTENSION: &str = "job";
+
+/// Maximum number of history entries that are removed by a single pruning step.
+/// Pruning is done incrementally to avoid blocking the server for a long time.
+const PRUNE_BATCH_SIZE: usize = 16;
+
+#[derive(Debug, Default, Clone)]
+pub struct JobHistoryRetention {
+    pub max_count: Option<usize>,
+    pub max_age: Option<Duration>,
+}
+
+struct HistoryEntry {
+    /// Name of the server run that has stored the job
+    run: String,
+    job_id: JobId,
+    date: DateTime<Utc>,
+}
+
+/// Persists details of completed jobs on disk, so that they can be queried
+/// even when they are no longer present in the server state.
+///
+/// Job ids are reused across server runs, therefore jobs of each server run are
+/// stored in a separate subdirectory.
+pub struct JobHistory {
+    directory: PathBuf,
+    /// Name of the current server run
+    run: String,
+    retention: JobHistoryRetention,
+    /// Stored jobs ordered by their completion date (oldest first)
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl JobHistory {
+    pub fn open(
+        directory: PathBuf,
+        run: &str,
+        retention: JobHistoryRetention,
+    ) -> crate::Result<Self> {
+        std::fs::create_dir_all(directory.join(run))?;
+
+        let mut entries = Vec::new();
+        for run_entry in std::fs::read_dir(&directory)? {
+            let run_entry = run_entry?;
+            if !run_entry.file_type()?.is_dir() {
+                continue;
+            }
+            let run_name = match run_entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            for entry in std::fs::read_dir(run_entry.path())? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some(HISTORY_FILE_EXTENSION) {
+                    continue;
+                }
+                let job_id: Option<JobId> = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok());
+                if let Some(job_id) = job_id {
+                    let modified: DateTime<Utc> = std::fs::metadata(&path)?.modified()?.into();
+                    entries.push(HistoryEntry {
+                        run: run_name.clone(),
+                        job_id,
+                        date: modified,
+                    });
+                }
+            }
+        }
+        entries.sort_unstable_by_key(|entry| entry.date);
+
+        log::debug!(
+            "Job history opened at {:?} with {} job(s)",
+            directory,
+            entries.len()
+        );
+
+        let mut history = JobHistory {
+            directory,
+            run: run.to_string(),
+            retention,
+            entries: entries.into(),
+        };
+        let pruned = history.prune(Utc::now());
+        remove_files(pruned, &history.directory.join(run));
+        Ok(history)
+    }
+
+    fn job_path(&self, run: &str, job_id: JobId) -> PathBuf {
+        self.directory
+            .join(run)
+            .join(format!("{}.{}", job_id, HISTORY_FILE_EXTENSION))
+    }
+
+    /// Stores the job into the history of the current server run.
+    /// The job is written to disk (and pruned jobs are removed) in a blocking task,
+    /// the returned handle can be used to wait for its completion.
+    pub fn store(&mut self, detail: JobDetail) -> JoinHandle<()> {
+        let job_id = detail.info.id;
+        let run = self.run.clone();
+        self.entries
+            .retain(|entry| entry.run != run || entry.job_id != job_id);
+        self.entries.push_back(HistoryEntry {
+            run,
+            job_id,
+            date: detail.completion_date_or_now,
+        });
+
+        let path = self.job_path(&self.run, job_id);
+        let run_directory = self.directory.join(&self.run);
+        let pruned = self.prune(Utc::now());
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = write_job(&path, &detail) {
+                log::error!("Cannot store job {} into job history: {}", job_id, e);
+            }
+            remove_files(pruned, &run_directory);
+        })
+    }
+
+    /// Loads the job with the given id.
+    /// If several server runs have stored a job with this id, the most recently completed one
+    /// is returned.
+    pub fn load(&self, job_id: JobId) -> Option<JobDetail> {
+        let entry = self
+            .entries
+            .iter()
+            .rev()
+            .find(|entry| entry.job_id == job_id)?;
+        let result = File::open(self.job_path(&entry.run, job_id))
+            .map_err(crate::Error::from)
+            .and_then(|file| Ok(rmp_serde::decode::from_read(BufReader::new(file))?));
+        match result {
+            Ok(detail) => Some(detail),
+            Err(e) => {
+                log::error!("Cannot load job {} from job history: {}", job_id, e);
+                None
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn should_prune(&self, now: DateTime<Utc>) -> bool {
+        let too_many = self
+            .retention
+            .max_count
+            .map_or(false, |count| self.entries.len() > count);
+        let too_old = match (self.retention.max_age, self.entries.front()) {
+            (Some(max_age), Some(entry)) => now
+                .signed_duration_since(entry.date)
+                .to_std()
+                .map_or(false, |age| age > max_age),
+            _ => false,
+        };
+        too_many || too_old
+    }
+
+    /// Removes (at most [`PRUNE_BATCH_SIZE`]) oldest entries that violate the retention policy.
+    /// Returns paths of the removed jobs, the caller is responsible for deleting them.
+    fn prune(&mut self, now: DateTime<Utc>) -> Vec<PathBuf> {
+        let mut pruned = Vec::new();
+        for _ in 0..PRUNE_BATCH_SIZE {
+            if !self.should_prune(now) {
+                break;
+            }
+            if let Some(entry) = self.entries.pop_front() {
+                pruned.push(self.job_path(&entry.run, entry.job_id));
+            }
+        }
+        pruned
+    }
+}
+
+fn write_job(path: &Path, detail: &JobDetail) -> crate::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    rmp_serde::encode::write(&mut file, detail)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Removes the given job files.
+/// Directories of previous server runs that become empty are removed too.
+fn remove_files(paths: Vec<PathBuf>, current_run_directory: &Path) {
+    for path in paths {
+        if let Err(e) = std::fs::remove_file(&path) {
+            log::warn!("Cannot remove {:?} from job history: {}", path, e);
+            continue;
+        }
+        if let Some(run_directory) = path.parent().filter(|dir| *dir != current_run_directory) {
+            let is_empty = std::fs::read_dir(run_directory)
+                .map_or(false, |mut entries| entries.next().is_none());
+            if is_empty {
+                let _ = std::fs::remove_dir(run_directory);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tako::common::resources::ResourceRequest;
+    use tako::messages::common::{ProgramDefinition, StdioDef};
+    use tempdir::TempDir;
+
+    use crate::server::history::{JobHistory, JobHistoryRetention};
+    use crate::server::job::Job;
+    use crate::transfer::messages::{JobDetail, JobType};
+    use crate::JobId;
+
+    fn test_job_detail(job_id: JobId) -> JobDetail {
+        Job::new(
+            JobType::Simple,
+            job_id,
+            job_id as u64,
+            format!("job-{}", job_id),
+            ProgramDefinition {
+                args: vec![],
+                env: Default::default(),
+                stdout: StdioDef::Null,
+                stderr: StdioDef::Null,
+                cwd: None,
+            },
+            ResourceRequest::default(),
+            false,
+            false,
+            None,
+            None,
+            None,
+            0,
+            None,
+            None,
+            Default::default(),
+        )
+        .make_job_detail(true)
+    }
+
+    #[tokio::test]
+    async fn test_store_and_load() {
+        let tmp_dir = TempDir::new("hq").unwrap();
+        let mut history =
+            JobHistory::open(tmp_dir.path().join("history"), "run", Default::default()).unwrap();
+        history.store(test_job_detail(1)).await.unwrap();
+        history.store(test_job_detail(2)).await.unwrap();
+
+        assert_eq!(history.load(1).unwrap().info.name, "job-1");
+        assert_eq!(history.load(2).unwrap().info.name, "job-2");
+        assert!(history.load(3).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reopen() {
+        let tmp_dir = TempDir::new("hq").unwrap();
+        let path = tmp_dir.path().join("history");
+        {
+            let mut history = JobHistory::open(path.clone(), "run", Default::default()).unwrap();
+            history.store(test_job_detail(1)).await.unwrap();
+        }
+        let history = JobHistory::open(path, "run", Default::default()).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.load(1).unwrap().info.name, "job-1");
+    }
+
+    #[tokio::test]
+    async fn test_same_job_id_in_different_runs() {
+        let tmp_dir = TempDir::new("hq").unwrap();
+        let path = tmp_dir.path().join("history");
+        {
+            let mut history = JobHistory::open(path.clone(), "run1", Default::default()).unwrap();
+            history.store(test_job_detail(1)).await.unwrap();
+        }
+        let mut history = JobHistory::open(path.clone(), "run2", Default::default()).unwrap();
+        let mut detail = test_job_detail(1);
+        detail.info.name = "new-job".to_string();
+        history.store(detail).await.unwrap();
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.load(1).unwrap().info.name, "new-job");
+        assert!(path.join("run1").join("1.job").exists());
+        assert!(path.join("run2").join("1.job").exists());
+    }
+
+    #[tokio::test]
+    async fn test_prune_by_count() {
+        let tmp_dir = TempDir::new("hq").unwrap();
+        let retention = JobHistoryRetention {
+            max_count: Some(2),
+            max_age: None,
+        };
+        let mut history =
+            JobHistory::open(tmp_dir.path().join("history"), "run", retention).unwrap();
+        for job_id in 1..=4 {
+            history.store(test_job_detail(job_id)).await.unwrap();
+        }
+        assert_eq!(history.len(), 2);
+        assert!(history.load(1).is_none());
+        assert!(history.load(2).is_none());
+        assert!(history.load(3).is_some());
+        assert!(history.load(4).is_some());
+        assert!(!tmp_dir.path().join("history/run/1.job").exists());
+    }
+
+    #[tokio::test]
+    async fn test_prune_removes_empty_run_directory() {
+        let tmp_dir = TempDir::new("hq").unwrap();
+        let path = tmp_dir.path().join("history");
+        {
+            let mut history = JobHistory::open(path.clone(), "run1", Default::default()).unwrap();
+            history.store(test_job_detail(1)).await.unwrap();
+        }
+        let retention = JobHistoryRetention {
+            max_count: Some(1),
+            max_age: None,
+        };
+        let mut history = JobHistory::open(path.clone(), "run2", retention).unwrap();
+        history.store(test_job_detail(1)).await.unwrap();
+
+        assert_eq!(history.len(), 1);
+        assert!(!path.join("run1").exists());
+        assert!(path.join("run2").join("1.job").exists());
+    }
+
+    #[tokio::test]
+    async fn test_store_same_job_twice() {
+        let tmp_dir = TempDir::new("hq").unwrap();
+        let mut history =
+            JobHistory::open(tmp_dir.path().join("history"), "run", Default::default()).unwrap();
+        history.store(test_job_detail(1)).await.unwrap();
+        history.store(test_job_detail(1)).await.unwrap();
+        assert_eq!(history.len(), 1);
+    }
+}
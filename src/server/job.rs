@@ -3,12 +3,14 @@ use tako::messages::common::ProgramDefinition;
 
 use crate::server::rpc::Backend;
 use crate::stream::server::control::StreamServerControlMessage;
-use crate::transfer::messages::{JobDetail, JobInfo, JobType};
+use crate::transfer::messages::{JobDetail, JobInfo, JobType, TaskWaitStats};
 use crate::{JobId, JobTaskCount, JobTaskId, Map, TakoTaskId, WorkerId};
 use bstr::BString;
 use chrono::{DateTime, Utc};
 use std::path::PathBuf;
+use std::time::Duration;
 use tako::common::resources::ResourceRequest;
+use tako::messages::gateway::TaskDef;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum JobTaskState {
@@ -93,6 +95,8 @@ pub struct Job {
     pub state: JobState,
 
     pub log: Option<PathBuf>,
+    /// Directory from which the job was submitted
+    pub submit_dir: PathBuf,
 
     pub job_type: JobType,
     pub name: String,
@@ -100,10 +104,27 @@ pub struct Job {
     pub program_def: ProgramDefinition,
     pub resources: ResourceRequest,
     pub pin: bool,
+    pub merge_stderr_into_stdout: bool,
 
     pub entries: Option<Vec<BString>>,
+    pub task_stdin: Option<Vec<BString>>,
     pub priority: tako::Priority,
     pub time_limit: Option<std::time::Duration>,
+    /// Limit of CPU time of each task, enforced by workers
+    pub cpu_time_limit: Option<std::time::Duration>,
+
+    /// Tasks that are not submitted to tako yet, because they wait for the canary task
+    /// (the first task of the job) to finish successfully
+    pub held_tasks: Vec<TaskDef>,
+    /// Tasks that are not submitted to tako yet, because the start delay of the job
+    /// has not elapsed yet
+    pub deferred_tasks: Vec<TaskDef>,
+    /// Dates when tasks were handed over to tako. Each entry applies to the tasks starting with
+    /// the given id, up to the first task of the next entry.
+    release_dates: Vec<(TakoTaskId, DateTime<Utc>)>,
+
+    /// Informational key/value metadata given by the user
+    pub metadata: Map<String, String>,
 
     pub submission_date: DateTime<Utc>,
     pub completion_date: Option<DateTime<Utc>>,
@@ -121,11 +142,14 @@ impl Job {
         program_def: ProgramDefinition,
         resources: ResourceRequest,
         pin: bool,
+        merge_stderr_into_stdout: bool,
         max_fails: Option<JobTaskCount>,
         entries: Option<Vec<BString>>,
+        task_stdin: Option<Vec<BString>>,
         priority: tako::Priority,
         time_limit: Option<std::time::Duration>,
         job_log: Option<PathBuf>,
+        metadata: Map<String, String>,
     ) -> Self {
         let state = match &job_type {
             JobType::Simple => JobState::SingleTask(JobTaskState::Waiting),
@@ -156,11 +180,19 @@ impl Job {
             program_def,
             resources,
             pin,
+            merge_stderr_into_stdout,
             max_fails,
             entries,
+            task_stdin,
             priority,
             log: job_log,
+            submit_dir: PathBuf::new(),
             time_limit,
+            cpu_time_limit: None,
+            held_tasks: Vec::new(),
+            deferred_tasks: Vec::new(),
+            release_dates: Vec::new(),
+            metadata,
             submission_date: Utc::now(),
             completion_date: None,
         }
@@ -186,14 +218,66 @@ impl Job {
                 Vec::new()
             },
             pin: self.pin,
+            merge_stderr_into_stdout: self.merge_stderr_into_stdout,
             max_fails: self.max_fails,
             priority: self.priority,
             time_limit: self.time_limit,
+            cpu_time_limit: self.cpu_time_limit,
             submission_date: self.submission_date,
             completion_date_or_now: self.completion_date.unwrap_or_else(Utc::now),
+            wait_stats: self.compute_wait_stats(),
+            metadata: self.metadata.clone(),
+            submit_dir: self.submit_dir.clone(),
+            log: self.log.as_ref().map(|log| self.submit_dir.join(log)),
         }
     }
 
+    /// Records that the given tasks were handed over to tako, their wait time is measured from now
+    pub fn set_tasks_released(&mut self, tasks: &[TaskDef]) {
+        if let Some(first_id) = tasks.iter().map(|task| task.id).min() {
+            self.release_dates.push((first_id, Utc::now()));
+        }
+    }
+
+    /// Returns the date when the task was handed over to tako
+    fn release_date(&self, task_id: TakoTaskId) -> DateTime<Utc> {
+        self.release_dates
+            .iter()
+            .rev()
+            .find(|(first_id, _)| *first_id <= task_id)
+            .map(|(_, date)| *date)
+            .unwrap_or(self.submission_date)
+    }
+
+    /// Computes how long the started tasks of this job have waited since they were handed over
+    /// to the scheduler. Tasks deferred by a start delay or held by a canary task are measured
+    /// from the moment they were released.
+    pub fn compute_wait_stats(&self) -> Option<TaskWaitStats> {
+        let mut wait_times: Vec<Duration> = self
+            .iter_task_states()
+            .filter_map(|(tako_id, _, state)| match state {
+                JobTaskState::Running { start_date, .. }
+                | JobTaskState::Finished { start_date, .. }
+                | JobTaskState::Failed { start_date, .. } => Some(
+                    (*start_date - self.release_date(tako_id))
+                        .to_std()
+                        .unwrap_or_default(),
+                ),
+                JobTaskState::Waiting | JobTaskState::Canceled => None,
+            })
+            .collect();
+        if wait_times.is_empty() {
+            return None;
+        }
+        wait_times.sort_unstable();
+        Some(TaskWaitStats {
+            started_tasks: wait_times.len() as JobTaskCount,
+            p50: percentile(&wait_times, 50),
+            p95: percentile(&wait_times, 95),
+            max: *wait_times.last().unwrap(),
+        })
+    }
+
     pub fn make_job_info(&self) -> JobInfo {
         /*let error = match &self.state {
             JobState::Waiting => (JobStatus::Waiting, None),
@@ -240,6 +324,27 @@ impl Job {
         }
     }
 
+    pub fn get_task_state(&self, tako_task_id: TakoTaskId) -> (JobTaskId, &JobTaskState) {
+        match &self.state {
+            JobState::SingleTask(s) => {
+                debug_assert_eq!(tako_task_id, self.base_task_id);
+                (0, s)
+            }
+            JobState::ManyTasks(m) => {
+                let state = &m[&tako_task_id];
+                (state.task_id, &state.state)
+            }
+        }
+    }
+
+    /// Returns the number of tasks that were submitted to tako, but are not running yet
+    pub fn n_pending_tasks(&self) -> JobTaskCount {
+        let n_unreleased = (self.held_tasks.len() + self.deferred_tasks.len()) as JobTaskCount;
+        self.counters
+            .n_waiting_tasks(self.n_tasks())
+            .saturating_sub(n_unreleased)
+    }
+
     pub fn iter_task_states<'a>(
         &'a self,
     ) -> Box<dyn Iterator<Item = (TakoTaskId, JobTaskId, &'a JobTaskState)> + 'a> {
@@ -264,7 +369,23 @@ impl Job {
         result
     }
 
-    pub fn set_running_state(&mut self, tako_task_id: TakoTaskId, worker: WorkerId) {
+    /// Returns true if the given task is a canary task that holds other tasks of the job
+    pub fn is_holding_canary(&self, tako_task_id: TakoTaskId) -> bool {
+        tako_task_id == self.base_task_id && !self.held_tasks.is_empty()
+    }
+
+    /// Cancels tasks that were not submitted to tako yet
+    pub fn cancel_held_tasks(&mut self, backend: &Backend) -> Vec<JobTaskId> {
+        let mut tasks = std::mem::take(&mut self.held_tasks);
+        tasks.append(&mut self.deferred_tasks);
+        tasks
+            .into_iter()
+            .map(|task| self.set_cancel_state(task.id, backend))
+            .collect()
+    }
+
+    /// Returns false if the task was not waiting (e.g. it was already canceled)
+    pub fn set_running_state(&mut self, tako_task_id: TakoTaskId, worker: WorkerId) -> bool {
         let (_, state) = self.get_task_state_mut(tako_task_id);
 
         if matches!(state, JobTaskState::Waiting) {
@@ -273,6 +394,9 @@ impl Job {
                 start_date: Utc::now(),
             };
             self.counters.n_running_tasks += 1;
+            true
+        } else {
+            false
         }
     }
 
@@ -350,3 +474,26 @@ impl Job {
         //));
     }
 }
+
+/// Returns the nearest-rank percentile of a sorted, non-empty slice
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (sorted.len() * percent + 99) / 100;
+    sorted[rank.max(1) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::server::job::percentile;
+
+    #[test]
+    fn test_percentile() {
+        let durations: Vec<_> = (1..=20).map(Duration::from_secs).collect();
+        assert_eq!(percentile(&durations, 50), Duration::from_secs(10));
+        assert_eq!(percentile(&durations, 95), Duration::from_secs(19));
+        assert_eq!(percentile(&durations, 100), Duration::from_secs(20));
+        assert_eq!(percentile(&durations[..1], 50), Duration::from_secs(1));
+        assert_eq!(percentile(&durations[..1], 95), Duration::from_secs(1));
+    }
+}
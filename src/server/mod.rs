@@ -1,7 +1,9 @@
 pub mod autoalloc;
 pub mod bootstrap;
 pub mod client;
+pub mod history;
 pub mod job;
 pub mod rpc;
+pub mod snapshot;
 pub mod state;
 pub mod worker;
@@ -37,13 +37,21 @@ impl Backend {
         &self,
         message: FromGatewayMessage,
     ) -> crate::Result<ToGatewayMessage> {
+        let receiver = self.queue_tako_message(message);
+        Ok(receiver.await.unwrap())
+    }
+
+    /// Sends a message to tako without waiting for its response.
+    /// Tako receives the messages in the order in which they were queued.
+    pub fn queue_tako_message(
+        &self,
+        message: FromGatewayMessage,
+    ) -> oneshot::Receiver<ToGatewayMessage> {
         let (sx, rx) = oneshot::channel::<ToGatewayMessage>();
-        {
-            let mut inner = self.inner.get_mut();
-            inner.tako_responses.push_back(sx);
-            inner.tako_sender.send(message).unwrap();
-        }
-        Ok(rx.await.unwrap())
+        let mut inner = self.inner.get_mut();
+        inner.tako_responses.push_back(sx);
+        inner.tako_sender.send(message).unwrap();
+        rx
     }
 
     pub fn send_stream_control(&self, message: StreamServerControlMessage) {
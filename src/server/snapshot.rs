@@ -0,0 +1,312 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+
+use crate::client::status::{job_status, Status};
+use crate::common::serverdir::AccessRecord;
+use crate::server::autoalloc::AllocationStatus;
+use crate::server::state::{State, StateRef};
+use crate::{JobId, JobTaskCount, WorkerId};
+
+/// Version of the database schema, stored in `PRAGMA user_version`.
+/// It has to be increased whenever the schema below changes.
+const SCHEMA_VERSION: i32 = 2;
+
+/// Job and worker ids start from 1 in each run of the server, therefore each snapshot refers
+/// to the server run (`server_runs`) in which it was taken.
+const SCHEMA: &str = "
+CREATE TABLE server_runs (
+    id INTEGER PRIMARY KEY,
+    start_time TEXT NOT NULL,
+    pid INTEGER NOT NULL
+);
+CREATE TABLE snapshots (
+    id INTEGER PRIMARY KEY,
+    server_run_id INTEGER NOT NULL REFERENCES server_runs(id),
+    time TEXT NOT NULL
+);
+CREATE TABLE jobs (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshots(id),
+    job_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    state TEXT NOT NULL,
+    n_tasks INTEGER NOT NULL,
+    n_running INTEGER NOT NULL,
+    n_finished INTEGER NOT NULL,
+    n_failed INTEGER NOT NULL,
+    n_canceled INTEGER NOT NULL
+);
+CREATE TABLE workers (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshots(id),
+    worker_id INTEGER NOT NULL,
+    hostname TEXT NOT NULL,
+    online INTEGER NOT NULL,
+    n_cpus INTEGER NOT NULL
+);
+CREATE TABLE allocations (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshots(id),
+    queue TEXT NOT NULL,
+    allocation_id TEXT NOT NULL,
+    worker_count INTEGER NOT NULL,
+    state TEXT NOT NULL
+);
+";
+
+pub const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct SnapshotConfig {
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+struct JobRow {
+    job_id: JobId,
+    name: String,
+    state: &'static str,
+    n_tasks: JobTaskCount,
+    n_running: JobTaskCount,
+    n_finished: JobTaskCount,
+    n_failed: JobTaskCount,
+    n_canceled: JobTaskCount,
+}
+
+struct WorkerRow {
+    worker_id: WorkerId,
+    hostname: String,
+    online: bool,
+    n_cpus: u32,
+}
+
+struct AllocationRow {
+    queue: String,
+    allocation_id: String,
+    worker_count: u64,
+    state: &'static str,
+}
+
+/// State of jobs, workers and allocations of the server at a single point in time.
+pub struct Snapshot {
+    time: DateTime<Utc>,
+    jobs: Vec<JobRow>,
+    workers: Vec<WorkerRow>,
+    allocations: Vec<AllocationRow>,
+}
+
+impl Snapshot {
+    pub fn new(state: &State) -> Self {
+        let jobs = state
+            .jobs()
+            .map(|job| {
+                let info = job.make_job_info();
+                JobRow {
+                    job_id: info.id,
+                    state: match job_status(&info) {
+                        Status::Waiting => "WAITING",
+                        Status::Running => "RUNNING",
+                        Status::Finished => "FINISHED",
+                        Status::Failed => "FAILED",
+                        Status::Canceled => "CANCELED",
+                    },
+                    name: info.name,
+                    n_tasks: info.n_tasks,
+                    n_running: info.counters.n_running_tasks,
+                    n_finished: info.counters.n_finished_tasks,
+                    n_failed: info.counters.n_failed_tasks,
+                    n_canceled: info.counters.n_canceled_tasks,
+                }
+            })
+            .collect();
+        let workers = state
+            .get_workers()
+            .values()
+            .map(|worker| WorkerRow {
+                worker_id: worker.worker_id(),
+                hostname: worker.configuration().hostname.clone(),
+                online: worker.is_online(),
+                n_cpus: worker.cpu_count() as u32,
+            })
+            .collect();
+
+        let autoalloc = state.get_autoalloc_state().get();
+        let allocations = autoalloc
+            .descriptor_names()
+            .filter_map(|name| autoalloc.get_descriptor(name).map(|d| (name, d)))
+            .flat_map(|(name, descriptor)| {
+                descriptor
+                    .allocations
+                    .iter()
+                    .map(move |allocation| AllocationRow {
+                        queue: name.to_string(),
+                        allocation_id: allocation.id.clone(),
+                        worker_count: allocation.worker_count,
+                        state: match allocation.status {
+                            AllocationStatus::Queued { .. } => "QUEUED",
+                            AllocationStatus::Running { .. } => "RUNNING",
+                        },
+                    })
+            })
+            .collect();
+
+        Snapshot {
+            time: Utc::now(),
+            jobs,
+            workers,
+            allocations,
+        }
+    }
+}
+
+/// Append-only SQLite database of server snapshots, intended for offline analysis.
+pub struct SnapshotDatabase {
+    connection: Connection,
+    /// Row of the current server run in the `server_runs` table
+    server_run_id: i64,
+}
+
+impl SnapshotDatabase {
+    /// Opens (or creates) the database and registers a new server run in it
+    pub fn open(path: &Path, record: &AccessRecord) -> crate::Result<Self> {
+        let connection = Connection::open(path)?;
+        let version: i32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        match version {
+            0 => {
+                connection.execute_batch(SCHEMA)?;
+                connection.pragma_update(None, "user_version", &SCHEMA_VERSION)?;
+            }
+            SCHEMA_VERSION => {}
+            _ => {
+                return crate::common::error::error(format!(
+                    "Snapshot database {:?} has schema version {}, but version {} is required",
+                    path, version, SCHEMA_VERSION
+                ))
+            }
+        }
+        connection.execute(
+            "INSERT INTO server_runs (start_time, pid) VALUES (?1, ?2)",
+            params![record.start_date().to_rfc3339(), record.pid()],
+        )?;
+        let server_run_id = connection.last_insert_rowid();
+        Ok(Self {
+            connection,
+            server_run_id,
+        })
+    }
+
+    pub fn store(&mut self, snapshot: &Snapshot) -> crate::Result<()> {
+        let tx = self.connection.transaction()?;
+        tx.execute(
+            "INSERT INTO snapshots (server_run_id, time) VALUES (?1, ?2)",
+            params![self.server_run_id, snapshot.time.to_rfc3339()],
+        )?;
+        let snapshot_id = tx.last_insert_rowid();
+        {
+            let mut insert_job = tx.prepare(
+                "INSERT INTO jobs (snapshot_id, job_id, name, state, n_tasks, n_running, \
+                 n_finished, n_failed, n_canceled) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+            for job in &snapshot.jobs {
+                insert_job.execute(params![
+                    snapshot_id,
+                    job.job_id,
+                    job.name,
+                    job.state,
+                    job.n_tasks,
+                    job.n_running,
+                    job.n_finished,
+                    job.n_failed,
+                    job.n_canceled
+                ])?;
+            }
+            let mut insert_worker = tx.prepare(
+                "INSERT INTO workers (snapshot_id, worker_id, hostname, online, n_cpus) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for worker in &snapshot.workers {
+                insert_worker.execute(params![
+                    snapshot_id,
+                    worker.worker_id as i64,
+                    worker.hostname,
+                    worker.online,
+                    worker.n_cpus
+                ])?;
+            }
+            let mut insert_allocation = tx.prepare(
+                "INSERT INTO allocations (snapshot_id, queue, allocation_id, worker_count, state) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for allocation in &snapshot.allocations {
+                insert_allocation.execute(params![
+                    snapshot_id,
+                    allocation.queue,
+                    allocation.allocation_id,
+                    allocation.worker_count as i64,
+                    allocation.state
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// Periodically appends snapshots of the server state into the database.
+/// The database is written outside of the main thread to avoid blocking the server.
+pub async fn snapshot_process(
+    state_ref: StateRef,
+    mut database: SnapshotDatabase,
+    interval: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let snapshot = Snapshot::new(&state_ref.get());
+        database = tokio::task::spawn_blocking(move || {
+            if let Err(e) = database.store(&snapshot) {
+                log::error!("Cannot store server snapshot: {}", e);
+            }
+            database
+        })
+        .await
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::serverdir::AccessRecord;
+    use crate::server::snapshot::{Snapshot, SnapshotDatabase};
+    use crate::server::state::StateRef;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_store_snapshots() {
+        let dir = TempDir::new("hq").unwrap();
+        let path = dir.path().join("snapshots.db");
+        let state = StateRef::new(Duration::from_secs(1));
+        let record =
+            AccessRecord::new("foo".into(), 42, 43, Default::default(), Default::default());
+
+        let mut database = SnapshotDatabase::open(&path, &record).unwrap();
+        database.store(&Snapshot::new(&state.get())).unwrap();
+        drop(database);
+
+        // Reopening keeps the existing snapshots, new snapshots belong to a new server run
+        let mut database = SnapshotDatabase::open(&path, &record).unwrap();
+        database.store(&Snapshot::new(&state.get())).unwrap();
+        let count = |query: &str| -> i64 {
+            database
+                .connection
+                .query_row(query, [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(count("SELECT COUNT(*) FROM snapshots"), 2);
+        assert_eq!(count("SELECT COUNT(*) FROM server_runs"), 2);
+        assert_eq!(
+            count("SELECT COUNT(DISTINCT server_run_id) FROM snapshots"),
+            2
+        );
+    }
+}
@@ -1,17 +1,20 @@
 use std::collections::BTreeMap;
 
+use tako::common::resources::{CpuRequest, NumOfCpus, ResourceRequest};
 use tako::messages::gateway::{
-    CancelTasks, FromGatewayMessage, LostWorkerMessage, LostWorkerReason, NewWorkerMessage,
-    TaskFailedMessage, TaskState, TaskUpdate, ToGatewayMessage,
+    CancelTasks, FromGatewayMessage, LostWorkerMessage, LostWorkerReason, NewTasksMessage,
+    NewWorkerMessage, TaskDef, TaskFailedMessage, TaskState, TaskUpdate, ToGatewayMessage,
 };
 
+use crate::common::logging::{with_log_context, LogContext};
 use crate::common::WrappedRcRefCell;
 use crate::server::autoalloc::AutoAllocState;
-use crate::server::job::Job;
+use crate::server::history::JobHistory;
+use crate::server::job::{Job, JobTaskState};
 use crate::server::rpc::Backend;
 use crate::server::worker::Worker;
 use crate::transfer::messages::LostWorkerReasonInfo;
-use crate::{JobId, JobTaskCount, Map, TakoTaskId, WorkerId};
+use crate::{JobId, JobTaskCount, JobTaskId, Map, Set, TakoTaskId, WorkerId};
 use std::cmp::min;
 use std::time::Duration;
 
@@ -36,7 +39,14 @@ pub struct State {
     job_id_counter: JobId,
     task_id_counter: TakoTaskId,
 
+    /// Tasks that are currently running on the individual workers
+    running_tasks: Map<WorkerId, Set<TakoTaskId>>,
+
     autoalloc_state: WrappedRcRefCell<AutoAllocState>,
+    job_history: Option<JobHistory>,
+
+    /// Resource request used for tasks submitted without an explicit resource request
+    default_resources: ResourceRequest,
 }
 
 pub type StateRef = WrappedRcRefCell<State>;
@@ -59,10 +69,8 @@ fn cancel_tasks_from_callback(
         match response {
             ToGatewayMessage::CancelTasksResponse(msg) => {
                 let mut state = state_ref.get_mut();
-                let job = state.get_job_mut(job_id).unwrap();
-                for tako_id in msg.cancelled_tasks {
-                    job.set_cancel_state(tako_id, &tako_ref);
-                }
+                state.cancel_tasks(job_id, &msg.cancelled_tasks, &tako_ref);
+                state.store_job_if_terminated(job_id);
             }
             ToGatewayMessage::Error(msg) => {
                 log::debug!("Canceling job {} failed: {}", job_id, msg.message);
@@ -74,6 +82,56 @@ fn cancel_tasks_from_callback(
     });
 }
 
+/// Submits tasks that were held by a canary task of a job
+fn submit_tasks_from_callback(tako_ref: &Backend, job_id: JobId, tasks: Vec<TaskDef>) {
+    let tako_ref = tako_ref.clone();
+    tokio::task::spawn_local(async move {
+        let message = FromGatewayMessage::NewTasks(NewTasksMessage { tasks });
+        match tako_ref.send_tako_message(message).await.unwrap() {
+            ToGatewayMessage::NewTasksResponse(_) => { /* Ok */ }
+            ToGatewayMessage::Error(msg) => {
+                log::error!(
+                    "Submitting held tasks of job {} failed: {}",
+                    job_id,
+                    msg.message
+                );
+            }
+            _ => {
+                panic!("Invalid message");
+            }
+        };
+    });
+}
+
+/// Submits the deferred tasks of a job once the given delay elapses
+pub fn submit_deferred_tasks_after(
+    state_ref: &StateRef,
+    tako_ref: &Backend,
+    job_id: JobId,
+    delay: Duration,
+) {
+    let state_ref = state_ref.clone();
+    let tako_ref = tako_ref.clone();
+    tokio::task::spawn_local(async move {
+        tokio::time::sleep(delay).await;
+        let tasks = match state_ref.get_mut().get_job_mut(job_id) {
+            Some(job) => {
+                let tasks = std::mem::take(&mut job.deferred_tasks);
+                job.set_tasks_released(&tasks);
+                tasks
+            }
+            None => return,
+        };
+        if !tasks.is_empty() {
+            log::debug!(
+                "Start delay of job {} has elapsed, submitting its tasks",
+                job_id
+            );
+            submit_tasks_from_callback(&tako_ref, job_id, tasks);
+        }
+    });
+}
+
 impl State {
     pub fn get_job(&self, job_id: JobId) -> Option<&Job> {
         self.jobs.get(&job_id)
@@ -87,6 +145,13 @@ impl State {
         self.jobs.values()
     }
 
+    /// Removes a job from the server, returns the removed job
+    pub fn forget_job(&mut self, job_id: JobId) -> Option<Job> {
+        let job = self.jobs.remove(&job_id)?;
+        self.base_task_id_to_job_id.remove(&job.base_task_id);
+        Some(job)
+    }
+
     pub fn add_worker(&mut self, worker: Worker) {
         let worker_id = worker.worker_id();
         assert!(self.workers.insert(worker_id, worker).is_none())
@@ -101,14 +166,14 @@ impl State {
         assert!(self.jobs.insert(job_id, job).is_none());
     }
 
-    pub fn get_job_mut_by_tako_task_id(&mut self, task_id: TakoTaskId) -> Option<&mut Job> {
+    pub fn get_job_by_tako_task_id(&self, task_id: TakoTaskId) -> Option<&Job> {
         let job_id: JobId = *self
             .base_task_id_to_job_id
             .range(..=task_id)
             .rev()
             .next()?
             .1;
-        let job = self.jobs.get_mut(&job_id)?;
+        let job = self.jobs.get(&job_id)?;
         if task_id < job.base_task_id + job.n_tasks() as u64 {
             Some(job)
         } else {
@@ -116,6 +181,11 @@ impl State {
         }
     }
 
+    pub fn get_job_mut_by_tako_task_id(&mut self, task_id: TakoTaskId) -> Option<&mut Job> {
+        let job_id = self.get_job_by_tako_task_id(task_id)?.job_id;
+        self.jobs.get_mut(&job_id)
+    }
+
     pub fn new_job_id(&mut self) -> JobId {
         let id = self.job_id_counter;
         self.job_id_counter += 1;
@@ -141,20 +211,149 @@ impl State {
         self.workers.get(&worker_id)
     }
 
+    pub fn running_task_count(&self, worker_id: WorkerId) -> usize {
+        self.running_tasks
+            .get(&worker_id)
+            .map_or(0, |tasks| tasks.len())
+    }
+
+    /// Returns online workers that are not running any task.
+    /// Tasks submitted to tako that are not running yet may be already assigned to a worker,
+    /// therefore a worker that is large enough for any of these tasks is not considered idle.
+    pub fn idle_workers(&self) -> Set<WorkerId> {
+        let mut idle: Set<WorkerId> = self
+            .workers
+            .values()
+            .filter(|worker| worker.is_online())
+            .map(|worker| worker.worker_id())
+            .filter(|worker_id| self.running_task_count(*worker_id) == 0)
+            .collect();
+        if idle.is_empty() {
+            return idle;
+        }
+        let pending_cpus = self
+            .jobs
+            .values()
+            .filter(|job| job.n_pending_tasks() > 0)
+            .map(|job| match job.resources.cpus() {
+                CpuRequest::Compact(n) | CpuRequest::ForceCompact(n) | CpuRequest::Scatter(n) => *n,
+                CpuRequest::All => 1,
+            })
+            .min();
+        if let Some(pending_cpus) = pending_cpus {
+            idle.retain(|worker_id| self.workers[worker_id].cpu_count() < pending_cpus);
+        }
+        idle
+    }
+
+    /// Returns true if at least one online worker is idle (see `idle_workers`)
+    pub fn has_idle_worker(&self) -> bool {
+        !self.idle_workers().is_empty()
+    }
+
+    /// Returns (job id, task id) pairs of tasks that are running on the given worker
+    pub fn get_running_tasks(&self, worker_id: WorkerId) -> Vec<(JobId, JobTaskId)> {
+        let mut tasks: Vec<_> = self
+            .running_tasks
+            .get(&worker_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|tako_id| {
+                let job = self.get_job_by_tako_task_id(*tako_id)?;
+                Some((job.job_id, job.get_task_state(*tako_id).0))
+            })
+            .collect();
+        tasks.sort_unstable();
+        tasks
+    }
+
+    fn set_task_running(&mut self, tako_task_id: TakoTaskId, worker_id: WorkerId) {
+        let job = self.get_job_mut_by_tako_task_id(tako_task_id).unwrap();
+        if job.set_running_state(tako_task_id, worker_id) {
+            self.running_tasks
+                .entry(worker_id)
+                .or_default()
+                .insert(tako_task_id);
+        }
+    }
+
+    /// Removes the task from the running tasks of its worker.
+    /// It has to be called before the state of the task is changed.
+    fn remove_running_task(&mut self, tako_task_id: TakoTaskId) {
+        let worker_id = self.get_job_by_tako_task_id(tako_task_id).and_then(|job| {
+            match job.get_task_state(tako_task_id).1 {
+                JobTaskState::Running { worker, .. } => Some(*worker),
+                _ => None,
+            }
+        });
+        if let Some(tasks) = worker_id.and_then(|id| self.running_tasks.get_mut(&id)) {
+            tasks.remove(&tako_task_id);
+        }
+    }
+
+    /// Marks the given tasks of a job, that were canceled in tako, as canceled.
+    /// Returns their job task ids.
+    pub fn cancel_tasks(
+        &mut self,
+        job_id: JobId,
+        tako_task_ids: &[TakoTaskId],
+        backend: &Backend,
+    ) -> Vec<JobTaskId> {
+        for tako_id in tako_task_ids {
+            self.remove_running_task(*tako_id);
+        }
+        let job = self.get_job_mut(job_id).unwrap();
+        tako_task_ids
+            .iter()
+            .map(|tako_id| job.set_cancel_state(*tako_id, backend))
+            .collect()
+    }
+
     pub fn get_worker_mut(&mut self, worker_id: WorkerId) -> Option<&mut Worker> {
         self.workers.get_mut(&worker_id)
     }
 
+    /// Returns a log context that identifies the given task, its job and its worker
+    fn task_log_context(&mut self, tako_task_id: TakoTaskId) -> LogContext {
+        match self.get_job_mut_by_tako_task_id(tako_task_id) {
+            Some(job) => {
+                let job_id = job.job_id;
+                let (task_id, state) = job.get_task_state_mut(tako_task_id);
+                LogContext {
+                    job_id: Some(job_id),
+                    task_id: Some(task_id),
+                    worker_id: state.get_worker(),
+                }
+            }
+            None => LogContext::default(),
+        }
+    }
+
     pub fn process_task_failed(
         &mut self,
         state_ref: &StateRef,
         tako_ref: &Backend,
         msg: TaskFailedMessage,
     ) {
+        let context = self.task_log_context(msg.id);
+        with_log_context(context, || self.fail_task(state_ref, tako_ref, msg))
+    }
+
+    fn fail_task(&mut self, state_ref: &StateRef, tako_ref: &Backend, msg: TaskFailedMessage) {
         log::debug!("Task id={} failed", msg.id);
 
+        self.remove_running_task(msg.id);
         let job = self.get_job_mut_by_tako_task_id(msg.id).unwrap();
         job.set_failed_state(msg.id, msg.info.message, tako_ref);
+        let job_id = job.job_id;
+
+        if job.is_holding_canary(msg.id) {
+            log::debug!(
+                "Canary task of job {} failed, canceling its held tasks",
+                job_id
+            );
+            job.cancel_held_tasks(tako_ref);
+        }
 
         if let Some(max_fails) = job.max_fails {
             if job.counters.n_failed_tasks > max_fails {
@@ -162,20 +361,39 @@ impl State {
                 cancel_tasks_from_callback(state_ref, tako_ref, job.job_id, task_ids);
             }
         }
+        self.store_job_if_terminated(job_id);
     }
 
     pub fn process_task_update(&mut self, msg: TaskUpdate, backend: &Backend) {
+        let mut context = self.task_log_context(msg.id);
+        if let TaskState::Running(worker_id) = msg.state {
+            context.worker_id = Some(worker_id);
+        }
+        with_log_context(context, || self.update_task(msg, backend))
+    }
+
+    fn update_task(&mut self, msg: TaskUpdate, backend: &Backend) {
         log::debug!("Task id={} updated {:?}", msg.id, msg.state);
         match msg.state {
-            TaskState::Running(worker_id) => {
-                let job = self.get_job_mut_by_tako_task_id(msg.id).unwrap();
-                job.set_running_state(msg.id, worker_id)
-            }
+            TaskState::Running(worker_id) => self.set_task_running(msg.id, worker_id),
             TaskState::Finished => {
+                self.remove_running_task(msg.id);
                 let job = self.get_job_mut_by_tako_task_id(msg.id).unwrap();
-                job.set_finished_state(msg.id, backend)
+                job.set_finished_state(msg.id, backend);
+                let job_id = job.job_id;
+                if job.is_holding_canary(msg.id) {
+                    log::debug!(
+                        "Canary task of job {} finished, submitting held tasks",
+                        job_id
+                    );
+                    let tasks = std::mem::take(&mut job.held_tasks);
+                    job.set_tasks_released(&tasks);
+                    submit_tasks_from_callback(backend, job_id, tasks);
+                }
+                self.store_job_if_terminated(job_id);
             }
             TaskState::Waiting => {
+                self.remove_running_task(msg.id);
                 let job = self.get_job_mut_by_tako_task_id(msg.id).unwrap();
                 job.set_waiting_state(msg.id)
             }
@@ -186,12 +404,24 @@ impl State {
     }
 
     pub fn process_worker_new(&mut self, msg: NewWorkerMessage) {
-        log::debug!("New worker id={}", msg.worker_id);
+        let context = LogContext {
+            worker_id: Some(msg.worker_id),
+            ..Default::default()
+        };
+        with_log_context(context, || {
+            log::debug!("New worker id={}", msg.worker_id);
+        });
         self.add_worker(Worker::new(msg.worker_id, msg.configuration));
     }
 
     pub fn process_worker_lost(&mut self, msg: LostWorkerMessage) {
-        log::debug!("Worker lost id={}", msg.worker_id);
+        let context = LogContext {
+            worker_id: Some(msg.worker_id),
+            ..Default::default()
+        };
+        with_log_context(context, || {
+            log::debug!("Worker lost id={}", msg.worker_id);
+        });
         let worker = self.workers.get_mut(&msg.worker_id).unwrap();
         worker.set_offline_state(match msg.reason {
             LostWorkerReason::Stopped => LostWorkerReasonInfo::Stopped,
@@ -199,6 +429,7 @@ impl State {
             LostWorkerReason::HeartbeatLost => LostWorkerReasonInfo::HeartbeatLost,
             LostWorkerReason::IdleTimeout => LostWorkerReasonInfo::IdleTimeout,
         });
+        self.running_tasks.remove(&msg.worker_id);
         for task_id in msg.running_tasks {
             let job = self.get_job_mut_by_tako_task_id(task_id).unwrap();
             job.set_waiting_state(task_id);
@@ -208,6 +439,41 @@ impl State {
     pub fn get_autoalloc_state(&self) -> &WrappedRcRefCell<AutoAllocState> {
         &self.autoalloc_state
     }
+
+    pub fn set_job_history(&mut self, job_history: JobHistory) {
+        self.job_history = Some(job_history);
+    }
+
+    pub fn get_job_history(&self) -> Option<&JobHistory> {
+        self.job_history.as_ref()
+    }
+
+    /// Returns the largest number of CPUs of a single online worker.
+    /// Returns `None` if there is no online worker.
+    pub fn max_worker_cpus(&self) -> Option<NumOfCpus> {
+        self.workers
+            .values()
+            .filter(|worker| worker.is_online())
+            .map(|worker| worker.cpu_count())
+            .max()
+    }
+
+    pub fn set_default_resources(&mut self, resources: ResourceRequest) {
+        self.default_resources = resources;
+    }
+
+    pub fn default_resources(&self) -> &ResourceRequest {
+        &self.default_resources
+    }
+
+    /// Stores the job into the job history (if it is enabled) once all its tasks have ended
+    pub fn store_job_if_terminated(&mut self, job_id: JobId) {
+        if let (Some(history), Some(job)) = (self.job_history.as_mut(), self.jobs.get(&job_id)) {
+            if job.is_terminated() {
+                history.store(job.make_job_detail(true));
+            }
+        }
+    }
 }
 
 impl StateRef {
@@ -218,7 +484,10 @@ impl StateRef {
             base_task_id_to_job_id: Default::default(),
             job_id_counter: 1,
             task_id_counter: 1,
+            running_tasks: Default::default(),
             autoalloc_state: WrappedRcRefCell::wrap(AutoAllocState::new(autoalloc_interval)),
+            job_history: None,
+            default_resources: Default::default(),
         })
     }
 }
@@ -254,11 +523,14 @@ mod tests {
             dummy_program_definition(),
             ResourceRequest::default(),
             false,
+            false,
             None,
             Some(Vec::new()),
+            None,
             0,
             None,
             None,
+            Default::default(),
         )
     }
 
@@ -293,4 +565,28 @@ mod tests {
         assert_eq!(state.get_job_mut_by_tako_task_id(130).unwrap().job_id, 227);
         assert!(state.get_job_mut_by_tako_task_id(131).is_none());
     }
+
+    #[test]
+    fn test_running_tasks() {
+        let state_ref = StateRef::new(Duration::from_secs(1));
+        let mut state = state_ref.get_mut();
+        state.add_job(test_job(
+            JobType::Array(IntArray::from_range(0, 10)),
+            1,
+            100,
+        ));
+        state.add_job(test_job(JobType::Simple, 2, 110));
+        assert_eq!(state.running_task_count(1), 0);
+
+        state.set_task_running(102, 1);
+        state.set_task_running(110, 1);
+        state.set_task_running(105, 2);
+        assert_eq!(state.running_task_count(1), 2);
+        assert_eq!(state.get_running_tasks(1), vec![(1, 2), (2, 0)]);
+        assert_eq!(state.get_running_tasks(2), vec![(1, 5)]);
+
+        state.remove_running_task(102);
+        assert_eq!(state.get_running_tasks(1), vec![(2, 0)]);
+        assert_eq!(state.running_task_count(3), 0);
+    }
 }
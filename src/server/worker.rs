@@ -1,4 +1,5 @@
 use chrono::Utc;
+use tako::common::resources::NumOfCpus;
 use tako::messages::common::WorkerConfiguration;
 
 use crate::server::worker::WorkerState::Offline;
@@ -33,6 +34,19 @@ impl Worker {
         &self.configuration
     }
 
+    pub fn is_online(&self) -> bool {
+        matches!(self.state, WorkerState::Online)
+    }
+
+    pub fn cpu_count(&self) -> NumOfCpus {
+        self.configuration
+            .resources
+            .cpus
+            .iter()
+            .map(|socket| socket.len() as NumOfCpus)
+            .sum()
+    }
+
     pub fn set_offline_state(&mut self, reason: LostWorkerReasonInfo) {
         self.state = Offline(WorkerExitInfo {
             ended_at: Utc::now(),
@@ -8,6 +8,7 @@ use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::time::Duration;
 use tako::InstanceId;
 
 pub const HQ_LOG_HEADER: &[u8] = b"HQ:log";
@@ -17,6 +18,9 @@ pub const BLOCK_STREAM_START: u8 = 0;
 pub const BLOCK_STREAM_CHUNK: u8 = 1;
 pub const BLOCK_STREAM_END: u8 = 2;
 
+/// How often is the log file checked for new data when following unfinished streams
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
 pub struct ChunkInfo {
     position: u64,
     size: u32, // Currently chunk is actually limited to 128kB
@@ -280,7 +284,45 @@ impl LogFile {
     }
 
     pub fn show(&mut self, opts: &ShowOpts) -> anyhow::Result<()> {
-        let id_width = if let Some(max_id) = self.index.keys().max() {
+        self.show_while(opts, |has_open_streams| has_open_streams)
+    }
+
+    /// Same as [`LogFile::show`], but in the follow mode, `keep_following` decides whether to wait
+    /// for more data once the end of the log is reached.
+    /// It receives a flag whether some of the followed streams are still open.
+    pub fn show_while<F: Fn(bool) -> bool>(
+        &mut self,
+        opts: &ShowOpts,
+        keep_following: F,
+    ) -> anyhow::Result<()> {
+        let selected_tasks: Option<Set<JobTaskId>> =
+            opts.task.as_ref().map(|array| array.iter().collect());
+        if opts.follow && selected_tasks.is_none() {
+            anyhow::bail!("--follow can be used only together with --task");
+        }
+        let is_selected = |task_id: JobTaskId| {
+            selected_tasks
+                .as_ref()
+                .map(|tasks| tasks.contains(&task_id))
+                .unwrap_or(true)
+        };
+        // Tasks whose active stream was not closed yet, they are waited for in the follow mode.
+        // Streams that are started later are added when their start is read from the log.
+        let mut unfinished: Set<JobTaskId> = self
+            .index
+            .iter()
+            .filter(|(task_id, info)| is_selected(**task_id) && !info.last_instance().finished)
+            .map(|(task_id, _)| *task_id)
+            .collect();
+
+        let max_id = self
+            .index
+            .keys()
+            .copied()
+            .filter(|task_id| is_selected(*task_id))
+            .chain(selected_tasks.iter().flatten().copied())
+            .max();
+        let id_width = if let Some(max_id) = max_id {
             max_id.to_string().len()
         } else {
             return Ok(());
@@ -305,7 +347,7 @@ impl LogFile {
         let stdout = std::io::stdout();
         let mut stdout_buf = BufWriter::new(stdout.lock());
 
-        let active_instances: Map<_, _> = self
+        let mut active_instances: Map<_, _> = self
             .index
             .iter()
             .map(|(job_id, info)| (*job_id, info.last_instance().instance_id))
@@ -314,18 +356,57 @@ impl LogFile {
         let mut has_content = Set::new();
 
         loop {
-            match Self::read_block(&mut self.file)? {
-                Some(Block::StreamStart { .. }) => {}
-                Some(Block::StreamChunk {
+            let position = self.file.stream_position()?;
+            let block = match Self::read_block(&mut self.file) {
+                Ok(Some(block)) => block,
+                Ok(None) if !opts.follow || !keep_following(!unfinished.is_empty()) => break,
+                Ok(None) => {
+                    self.wait_for_data(position, &mut stdout_buf)?;
+                    continue;
+                }
+                Err(e) if opts.follow && is_unexpected_eof(&e) => {
+                    // The block is not fully written yet
+                    self.wait_for_data(position, &mut stdout_buf)?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            match block {
+                Block::StreamStart {
+                    task_id,
+                    instance_id,
+                } => {
+                    // A task was (re)started after the index was created
+                    if opts.follow
+                        && is_selected(task_id)
+                        && active_instances
+                            .get(&task_id)
+                            .map(|id| *id < instance_id)
+                            .unwrap_or(true)
+                    {
+                        active_instances.insert(task_id, instance_id);
+                        unfinished.insert(task_id);
+                    }
+                }
+                Block::StreamChunk {
                     task_id,
                     instance_id,
                     channel_id,
                     size,
-                }) => {
+                } => {
+                    if opts.follow
+                        && self.file.stream_position()? + size as u64
+                            > self.file.get_ref().metadata()?.len()
+                    {
+                        // Data of the chunk are not fully written yet
+                        self.wait_for_data(position, &mut stdout_buf)?;
+                        continue;
+                    }
                     if selected_channel_id
                         .map(|id| channel_id == id)
                         .unwrap_or(true)
-                        && *active_instances.get(&task_id).unwrap() == instance_id
+                        && is_selected(task_id)
+                        && active_instances.get(&task_id) == Some(&instance_id)
                     {
                         buffer.resize(size as usize, 0u8);
                         self.file.read_exact(&mut buffer)?;
@@ -346,11 +427,13 @@ impl LogFile {
                         self.file.seek_relative(size as i64)?;
                     }
                 }
-                Some(Block::StreamEnd {
+                Block::StreamEnd {
                     task_id,
                     instance_id,
-                }) => {
-                    if *active_instances.get(&task_id).unwrap() == instance_id {
+                } => {
+                    if is_selected(task_id) && active_instances.get(&task_id) == Some(&instance_id)
+                    {
+                        unfinished.remove(&task_id);
                         if !opts.show_empty && !has_content.contains(&task_id) {
                             continue;
                         }
@@ -363,12 +446,19 @@ impl LogFile {
                         )?;
                     }
                 }
-                None => break,
             }
         }
         Ok(())
     }
 
+    /// Rewinds the log file to the given position and waits until more data may be available
+    fn wait_for_data<W: Write>(&mut self, position: u64, output: &mut W) -> anyhow::Result<()> {
+        output.flush()?;
+        self.file.seek(SeekFrom::Start(position))?;
+        std::thread::sleep(FOLLOW_INTERVAL);
+        Ok(())
+    }
+
     fn make_index(file: &mut BufReader<File>) -> anyhow::Result<BTreeMap<JobTaskId, TaskInfo>> {
         //let position = file.stream_position()?;
         // index: Map<JobTaskId, Vec<(u64, usize)>>,
@@ -427,3 +517,10 @@ impl LogFile {
         Ok(index)
     }
 }
+
+fn is_unexpected_eof(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .map(|e| e.kind() == ErrorKind::UnexpectedEof)
+        .unwrap_or(false)
+}
@@ -1,5 +1,9 @@
+use std::cell::Cell;
+use std::io::Read;
 use std::marker::PhantomData;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::{Bytes, BytesMut};
 use futures::future::ready;
@@ -9,6 +13,7 @@ use orion::aead::streaming::{StreamOpener, StreamSealer};
 use orion::kdf::SecretKey;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use serde_bytes::ByteBuf;
 use tako::transfer::auth::{do_authentication, open_message, seal_message};
 use tokio::net::TcpStream;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
@@ -16,39 +21,108 @@ use tokio_util::codec::{Framed, LengthDelimitedCodec};
 use crate::common::error::error;
 use crate::common::serverdir::AccessRecord;
 use crate::transfer::messages::{FromClientMessage, ToClientMessage};
-use crate::transfer::protocol::make_protocol_builder;
+use crate::transfer::protocol::{
+    make_protocol_builder, map_frame_error, oversized_message_error, DEFAULT_MAX_MESSAGE_SIZE,
+};
 
 type Codec = Framed<TcpStream, LengthDelimitedCodec>;
 
 const COMM_PROTOCOL: u32 = 0;
 
+/// How often should an idle client ping the server to keep its connection alive
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// If no message arrives from the other side within this duration, the connection is considered
+/// to be dead. The server closes client connections that are idle for longer than this timeout
+/// and the client gives up on pings that were not answered within this timeout. Other requests
+/// are not limited, because their handling may take an arbitrary time.
+pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Serialized messages smaller than this size are never compressed
+const COMPRESSION_THRESHOLD: usize = 64 * 1024;
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Messages that can carry a compressed message of the same type.
+pub trait CompressibleMessage: Sized {
+    fn compressed(data: Vec<u8>) -> Self;
+    fn compressed_data(&self) -> Option<&[u8]>;
+    /// Returns true if the message asks the receiver to compress the messages it sends back
+    fn enables_compression(&self) -> bool;
+}
+
+impl CompressibleMessage for FromClientMessage {
+    fn compressed(data: Vec<u8>) -> Self {
+        FromClientMessage::Compressed(ByteBuf::from(data))
+    }
+    fn compressed_data(&self) -> Option<&[u8]> {
+        match self {
+            FromClientMessage::Compressed(data) => Some(data.as_slice()),
+            _ => None,
+        }
+    }
+    fn enables_compression(&self) -> bool {
+        matches!(self, FromClientMessage::EnableCompression)
+    }
+}
+
+impl CompressibleMessage for ToClientMessage {
+    fn compressed(data: Vec<u8>) -> Self {
+        ToClientMessage::Compressed(ByteBuf::from(data))
+    }
+    fn compressed_data(&self) -> Option<&[u8]> {
+        match self {
+            ToClientMessage::Compressed(data) => Some(data.as_slice()),
+            _ => None,
+        }
+    }
+    fn enables_compression(&self) -> bool {
+        false
+    }
+}
+
 pub struct HqConnection<ReceiveMsg, SendMsg> {
     writer: SplitSink<Codec, Bytes>,
     reader: SplitStream<Codec>,
     sealer: Option<StreamSealer>,
     opener: Option<StreamOpener>,
+    /// Compress large messages sent to the other side
+    compression: bool,
+    /// Larger messages received from the other side are rejected
+    max_message_size: usize,
     _r: PhantomData<ReceiveMsg>,
     _s: PhantomData<SendMsg>,
 }
 
-impl<R: DeserializeOwned, S: Serialize> HqConnection<R, S> {
+impl<R: DeserializeOwned + CompressibleMessage, S: Serialize + CompressibleMessage>
+    HqConnection<R, S>
+{
     pub async fn send(&mut self, item: S) -> crate::Result<()> {
-        let data = serialize_message(item, &mut self.sealer)?;
+        let data = serialize_message(item, &mut self.sealer, self.compression)?;
         self.writer.send(data).await?;
         Ok(())
     }
     pub async fn receive(&mut self) -> Option<crate::Result<R>> {
+        let max_message_size = self.max_message_size;
         self.reader.next().await.map(|msg| {
-            msg.map_err(|e| e.into())
-                .and_then(|m| deserialize_message(Ok(m), &mut self.opener))
+            deserialize_message(
+                msg.map_err(|e| map_frame_error(e, max_message_size)),
+                &mut self.opener,
+                max_message_size,
+            )
         })
     }
 
     pub async fn send_and_receive(&mut self, item: S) -> crate::Result<R> {
         self.send(item).await?;
+        // A lost connection is reported as an IO error, so that it can be distinguished from
+        // errors reported by the other side
         match self.receive().await {
             Some(msg) => msg,
-            None => error("Expected response was not received".into()),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Expected response was not received",
+            )
+            .into()),
         }
     }
 
@@ -63,18 +137,43 @@ impl<R: DeserializeOwned, S: Serialize> HqConnection<R, S> {
             writer,
             mut sealer,
             mut opener,
+            compression,
+            max_message_size,
             ..
         } = self;
 
-        let sink = writer.with(move |msg| ready(serialize_message(msg, &mut sealer)));
+        // Compression can be enabled by a message received from the other side
+        let compression = Rc::new(Cell::new(compression));
+        let compression2 = compression.clone();
 
-        let stream = reader.map(move |message| deserialize_message(message, &mut opener));
+        let sink =
+            writer.with(move |msg| ready(serialize_message(msg, &mut sealer, compression.get())));
+
+        let stream = reader.filter_map(move |message| {
+            let message = deserialize_message::<R>(
+                message.map_err(|e| map_frame_error(e, max_message_size)),
+                &mut opener,
+                max_message_size,
+            );
+            ready(match message {
+                Ok(msg) if msg.enables_compression() => {
+                    compression2.set(true);
+                    None
+                }
+                message => Some(message),
+            })
+        });
 
         (sink, stream)
     }
 
-    async fn init(socket: TcpStream, server: bool, key: Arc<SecretKey>) -> crate::Result<Self> {
-        let connection = make_protocol_builder().new_framed(socket);
+    async fn init(
+        socket: TcpStream,
+        server: bool,
+        key: Arc<SecretKey>,
+        max_message_size: usize,
+    ) -> crate::Result<Self> {
+        let connection = make_protocol_builder(max_message_size).new_framed(socket);
         let (mut tx, mut rx) = connection.split();
 
         let mut my_role = "hq-server".to_string();
@@ -98,6 +197,8 @@ impl<R: DeserializeOwned, S: Serialize> HqConnection<R, S> {
             reader: rx,
             sealer,
             opener,
+            compression: false,
+            max_message_size,
             _r: Default::default(),
             _s: Default::default(),
         })
@@ -114,7 +215,46 @@ impl ClientConnection {
         let connection = TcpStream::connect(address).await?;
 
         let key = record.hq_secret_key().clone();
-        HqConnection::init(connection, false, key).await
+        let mut connection =
+            HqConnection::init(connection, false, key, DEFAULT_MAX_MESSAGE_SIZE).await?;
+
+        if record.supports_compression() {
+            connection
+                .send(FromClientMessage::EnableCompression)
+                .await?;
+            connection.compression = true;
+        }
+        Ok(connection)
+    }
+
+    /// Waits for the given duration while periodically pinging the server, so that
+    /// the connection is not closed because of inactivity and a dead server is detected.
+    pub async fn sleep_with_keepalive(&mut self, duration: Duration) -> crate::Result<()> {
+        let mut remaining = duration;
+        while remaining > KEEPALIVE_INTERVAL {
+            tokio::time::sleep(KEEPALIVE_INTERVAL).await;
+            remaining -= KEEPALIVE_INTERVAL;
+            let response = tokio::time::timeout(
+                KEEPALIVE_TIMEOUT,
+                self.send_and_receive(FromClientMessage::Ping),
+            )
+            .await
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!(
+                        "No response was received within {}, the connection seems to be dead",
+                        humantime::format_duration(KEEPALIVE_TIMEOUT)
+                    ),
+                )
+            })?;
+            match response? {
+                ToClientMessage::Pong => {}
+                msg => return error(format!("Received an invalid message {:?}", msg)),
+            }
+        }
+        tokio::time::sleep(remaining).await;
+        Ok(())
     }
 }
 
@@ -123,24 +263,109 @@ impl ServerConnection {
     pub async fn accept_client(
         socket: TcpStream,
         key: Arc<SecretKey>,
+        max_message_size: usize,
     ) -> crate::Result<ServerConnection> {
-        HqConnection::init(socket, true, key).await
+        HqConnection::init(socket, true, key, max_message_size).await
     }
 }
 
-fn serialize_message<S: Serialize>(
+fn serialize_message<S: Serialize + CompressibleMessage>(
     item: S,
     mut sealer: &mut Option<StreamSealer>,
+    compression: bool,
 ) -> crate::Result<Bytes> {
-    let data = tako::transfer::auth::serialize(&item)?;
+    let mut data = tako::transfer::auth::serialize(&item)?;
+    if compression && data.len() >= COMPRESSION_THRESHOLD {
+        let compressed = zstd::encode_all(data.as_slice(), COMPRESSION_LEVEL)?;
+        data = tako::transfer::auth::serialize(&S::compressed(compressed))?;
+    }
     Ok(seal_message(&mut sealer, data.into()))
 }
 
-fn deserialize_message<R: DeserializeOwned>(
-    message: Result<BytesMut, std::io::Error>,
+fn deserialize_message<R: DeserializeOwned + CompressibleMessage>(
+    message: crate::Result<BytesMut>,
     mut opener: &mut Option<StreamOpener>,
+    max_message_size: usize,
 ) -> crate::Result<R> {
     let message = message?;
-    let item = open_message(&mut opener, &message)?;
+    let item: R = open_message(&mut opener, &message)?;
+    if let Some(data) = item.compressed_data() {
+        // The decompressed message is subject to the same size limit as the received frames
+        let mut decompressed = Vec::new();
+        zstd::stream::read::Decoder::new(data)?
+            .take(max_message_size as u64 + 1)
+            .read_to_end(&mut decompressed)?;
+        if decompressed.len() > max_message_size {
+            return Err(oversized_message_error(max_message_size));
+        }
+        return Ok(tako::transfer::auth::deserialize(&decompressed)?);
+    }
     Ok(item)
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use crate::transfer::connection::{
+        deserialize_message, serialize_message, COMPRESSION_THRESHOLD,
+    };
+    use crate::transfer::messages::ToClientMessage;
+    use crate::transfer::protocol::DEFAULT_MAX_MESSAGE_SIZE;
+
+    fn roundtrip(message: ToClientMessage, compression: bool) -> (usize, ToClientMessage) {
+        let data = serialize_message(message, &mut None, compression).unwrap();
+        let size = data.len();
+        let message = deserialize_message(
+            Ok(BytesMut::from(data.as_ref())),
+            &mut None,
+            DEFAULT_MAX_MESSAGE_SIZE,
+        )
+        .unwrap();
+        (size, message)
+    }
+
+    #[test]
+    fn test_reject_oversized_decompressed_message() {
+        let error = "a".repeat(COMPRESSION_THRESHOLD * 4);
+        let data = serialize_message(ToClientMessage::Error(error), &mut None, true).unwrap();
+        let max_message_size = COMPRESSION_THRESHOLD;
+        assert!(data.len() < max_message_size);
+
+        let result = deserialize_message::<ToClientMessage>(
+            Ok(BytesMut::from(data.as_ref())),
+            &mut None,
+            max_message_size,
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            format!(
+                "Error: Received a message that exceeds the maximum message size ({} bytes)",
+                max_message_size
+            )
+        );
+    }
+
+    #[test]
+    fn test_compress_large_message() {
+        let error = "a".repeat(COMPRESSION_THRESHOLD * 2);
+        let (size, message) = roundtrip(ToClientMessage::Error(error.clone()), true);
+        assert!(size < COMPRESSION_THRESHOLD);
+        assert!(matches!(message, ToClientMessage::Error(e) if e == error));
+    }
+
+    #[test]
+    fn test_keep_small_message_uncompressed() {
+        let (size, message) = roundtrip(ToClientMessage::Error("a".repeat(100)), true);
+        assert!(size > 100);
+        assert!(matches!(message, ToClientMessage::Error(e) if e.len() == 100));
+    }
+
+    #[test]
+    fn test_uncompressed_large_message() {
+        let error = "a".repeat(COMPRESSION_THRESHOLD * 2);
+        let (size, message) = roundtrip(ToClientMessage::Error(error.clone()), false);
+        assert!(size > COMPRESSION_THRESHOLD * 2);
+        assert!(matches!(message, ToClientMessage::Error(e) if e == error));
+    }
+}
@@ -5,10 +5,14 @@ use tako::messages::common::{ProgramDefinition, WorkerConfiguration};
 
 use crate::client::status::Status;
 use crate::common::arraydef::IntArray;
+use crate::common::manager::info::ManagerType;
+use crate::common::timeutils::TimeWindow;
 use crate::server::job::{JobTaskCounters, JobTaskInfo};
-use crate::{JobId, JobTaskCount, JobTaskId, WorkerId};
+use crate::{JobId, JobTaskCount, JobTaskId, Map, WorkerId};
 use bstr::BString;
+use serde_bytes::ByteBuf;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::Duration;
 use tako::common::resources::ResourceRequest;
 
@@ -16,8 +20,44 @@ use tako::common::resources::ResourceRequest;
 pub struct TaskBody {
     pub program: ProgramDefinition,
     pub pin: bool,
+    pub merge_stderr_into_stdout: bool,
     pub job_id: JobId,
     pub task_id: JobTaskId,
+    /// Data written to the standard input of the task
+    pub stdin: Option<BString>,
+    pub output_flush: OutputFlush,
+    /// The task is killed by the worker when it consumes more CPU time than this limit
+    pub cpu_time_limit: Option<Duration>,
+}
+
+/// Determines how the worker forwards the streamed output of a task to the stream server
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum OutputFlush {
+    /// Data are forwarded as soon as they are read from the task (default)
+    Chunk,
+    /// Only complete lines are forwarded, so that lines are never split
+    Line,
+    /// Data are forwarded in large blocks, for tasks producing a lot of output
+    Block,
+}
+
+impl Default for OutputFlush {
+    fn default() -> Self {
+        OutputFlush::Chunk
+    }
+}
+
+impl FromStr for OutputFlush {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "chunk" => Self::Chunk,
+            "line" => Self::Line,
+            "block" => Self::Block,
+            _ => anyhow::bail!("Invalid output flush policy, use chunk, line or block"),
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -32,13 +72,27 @@ pub struct SubmitRequest {
     pub name: String,
     pub max_fails: Option<JobTaskCount>,
     pub spec: ProgramDefinition,
-    pub resources: ResourceRequest,
+    /// If not set, the default resource request of the server is used
+    pub resources: Option<ResourceRequest>,
     pub pin: bool,
+    pub merge_stderr_into_stdout: bool,
     pub entries: Option<Vec<BString>>,
+    /// Standard input of individual tasks of a task array
+    pub task_stdin: Option<Vec<BString>>,
+    /// Submit only the first task and hold the remaining tasks until it finishes successfully
+    pub canary: bool,
+    /// Tasks of the job are not submitted before this delay elapses
+    pub start_delay: Option<Duration>,
+    /// Informational key/value metadata of the job
+    pub metadata: Map<String, String>,
     pub submit_dir: PathBuf,
     pub priority: tako::Priority,
     pub time_limit: Option<Duration>,
+    /// Limit of CPU time consumed by each task, independent of the wall-clock time limit
+    pub cpu_time_limit: Option<Duration>,
     pub log: Option<PathBuf>,
+    /// How is the streamed output of tasks forwarded into the log
+    pub output_flush: OutputFlush,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -52,6 +106,8 @@ pub enum Selector {
 pub struct ResubmitRequest {
     pub job_id: JobId,
     pub status: Option<Vec<Status>>,
+    /// Cancel the original job before its tasks are submitted again
+    pub cancel: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -59,6 +115,11 @@ pub struct CancelRequest {
     pub selector: Selector,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ForgetJobRequest {
+    pub selector: Selector,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JobInfoRequest {
     pub selector: Selector,
@@ -73,6 +134,8 @@ pub struct JobDetailRequest {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StopWorkerMessage {
     pub selector: Selector,
+    /// Only stop workers that are not running any tasks
+    pub idle_only: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -80,6 +143,8 @@ pub enum FromClientMessage {
     Submit(SubmitRequest),
     Resubmit(ResubmitRequest),
     Cancel(CancelRequest),
+    /// Removes terminated jobs from the memory of the server
+    Forget(ForgetJobRequest),
     JobDetail(JobDetailRequest),
     JobInfo(JobInfoRequest),
     WorkerList,
@@ -87,6 +152,79 @@ pub enum FromClientMessage {
     Stats,
     StopWorker(StopWorkerMessage),
     Stop,
+    AutoAlloc(AutoAllocRequest),
+    /// Keeps an otherwise idle connection alive, the server responds with `Pong`
+    Ping,
+    /// Asks the server to compress large messages sent to this client.
+    /// It is handled by the connection itself and it does not have a response.
+    EnableCompression,
+    /// A zstd-compressed serialized message, unpacked by the connection
+    Compressed(ByteBuf),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AutoAllocRequest {
+    /// Allocations of the given descriptor, or of all descriptors if it is not set
+    Info {
+        descriptor: Option<String>,
+    },
+    AddQueue(AddQueueParams),
+    /// Render the submission of the largest allocation of a new queue without creating the queue
+    DryRun(AddQueueParams),
+    UpdateQueue(UpdateQueueParams),
+    /// Stop or resume creating new allocations of the given descriptor
+    PauseQueue {
+        descriptor: String,
+        paused: bool,
+    },
+    CancelAllocation {
+        descriptor: String,
+        allocation_id: String,
+    },
+    SubmitScript {
+        descriptor: String,
+        allocation_id: String,
+    },
+}
+
+/// Changes the scaling of an existing allocation queue, unset values are not changed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateQueueParams {
+    /// Name of the allocation queue (descriptor)
+    pub name: String,
+    pub target_worker_count: Option<u64>,
+    pub min_workers_per_alloc: Option<u64>,
+    pub max_workers_per_alloc: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddQueueParams {
+    pub manager: ManagerType,
+    /// Name of the allocation queue (descriptor)
+    pub name: String,
+    /// Name of the PBS queue or Slurm partition
+    pub queue: String,
+    pub target_worker_count: u64,
+    /// Lower bound of the number of workers requested by a single allocation
+    pub min_workers_per_alloc: u64,
+    pub max_workers_per_alloc: u64,
+    pub timelimit: Option<Duration>,
+    /// Additional arguments passed to `qsub`/`sbatch`
+    pub additional_args: Vec<String>,
+    pub active_window: Option<TimeWindow>,
+    /// Environment variables of the workers started by the queue
+    pub worker_env: Vec<(String, String)>,
+    /// Resources requested for each node of an allocation, in the syntax of the job manager
+    /// (e.g. `ngpus=4:mem=64gb` for PBS, `--gpus-per-node=4 --mem=64G` for Slurm)
+    pub node_resources: Option<String>,
+    /// How long can a running allocation stay without a connected worker
+    pub worker_start_timeout: Option<Duration>,
+    /// Cancel allocations whose workers have not connected within `worker_start_timeout`
+    pub cancel_on_start_timeout: bool,
+    /// Workers started by the queue stop after being idle for this duration
+    pub idle_timeout: Option<Duration>,
+    /// File into which events of the queue are appended as JSON lines
+    pub event_log: Option<PathBuf>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -96,6 +234,15 @@ pub enum CancelJobResponse {
     Failed(String),
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ForgetJobResponse {
+    /// The job was removed, contains the number of its tasks
+    Forgotten(JobTaskCount),
+    /// The job has not been terminated yet, so it was not removed
+    NotTerminated,
+    InvalidJob,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum StopWorkerResponse {
     Stopped,
@@ -119,6 +266,8 @@ pub struct StatsResponse {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SubmitResponse {
     pub job: JobDetail,
+    /// Set if the resource request of the job cannot be satisfied by any connected worker
+    pub resource_warning: Option<String>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -128,13 +277,70 @@ pub enum ToClientMessage {
     JobDetailResponse(Vec<(JobId, Option<JobDetail>)>),
     SubmitResponse(SubmitResponse),
     WorkerListResponse(WorkerListResponse),
-    WorkerInfoResponse(Option<WorkerInfo>),
+    WorkerInfoResponse(Option<WorkerInfoResponse>),
     StatsResponse(StatsResponse),
     StopWorkerResponse(Vec<(WorkerId, StopWorkerResponse)>),
     CancelJobResponse(Vec<(JobId, CancelJobResponse)>),
+    ForgetJobResponse(Vec<(JobId, ForgetJobResponse)>),
+    AutoAllocResponse(AutoAllocResponse),
+    Pong,
+    /// A zstd-compressed serialized message, unpacked by the connection
+    Compressed(ByteBuf),
     Error(String),
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AutoAllocResponse {
+    Info {
+        allocations: Vec<AllocationInfo>,
+        queues: Vec<QueueStateInfo>,
+    },
+    QueueCreated(String),
+    QueueUpdated(String),
+    SubmitScript(String),
+    AllocationCanceled,
+    DryRun {
+        command: String,
+        script: String,
+    },
+}
+
+/// State of an allocation queue that affects the submission of new allocations
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueStateInfo {
+    pub name: String,
+    pub paused: bool,
+    /// Number of consecutive failed attempts to submit an allocation
+    pub failed_submissions: u32,
+    /// No allocation is submitted before this date because of previous failures
+    pub next_submission_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AllocationInfo {
+    /// Name of the allocation queue (descriptor) that has created the allocation
+    pub queue: String,
+    pub id: String,
+    pub worker_count: u64,
+    pub status: AllocationStatusInfo,
+    pub working_dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum AllocationStatusInfo {
+    Queued {
+        queued_at: DateTime<Utc>,
+    },
+    Running {
+        queued_at: DateTime<Utc>,
+        started_at: DateTime<Utc>,
+        /// At least one worker of the allocation has connected to the server
+        worker_connected: bool,
+        /// No worker has connected within the start timeout of the queue
+        start_timeout_exceeded: bool,
+    },
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum JobStatus {
     Submitted,
@@ -190,15 +396,38 @@ pub struct JobDetail {
     pub tasks: Vec<JobTaskInfo>,
     pub resources: ResourceRequest,
     pub pin: bool,
+    pub merge_stderr_into_stdout: bool,
     pub max_fails: Option<JobTaskCount>,
     pub priority: tako::Priority,
     pub time_limit: Option<Duration>,
+    pub cpu_time_limit: Option<Duration>,
 
     // Date when job was submitted
     pub submission_date: DateTime<Utc>,
 
     // Time when job was completed or now if job is not completed
     pub completion_date_or_now: DateTime<Utc>,
+
+    /// Statistics of the time that tasks have spent waiting before being started
+    pub wait_stats: Option<TaskWaitStats>,
+
+    pub metadata: Map<String, String>,
+
+    /// Directory from which the job was submitted, relative output paths are resolved against it
+    pub submit_dir: PathBuf,
+
+    /// Log file into which the output of tasks is streamed
+    pub log: Option<PathBuf>,
+}
+
+/// Aggregated durations between the submission of a job and the start of its tasks.
+/// Only tasks that have already been started are included.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TaskWaitStats {
+    pub started_tasks: JobTaskCount,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub max: Duration,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -214,4 +443,6 @@ pub struct WorkerInfoRequest {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WorkerInfoResponse {
     pub worker: WorkerInfo,
+    /// Tasks that are currently running on the worker
+    pub running_tasks: Vec<(JobId, JobTaskId)>,
 }
@@ -1,8 +1,54 @@
 use tokio_util::codec::length_delimited::Builder;
-use tokio_util::codec::LengthDelimitedCodec;
+use tokio_util::codec::{LengthDelimitedCodec, LengthDelimitedCodecError};
 
-pub fn make_protocol_builder() -> Builder {
+use crate::common::error::HqError;
+
+/// Default maximum size of a single message exchanged between the client and the server
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 128 * 1024 * 1024;
+
+pub fn make_protocol_builder(max_message_size: usize) -> Builder {
     *LengthDelimitedCodec::builder()
         .little_endian()
-        .max_frame_length(128 * 1024 * 1024)
+        .max_frame_length(max_message_size)
+}
+
+/// Converts an error of the framing codec into an error that describes
+/// messages rejected because of their size.
+pub fn map_frame_error(error: std::io::Error, max_message_size: usize) -> HqError {
+    let oversized = error
+        .get_ref()
+        .map_or(false, |e| e.is::<LengthDelimitedCodecError>());
+    if oversized {
+        oversized_message_error(max_message_size)
+    } else {
+        error.into()
+    }
+}
+
+pub fn oversized_message_error(max_message_size: usize) -> HqError {
+    HqError::GenericError(format!(
+        "Received a message that exceeds the maximum message size ({} bytes)",
+        max_message_size
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+
+    use crate::transfer::protocol::{make_protocol_builder, map_frame_error};
+
+    #[test]
+    fn test_reject_oversized_message() {
+        let mut codec = make_protocol_builder(16).new_codec();
+        let mut data = BytesMut::from(&100u32.to_le_bytes()[..]);
+        data.extend_from_slice(&[0; 100]);
+
+        let error = map_frame_error(codec.decode(&mut data).unwrap_err(), 16);
+        assert_eq!(
+            error.to_string(),
+            "Error: Received a message that exceeds the maximum message size (16 bytes)"
+        );
+    }
 }
@@ -1,4 +1,4 @@
-use cli_table::{print_stdout, Cell, Style, Table};
+use cli_table::{print_stdout, Cell, CellStruct, Style, Table};
 use humantime::format_duration;
 use tako::messages::common::WorkerConfiguration;
 
@@ -11,13 +11,26 @@ pub fn print_worker_configuration(
     worker_id: WorkerId,
     configuration: WorkerConfiguration,
 ) {
+    let rows = worker_configuration_rows(worker_id, &configuration);
+    let table = rows.table().color_choice(gsettings.color_policy());
+    assert!(print_stdout(table).is_ok());
+}
+
+/// Creates (name, value) table rows that describe the configuration of a worker
+pub fn worker_configuration_rows(
+    worker_id: WorkerId,
+    configuration: &WorkerConfiguration,
+) -> Vec<Vec<CellStruct>> {
     let manager_info = configuration.get_manager_info();
-    let rows = vec![
+    vec![
         vec!["Worker ID".cell().bold(true), worker_id.cell()],
-        vec!["Hostname".cell().bold(true), configuration.hostname.cell()],
+        vec![
+            "Hostname".cell().bold(true),
+            configuration.hostname.as_str().cell(),
+        ],
         vec![
             "Data provider".cell().bold(true),
-            configuration.listen_address.cell(),
+            configuration.listen_address.as_str().cell(),
         ],
         vec![
             "Working directory".cell().bold(true),
@@ -61,7 +74,5 @@ pub fn print_worker_configuration(
                 .unwrap_or("N/A")
                 .cell(),
         ],
-    ];
-    let table = rows.table().color_choice(gsettings.color_policy());
-    assert!(print_stdout(table).is_ok());
+    ]
 }
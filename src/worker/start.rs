@@ -23,26 +23,30 @@ use crate::common::manager::info::{ManagerInfo, ManagerType, WORKER_EXTRA_MANAGE
 use crate::common::manager::pbs;
 use crate::common::serverdir::ServerDir;
 use crate::common::timeutils::ArgDuration;
-use crate::transfer::messages::TaskBody;
+use crate::common::WrappedRcRefCell;
+use crate::transfer::messages::{OutputFlush, TaskBody};
 use crate::transfer::stream::ChannelId;
 use crate::worker::hwdetect::detect_resource;
 use crate::worker::output::print_worker_configuration;
 use crate::worker::parser::parse_cpu_definition;
 use crate::worker::streamer::StreamSender;
 use crate::worker::streamer::StreamerRef;
-use crate::Map;
 use crate::{JobId, JobTaskId};
+use crate::{Map, Set};
 use std::future::Future;
 use std::io;
+use std::os::unix::io::FromRawFd;
 use std::pin::Pin;
 use std::process::ExitStatus;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::Duration;
 use tako::common::error::DsError;
 use tako::worker::taskenv::{StopReason, TaskResult};
 use tako::InstanceId;
-use tokio::io::AsyncReadExt;
-use tokio::sync::oneshot;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::ChildStdin;
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
 
 const STDIO_BUFFER_SIZE: usize = 16 * 1024; // 16kB
 
@@ -91,6 +95,15 @@ pub struct WorkerStartOpts {
     /// What HPC job manager should be used by the worker.
     #[clap(long, default_value = "detect", possible_values = & ["detect", "slurm", "pbs", "none"])]
     manager: ManagerOpts,
+
+    /// Maximal number of tasks whose processes are being launched at the same time.
+    /// Other tasks wait until a launch finishes, the number of running tasks is not affected.
+    #[clap(long)]
+    max_parallel_launches: Option<u32>,
+
+    /// Do not create missing parent directories of the stdout/stderr files of tasks
+    #[clap(long)]
+    no_create_output_dirs: bool,
 }
 
 /// Replace placeholders in user-defined program attributes
@@ -149,32 +162,66 @@ fn replace_placeholders(program: &mut ProgramDefinition) {
         .map_filename(|path| submit_dir.join(replace(&placeholder_map, &path)));
 }
 
+/// Takes the part of the buffered output that should be forwarded according to the flush policy
+fn take_flushable_output(pending: &mut Vec<u8>, flush: OutputFlush) -> Option<Vec<u8>> {
+    let size = match flush {
+        OutputFlush::Chunk => pending.len(),
+        // Lines longer than the buffer are split anyway
+        OutputFlush::Line if pending.len() >= STDIO_BUFFER_SIZE => pending.len(),
+        OutputFlush::Line => pending
+            .iter()
+            .rposition(|&c| c == b'\n')
+            .map(|position| position + 1)
+            .unwrap_or(0),
+        OutputFlush::Block if pending.len() >= STDIO_BUFFER_SIZE => STDIO_BUFFER_SIZE,
+        OutputFlush::Block => 0,
+    };
+    if size == 0 {
+        return None;
+    }
+    let rest = pending.split_off(size);
+    Some(std::mem::replace(pending, rest))
+}
+
 async fn resend_stdio(
     job_id: JobId,
     job_task_id: JobTaskId,
     channel: ChannelId,
     stdio: Option<impl tokio::io::AsyncRead + Unpin>,
     stream: Rc<StreamSender>,
+    flush: OutputFlush,
 ) -> tako::Result<()> {
     if let Some(mut stdio) = stdio {
         log::debug!("Starting stream {}/{}/1", job_id, job_task_id);
+        let mut pending = Vec::new();
         loop {
-            let mut buffer = vec![0; STDIO_BUFFER_SIZE];
-            let size = stdio.read(&mut buffer[..]).await?;
+            let start = pending.len();
+            pending.resize(start + STDIO_BUFFER_SIZE, 0);
+            let size = stdio.read(&mut pending[start..]).await?;
+            pending.truncate(start + size);
             if size == 0 {
                 break;
             };
-            buffer.truncate(size);
-            stream.send_data(channel, buffer).await?;
+            while let Some(data) = take_flushable_output(&mut pending, flush) {
+                stream.send_data(channel, data).await?;
+            }
+        }
+        if !pending.is_empty() {
+            stream.send_data(channel, pending).await?;
         }
     }
     Ok(())
 }
 
-fn create_directory_if_needed(file: &StdioDef) -> io::Result<()> {
+fn create_directory_if_needed(file: &StdioDef) -> tako::Result<()> {
     if let StdioDef::File(path) = file {
-        if let Some(path) = path.parent() {
-            std::fs::create_dir_all(path)?;
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory).map_err(|e| {
+                DsError::GenericError(format!(
+                    "Cannot create directory {:?} for output file {:?}: {}",
+                    directory, path, e
+                ))
+            })?;
         }
     }
     Ok(())
@@ -182,8 +229,11 @@ fn create_directory_if_needed(file: &StdioDef) -> io::Result<()> {
 
 async fn launcher_main(
     streamer_ref: StreamerRef,
+    launch_limit: Option<Arc<Semaphore>>,
+    process_groups: ProcessGroups,
+    create_output_dirs: bool,
     task_ref: TaskRef,
-    end_receiver: tokio::sync::oneshot::Receiver<StopReason>,
+    mut end_receiver: tokio::sync::oneshot::Receiver<StopReason>,
 ) -> tako::Result<TaskResult> {
     log::debug!(
         "Starting program launcher {} {:?} {:?}",
@@ -192,11 +242,24 @@ async fn launcher_main(
         task_ref.get().resource_allocation()
     );
 
-    let (program, job_id, job_task_id, instance_id): (
+    let (
+        program,
+        merge_stderr,
+        stdin,
+        job_id,
+        job_task_id,
+        instance_id,
+        output_flush,
+        cpu_time_limit,
+    ): (
         ProgramDefinition,
+        bool,
+        Option<BString>,
         JobId,
         JobTaskId,
         InstanceId,
+        OutputFlush,
+        Option<Duration>,
     ) = {
         let task = task_ref.get();
         let body: TaskBody = tako::transfer::auth::deserialize(&task.configuration.body)?;
@@ -220,18 +283,47 @@ async fn launcher_main(
 
         replace_placeholders(&mut program);
 
-        create_directory_if_needed(&program.stdout)?;
-        create_directory_if_needed(&program.stderr)?;
+        if create_output_dirs {
+            create_directory_if_needed(&program.stdout)?;
+            create_directory_if_needed(&program.stderr)?;
+        }
+
+        (
+            program,
+            body.merge_stderr_into_stdout,
+            body.stdin,
+            body.job_id,
+            body.task_id,
+            task.instance_id,
+            body.output_flush,
+            body.cpu_time_limit,
+        )
+    };
 
-        (program, body.job_id, body.task_id, task.instance_id)
+    // The permit is held during the launch phase of the task, see `hold_launch_permit`
+    let launch_permit = match launch_limit {
+        Some(semaphore) => tokio::select! {
+            biased;
+            r = &mut end_receiver => {
+                return Ok(r.unwrap().into());
+            }
+            permit = semaphore.acquire_owned() => Some(permit.unwrap())
+        },
+        None => None,
     };
 
     run_task(
         streamer_ref,
+        launch_permit,
+        process_groups,
         &program,
+        merge_stderr,
+        stdin,
         job_id,
         job_task_id,
         instance_id,
+        output_flush,
+        cpu_time_limit,
         end_receiver,
     )
     .await
@@ -242,10 +334,16 @@ async fn launcher_main(
 #[cfg(feature = "zero-worker")]
 async fn run_task(
     _streamer_ref: StreamerRef,
+    _launch_permit: Option<OwnedSemaphorePermit>,
+    _process_groups: ProcessGroups,
     _program: &ProgramDefinition,
+    _merge_stderr: bool,
+    _stdin: Option<BString>,
     _job_id: JobId,
     _job_task_id: JobTaskId,
     _instance_id: InstanceId,
+    _output_flush: OutputFlush,
+    _cpu_time_limit: Option<Duration>,
     _end_receiver: tokio::sync::oneshot::Receiver<StopReason>,
 ) -> tako::Result<TaskResult> {
     Ok(TaskResult::Finished)
@@ -254,13 +352,57 @@ async fn run_task(
 #[cfg(not(feature = "zero-worker"))]
 async fn run_task(
     streamer_ref: StreamerRef,
+    launch_permit: Option<OwnedSemaphorePermit>,
+    process_groups: ProcessGroups,
     program: &ProgramDefinition,
+    merge_stderr: bool,
+    stdin: Option<BString>,
     job_id: JobId,
     job_task_id: JobTaskId,
     instance_id: InstanceId,
+    output_flush: OutputFlush,
+    cpu_time_limit: Option<Duration>,
     end_receiver: tokio::sync::oneshot::Receiver<StopReason>,
 ) -> tako::Result<TaskResult> {
     let mut command = command_from_definitions(program)?;
+    if cpu_time_limit.is_some() {
+        // The task gets its own process group, so that the CPU time of all its processes
+        // can be measured and all of them can be killed when the task ends
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    if stdin.is_some() {
+        command.stdin(std::process::Stdio::piped());
+    }
+
+    // Read end of the pipe that is shared by stdout and stderr of a streamed task
+    let mut merged_output = None;
+    if merge_stderr {
+        // Both stdout and stderr have to share a single file handle,
+        // otherwise they would overwrite each other's output
+        match &program.stdout {
+            StdioDef::File(path) => {
+                let file = std::fs::File::create(path)?;
+                command.stderr(file.try_clone()?);
+                command.stdout(file);
+            }
+            StdioDef::Pipe => {
+                let (reader, writer) = create_pipe()?;
+                command.stderr(writer.try_clone()?);
+                command.stdout(writer);
+                merged_output = Some(tokio::fs::File::from_std(reader));
+            }
+            StdioDef::Null => {
+                command.stderr(std::process::Stdio::null());
+            }
+        }
+    }
 
     let status_to_result = |status: ExitStatus| {
         if !status.success() {
@@ -278,6 +420,11 @@ async fn run_task(
         let streamer_error =
             |e: DsError| DsError::GenericError(format!("Streamer: {:?}", e.to_string()));
         let mut child = command.spawn()?;
+        // The write end of the merged output pipe has to be closed in the worker,
+        // otherwise the end of the output would never be reached
+        drop(command);
+        let _group_guard = guard_process_group(&process_groups, child.id(), cpu_time_limit);
+        let cpu_limit_fut = check_cpu_time_limit(child.id(), cpu_time_limit);
         let (close_sender, close_responder) = oneshot::channel();
         let stream = Rc::new(streamer_ref.get_mut().get_stream(
             &streamer_ref,
@@ -292,24 +439,39 @@ async fn run_task(
         let stream2 = stream.clone();
 
         let main_fut = async move {
-            let stdout = child.stdout.take();
+            let stdout: Option<Box<dyn AsyncRead + Unpin>> = match merged_output {
+                Some(output) => Some(Box::new(output)),
+                None => child.stdout.take().map(|stdout| Box::new(stdout) as _),
+            };
             let stderr = child.stderr.take();
+            let child_stdin = child.stdin.take();
             let response = tokio::try_join!(
                 child.wait().map_err(DsError::from),
-                resend_stdio(job_id, job_task_id, 0, stdout, stream2.clone())
+                write_stdin(child_stdin, stdin).map_err(DsError::from),
+                resend_stdio(
+                    job_id,
+                    job_task_id,
+                    0,
+                    stdout,
+                    stream2.clone(),
+                    output_flush
+                )
+                .map_err(streamer_error),
+                resend_stdio(job_id, job_task_id, 1, stderr, stream2, output_flush)
                     .map_err(streamer_error),
-                resend_stdio(job_id, job_task_id, 1, stderr, stream2).map_err(streamer_error),
             );
             status_to_result(response?.0)
         };
+        let main_fut = hold_launch_permit(launch_permit, main_fut);
 
         let guard_fut = async move {
             let result = tokio::select! {
                 biased;
-                    r = end_receiver => {
-                        Ok(r.unwrap().into())
-                    }
-                    r = main_fut => r
+                r = end_receiver => {
+                    Ok(r.unwrap().into())
+                }
+                r = cpu_limit_fut => r,
+                r = main_fut => r
             };
             stream.close().await.map_err(streamer_error)?;
             result
@@ -323,24 +485,249 @@ async fn run_task(
         )?
         .0)
     } else {
+        let mut child = command.spawn()?;
+        let _group_guard = guard_process_group(&process_groups, child.id(), cpu_time_limit);
+        let cpu_limit_fut = check_cpu_time_limit(child.id(), cpu_time_limit);
+        let child_stdin = child.stdin.take();
+        let main_fut = async move {
+            tokio::try_join!(
+                child.wait().map_err(DsError::from),
+                write_stdin(child_stdin, stdin).map_err(DsError::from),
+            )
+        };
+        let main_fut = hold_launch_permit(launch_permit, main_fut);
         tokio::select! {
             biased;
-                r = end_receiver => {
-                    Ok(r.unwrap().into())
-                }
-                r = command.status() => status_to_result(r?)
+            r = end_receiver => {
+                Ok(r.unwrap().into())
+            }
+            r = cpu_limit_fut => r,
+            r = main_fut => status_to_result(r?.0)
+        }
+    }
+}
+
+/// How long is a freshly spawned task process considered to be launching.
+const LAUNCH_SETTLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Keeps the launch permit of a task until its process has had some time to start up
+/// (`LAUNCH_SETTLE_INTERVAL`) or until the task finishes, whichever comes first.
+async fn hold_launch_permit<F: Future>(permit: Option<OwnedSemaphorePermit>, fut: F) -> F::Output {
+    tokio::pin!(fut);
+    if let Some(permit) = permit {
+        tokio::select! {
+            r = &mut fut => return r,
+            _ = tokio::time::sleep(LAUNCH_SETTLE_INTERVAL) => drop(permit),
+        }
+    }
+    fut.await
+}
+
+const CPU_TIME_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Process groups of running tasks that were started in their own process group.
+type ProcessGroups = WrappedRcRefCell<Set<u32>>;
+
+/// Kills the process group of a task once the task ends, regardless of whether it has finished,
+/// failed or was canceled, so that no process of the task outlives it.
+struct ProcessGroupGuard {
+    pgid: u32,
+    process_groups: ProcessGroups,
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        self.process_groups.get_mut().remove(&self.pgid);
+        unsafe {
+            libc::kill(-(self.pgid as libc::pid_t), libc::SIGKILL);
+        }
+    }
+}
+
+/// Registers the process group of a task, if the task was started in its own process group.
+fn guard_process_group(
+    process_groups: &ProcessGroups,
+    pid: Option<u32>,
+    cpu_time_limit: Option<Duration>,
+) -> Option<ProcessGroupGuard> {
+    let pgid = pid.filter(|_| cpu_time_limit.is_some())?;
+    process_groups.get_mut().insert(pgid);
+    Some(ProcessGroupGuard {
+        pgid,
+        process_groups: process_groups.clone(),
+    })
+}
+
+/// Processes in their own process group do not receive signals sent to the process group of the
+/// worker (e.g. SIGINT from a terminal), therefore the signal is forwarded to them.
+fn interrupt_process_groups(process_groups: &ProcessGroups) {
+    for pgid in process_groups.get().iter() {
+        unsafe {
+            libc::kill(-(*pgid as libc::pid_t), libc::SIGINT);
         }
     }
 }
 
+/// Periodically checks the CPU time consumed by all processes of a task.
+/// The task process is expected to lead its own process group, the CPU time is summed over all
+/// processes of this group. Once the limit is exceeded, the future resolves with an error (the
+/// group is then killed by its `ProcessGroupGuard`). Without a limit, it never resolves.
+///
+/// The CPU time is read from `/proc`, therefore the limit is only enforced on Linux.
+async fn check_cpu_time_limit(
+    pid: Option<u32>,
+    limit: Option<Duration>,
+) -> tako::Result<TaskResult> {
+    let (pgid, limit) = match (pid, limit) {
+        (Some(pid), Some(limit)) => (pid, limit),
+        _ => return futures::future::pending().await,
+    };
+    if !std::path::Path::new("/proc/self/stat").exists() {
+        log::warn!("CPU time limit of a task cannot be enforced, /proc is not available");
+        return futures::future::pending().await;
+    }
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    let mut interval = tokio::time::interval(CPU_TIME_CHECK_INTERVAL);
+    let mut processes = Set::new();
+    processes.insert(pgid);
+    loop {
+        interval.tick().await;
+        let cpu_time = process_group_cpu_time(pgid, &mut processes, ticks_per_second).await;
+        if cpu_time > limit {
+            return Err(DsError::GenericError(format!(
+                "CPU time limit reached ({} consumed, limit {})",
+                humantime::format_duration(Duration::from_secs(cpu_time.as_secs())),
+                humantime::format_duration(limit)
+            )));
+        }
+    }
+}
+
+/// Sums the CPU time of all running processes that belong to the given process group.
+///
+/// Only the processes found in the previous check (`processes`) and their children are visited,
+/// instead of scanning all processes of the system. Processes stay tracked even if their parent
+/// terminates, `processes` is updated with the processes that were found.
+async fn process_group_cpu_time(
+    pgid: u32,
+    processes: &mut Set<u32>,
+    ticks_per_second: u64,
+) -> Duration {
+    let mut cpu_time = Duration::default();
+    let mut pending: Vec<u32> = processes.drain().collect();
+    while let Some(pid) = pending.pop() {
+        if processes.contains(&pid) {
+            continue;
+        }
+        // The process may have already terminated or left the process group
+        let stat = match tokio::fs::read_to_string(format!("/proc/{}/stat", pid)).await {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        match parse_process_stat(&stat, ticks_per_second) {
+            Some((group, process_time)) if group == pgid => cpu_time += process_time,
+            _ => continue,
+        }
+        processes.insert(pid);
+        pending.extend(process_children(pid).await);
+    }
+    cpu_time
+}
+
+/// Returns the child processes of all threads of the given process.
+async fn process_children(pid: u32) -> Vec<u32> {
+    let mut children = Vec::new();
+    let mut threads = match tokio::fs::read_dir(format!("/proc/{}/task", pid)).await {
+        Ok(threads) => threads,
+        Err(_) => return children,
+    };
+    while let Ok(Some(thread)) = threads.next_entry().await {
+        if let Ok(content) = tokio::fs::read_to_string(thread.path().join("children")).await {
+            children.extend(
+                content
+                    .split_whitespace()
+                    .filter_map(|pid| pid.parse().ok()),
+            );
+        }
+    }
+    children
+}
+
+/// Parses the process group and the CPU time (user and system) of a process and of its already
+/// terminated children from the content of `/proc/<pid>/stat`.
+fn parse_process_stat(stat: &str, ticks_per_second: u64) -> Option<(u32, Duration)> {
+    // The command name may contain spaces, the remaining fields follow after its closing bracket
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // pgrp (field 5 of the stat file)
+    let group = fields.get(2)?.parse::<u32>().ok()?;
+    // utime, stime, cutime and cstime (fields 14-17 of the stat file)
+    let ticks = fields
+        .get(11..15)?
+        .iter()
+        .map(|field| field.parse::<u64>().ok())
+        .sum::<Option<u64>>()?;
+    Some((
+        group,
+        Duration::from_millis(ticks * 1000 / ticks_per_second.max(1)),
+    ))
+}
+
+/// Creates an anonymous pipe and returns its read and write ends.
+/// Both ends are closed on exec, so that they are not leaked into other spawned tasks.
+fn create_pipe() -> io::Result<(std::fs::File, std::fs::File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (reader, writer) = unsafe {
+        (
+            std::fs::File::from_raw_fd(fds[0]),
+            std::fs::File::from_raw_fd(fds[1]),
+        )
+    };
+    for fd in &fds {
+        if unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((reader, writer))
+}
+
+/// Writes the given data into the standard input of a task and closes it afterwards.
+/// The task may exit without reading the whole input, therefore a broken pipe is not an error.
+async fn write_stdin(stdin: Option<ChildStdin>, data: Option<BString>) -> io::Result<()> {
+    if let (Some(mut stdin), Some(data)) = (stdin, data) {
+        match stdin.write_all(&data).await {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
 fn launcher(
     streamer_ref: &StreamerRef,
+    launch_limit: &Option<Arc<Semaphore>>,
+    process_groups: &ProcessGroups,
+    create_output_dirs: bool,
     task_ref: &TaskRef,
     end_receiver: tokio::sync::oneshot::Receiver<StopReason>,
 ) -> Pin<Box<dyn Future<Output = tako::Result<TaskResult>> + 'static>> {
     let task_ref = task_ref.clone();
     let streamer_ref = streamer_ref.clone();
-    Box::pin(async move { launcher_main(streamer_ref, task_ref, end_receiver).await })
+    let launch_limit = launch_limit.clone();
+    let process_groups = process_groups.clone();
+    Box::pin(async move {
+        launcher_main(
+            streamer_ref,
+            launch_limit,
+            process_groups,
+            create_output_dirs,
+            task_ref,
+            end_receiver,
+        )
+        .await
+    })
 }
 
 pub async fn start_hq_worker(
@@ -359,6 +746,12 @@ pub async fn start_hq_worker(
     let server_address = format!("{}:{}", record.host(), record.worker_port());
     log::info!("Connecting to: {}", server_address);
 
+    let launch_limit = match opts.max_parallel_launches {
+        Some(0) => anyhow::bail!("Maximal number of parallel launches has to be positive"),
+        Some(limit) => Some(Arc::new(Semaphore::new(limit as usize))),
+        None => None,
+    };
+    let create_output_dirs = !opts.no_create_output_dirs;
     let configuration = gather_configuration(opts)?;
 
     let server_addr = lookup_host(&server_address)
@@ -373,12 +766,24 @@ pub async fn start_hq_worker(
         record.tako_secret_key().clone(),
     );
 
+    let process_groups = ProcessGroups::default();
+    let task_process_groups = process_groups.clone();
+
     log::debug!("Starting Tako worker ...");
     let ((worker_id, configuration), worker_future) = run_worker(
         server_addr,
         configuration,
         Some(record.tako_secret_key().clone()),
-        Box::new(move |task_ref, end_receiver| launcher(&streamer_ref, task_ref, end_receiver)),
+        Box::new(move |task_ref, end_receiver| {
+            launcher(
+                &streamer_ref,
+                &launch_limit,
+                &task_process_groups,
+                create_output_dirs,
+                task_ref,
+                end_receiver,
+            )
+        }),
     )
     .await?;
     print_worker_configuration(gsettings, worker_id, configuration);
@@ -389,6 +794,10 @@ pub async fn start_hq_worker(
             tokio::select! {
                 () = worker_future => {}
                 () = streamer_future => {}
+                _ = tokio::signal::ctrl_c() => {
+                    log::info!("Interrupted, stopping the worker");
+                    interrupt_process_groups(&process_groups);
+                }
             }
         })
         .await;
@@ -494,9 +903,71 @@ mod tests {
     use tako::messages::common::{ProgramDefinition, StdioDef};
 
     use crate::common::env::{HQ_INSTANCE_ID, HQ_JOB_ID, HQ_SUBMIT_DIR, HQ_TASK_ID};
+    use crate::transfer::messages::OutputFlush;
     use crate::{JobId, JobTaskId, Map};
 
-    use super::replace_placeholders;
+    use super::{
+        parse_process_stat, replace_placeholders, take_flushable_output, STDIO_BUFFER_SIZE,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_process_stat() {
+        let stat = "1234 (my (task) 1) R 1 1200 1234 0 -1 4194304 100 0 0 0 \
+                    250 50 10 40 20 0 1 0 100 1000 100";
+        assert_eq!(
+            parse_process_stat(stat, 100),
+            Some((1200, Duration::from_millis(3500)))
+        );
+        assert_eq!(parse_process_stat("1234 (task) R 1", 100), None);
+    }
+
+    #[test]
+    fn test_flush_output_chunk() {
+        let mut pending = b"a\nb".to_vec();
+        assert_eq!(
+            take_flushable_output(&mut pending, OutputFlush::Chunk),
+            Some(b"a\nb".to_vec())
+        );
+        assert!(pending.is_empty());
+        assert_eq!(
+            take_flushable_output(&mut pending, OutputFlush::Chunk),
+            None
+        );
+    }
+
+    #[test]
+    fn test_flush_output_line() {
+        let mut pending = b"a\nb\nc".to_vec();
+        assert_eq!(
+            take_flushable_output(&mut pending, OutputFlush::Line),
+            Some(b"a\nb\n".to_vec())
+        );
+        assert_eq!(pending, b"c");
+        assert_eq!(take_flushable_output(&mut pending, OutputFlush::Line), None);
+
+        let mut pending = vec![b'x'; STDIO_BUFFER_SIZE];
+        assert_eq!(
+            take_flushable_output(&mut pending, OutputFlush::Line).map(|data| data.len()),
+            Some(STDIO_BUFFER_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_flush_output_block() {
+        let mut pending = vec![b'x'; 100];
+        assert_eq!(
+            take_flushable_output(&mut pending, OutputFlush::Block),
+            None
+        );
+
+        let mut pending = vec![b'x'; STDIO_BUFFER_SIZE + 100];
+        assert_eq!(
+            take_flushable_output(&mut pending, OutputFlush::Block).map(|data| data.len()),
+            Some(STDIO_BUFFER_SIZE)
+        );
+        assert_eq!(pending.len(), 100);
+    }
 
     #[test]
     fn test_replace_task_id() {
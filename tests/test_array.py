@@ -189,3 +189,72 @@ def test_array_mix_with_simple_jobs(hq_env: HqEnv):
         assert table[i][0] == str(i)
         assert table[i][2] == "FINISHED"
         assert table[i][3] == "4" if i % 2 == 1 else "1"
+
+
+def test_job_array_step(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=4)
+    hq_env.command(["submit", "--array=1-20:5", "--", "bash", "-c", "echo $HQ_TASK_ID"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    table = hq_env.command(["job", "1"], as_table=True)
+    table.check_value_row("Tasks", "4; Ids: 1-20:5")
+
+    for i in range(1, 21):
+        stdout = os.path.join(hq_env.work_path, f"job-1/stdout.{i}")
+        assert os.path.isfile(stdout) == (i in (1, 6, 11, 16))
+
+
+def test_job_array_canary_success(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=4)
+    hq_env.command(
+        [
+            "submit",
+            "--array=0-3",
+            "--canary",
+            "--",
+            "bash",
+            "-c",
+            # Other tasks must not run while the canary is running
+            "if [ $HQ_TASK_ID = 0 ]; then sleep 1; ls started-* && exit 1; fi; "
+            "touch started-$HQ_TASK_ID",
+        ]
+    )
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    table = hq_env.command(["job", "1"], as_table=True)
+    assert table.get_row_value("State").split("\n")[-1] == "FINISHED (4)"
+
+
+def test_job_array_canary_failure(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=4)
+    hq_env.command(
+        [
+            "submit",
+            "--array=0-3",
+            "--canary",
+            "--",
+            "python3",
+            "-c",
+            "import os; assert os.environ['HQ_TASK_ID'] != '0'",
+        ]
+    )
+    wait_for_job_state(hq_env, 1, "CANCELED")
+
+    table = hq_env.command(["job", "1"], as_table=True)
+    states = table.get_row_value("State").split("\n")
+    assert "FAILED (1)" in states
+    assert "CANCELED (3)" in states
+
+
+def test_job_array_canary_cancel(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(["submit", "--array=0-3", "--canary", "--", "hostname"])
+    hq_env.command(["cancel", "1"])
+    wait_for_job_state(hq_env, 1, "CANCELED")
+
+    table = hq_env.command(["job", "1"], as_table=True)
+    states = table.get_row_value("State").split("\n")
+    assert "CANCELED (4)" in states
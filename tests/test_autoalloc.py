@@ -0,0 +1,449 @@
+import json
+import os
+import time
+
+from .conftest import HqEnv
+
+
+def test_alloc_info_missing_descriptor(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(["alloc", "info", "foo"], expect_fail="Descriptor foo not found")
+
+
+QSTAT_QUEUED = """
+import sys
+import json
+
+job_id = sys.argv[-1]
+print(json.dumps({"Jobs": {job_id: {"job_state": "Q"}}}))
+"""
+
+
+def test_pbs_queue_submit_script(hq_env: HqEnv):
+    hq_env.start_server(args=["--autoalloc-interval", "100ms"])
+    with hq_env.mock.mock_program("qsub", "print('1.pbs')"):
+        with hq_env.mock.mock_program("qstat", QSTAT_QUEUED):
+            hq_env.command(
+                [
+                    "alloc",
+                    "add",
+                    "pbs",
+                    "--name",
+                    "foo",
+                    "--queue",
+                    "qexp",
+                    "--time-limit",
+                    "1h",
+                ]
+            )
+            time.sleep(0.5)
+
+            table = hq_env.command(["alloc", "info", "foo"], as_table=True)
+            assert len(table) == 2
+            table.check_value_columns(["Id", "State"], 0, ["1.pbs", "QUEUED"])
+
+            script = hq_env.command(
+                ["alloc", "info", "foo", "--allocation", "1.pbs", "--show-script"]
+            )
+            assert "#PBS -q qexp\n" in script
+            assert "#PBS -l walltime=01:00:00\n" in script
+            assert "worker start --manager pbs" in script
+
+            hq_env.command(
+                ["alloc", "info", "foo", "--allocation", "2.pbs", "--show-script"],
+                expect_fail="Allocation 2.pbs not found",
+            )
+
+
+QSTAT_RUNNING = """
+import sys
+import json
+
+job_id = sys.argv[-1]
+data = {"job_state": "R", "stime": "Mon Oct 11 10:00:00 2021"}
+print(json.dumps({"Jobs": {job_id: data}}))
+"""
+
+
+def test_pbs_queue_nudged_by_submit(hq_env: HqEnv):
+    hq_env.start_server(args=["--autoalloc-interval", "1h"])
+    with hq_env.mock.mock_program("qsub", "print('1.pbs')"):
+        with hq_env.mock.mock_program("qstat", QSTAT_QUEUED):
+            hq_env.command(["alloc", "add", "pbs", "--name", "foo", "--queue", "q"])
+            time.sleep(0.5)
+            table = hq_env.command(["alloc", "info", "foo"], as_table=True)
+            assert len(table) == 1
+
+            hq_env.command(["submit", "--", "hostname"])
+            time.sleep(1.5)
+            table = hq_env.command(["alloc", "info", "foo"], as_table=True)
+            assert len(table) == 2
+            table.check_value_columns(["Id", "State"], 0, ["1.pbs", "QUEUED"])
+
+
+def test_pbs_queue_worker_start_timeout(hq_env: HqEnv):
+    hq_env.start_server(args=["--autoalloc-interval", "100ms"])
+    with hq_env.mock.mock_program("qsub", "print('1.pbs')"):
+        with hq_env.mock.mock_program("qstat", QSTAT_RUNNING):
+            hq_env.command(
+                [
+                    "alloc",
+                    "add",
+                    "pbs",
+                    "--name",
+                    "foo",
+                    "--queue",
+                    "qexp",
+                    "--start-cmd-timeout",
+                    "1s",
+                ]
+            )
+            time.sleep(0.5)
+
+            table = hq_env.command(["alloc", "info", "foo"], as_table=True)
+            table.check_value_columns(
+                ["Id", "State"], 0, ["1.pbs", "RUNNING (NO WORKER CONNECTED)"]
+            )
+
+    hq_env.command(
+        [
+            "alloc",
+            "add",
+            "pbs",
+            "--name",
+            "bar",
+            "--queue",
+            "q",
+            "--cancel-on-start-timeout",
+        ],
+        expect_fail="--cancel-on-start-timeout requires --start-cmd-timeout",
+    )
+
+
+def test_pbs_queue_worker_env(hq_env: HqEnv):
+    hq_env.start_server(args=["--autoalloc-interval", "100ms"])
+    with hq_env.mock.mock_program("qsub", "print('1.pbs')"):
+        with hq_env.mock.mock_program("qstat", QSTAT_QUEUED):
+            hq_env.command(
+                [
+                    "alloc",
+                    "add",
+                    "pbs",
+                    "--name",
+                    "foo",
+                    "--queue",
+                    "qexp",
+                    "--worker-env=A=1",
+                    "--worker-env=LIB_PATH=/gpu/lib:$LIB_PATH",
+                ]
+            )
+            time.sleep(0.5)
+
+            script = hq_env.command(
+                ["alloc", "info", "foo", "--allocation", "1.pbs", "--show-script"]
+            )
+            assert 'env A="1" LIB_PATH="/gpu/lib:$LIB_PATH" ' in script
+
+    hq_env.command(
+        ["alloc", "add", "pbs", "--name", "bar", "--queue", "q", "--worker-env=1A=x"],
+        expect_fail="Invalid name of environment variable: 1A",
+    )
+
+
+def test_pbs_queue_node_resources(hq_env: HqEnv):
+    hq_env.start_server(args=["--autoalloc-interval", "100ms"])
+    with hq_env.mock.mock_program("qsub", "print('1.pbs')"):
+        with hq_env.mock.mock_program("qstat", QSTAT_QUEUED):
+            hq_env.command(
+                [
+                    "alloc",
+                    "add",
+                    "pbs",
+                    "--name",
+                    "foo",
+                    "--queue",
+                    "qexp",
+                    "--node-resources=ngpus=4:mem=64gb",
+                ]
+            )
+            time.sleep(0.5)
+
+            script = hq_env.command(
+                ["alloc", "info", "foo", "--allocation", "1.pbs", "--show-script"]
+            )
+            assert "#PBS -l select=1:ngpus=4:mem=64gb\n" in script
+
+    hq_env.command(
+        ["alloc", "add", "pbs", "--name", "bar", "--queue", "q"]
+        + ["--node-resources", "ngpus=4 mem=64gb"],
+        expect_fail="PBS node resources cannot contain whitespace",
+    )
+
+
+def test_add_queue_with_same_name_twice(hq_env: HqEnv):
+    hq_env.start_server()
+    args = ["alloc", "add", "slurm", "--name", "foo", "--partition", "p"]
+    hq_env.command(args)
+    hq_env.command(args, expect_fail="Descriptor named foo already exists")
+
+
+def test_add_queue_max_allocation_duration(hq_env: HqEnv):
+    hq_env.start_server(args=["--max-allocation-duration", "2h"])
+    args = ["alloc", "add", "slurm", "--partition", "p"]
+    hq_env.command(
+        args + ["--name", "foo", "--time-limit", "3h"],
+        expect_fail="Time limit 3h exceeds the maximum allocation duration 2h",
+    )
+    hq_env.command(
+        args + ["--name", "foo", "--time-limit", "3h", "--dry-run"],
+        expect_fail="Time limit 3h exceeds the maximum allocation duration 2h",
+    )
+    # The time limit defaults to the maximum allocation duration
+    output = hq_env.command(args + ["--name", "foo", "--dry-run"])
+    assert "#SBATCH --time=02:00:00\n" in output
+
+    hq_env.command(args + ["--name", "foo", "--time-limit", "2h"])
+    hq_env.command(args + ["--name", "bar"])
+
+
+def test_add_queue_min_workers_per_alloc(hq_env: HqEnv):
+    hq_env.start_server()
+    args = ["alloc", "add", "slurm", "--partition", "p"]
+    hq_env.command(
+        args
+        + ["--name", "foo", "--min-workers-per-alloc", "4"]
+        + ["--max-workers-per-alloc", "2"],
+        expect_fail="--min-workers-per-alloc has to be between 1",
+    )
+    hq_env.command(
+        args
+        + ["--name", "foo", "--min-workers-per-alloc", "1"]
+        + ["--max-workers-per-alloc", "4"]
+    )
+
+
+def test_pbs_cancel_allocation(hq_env: HqEnv):
+    hq_env.start_server(args=["--autoalloc-interval", "100ms"])
+    with hq_env.mock.mock_program("qsub", "print('1.pbs')"):
+        with hq_env.mock.mock_program("qstat", QSTAT_QUEUED):
+            with hq_env.mock.mock_program("qdel", "print('deleted')"):
+                hq_env.command(
+                    ["alloc", "add", "pbs", "--name", "foo", "--queue", "qexp"]
+                )
+                time.sleep(0.5)
+
+                hq_env.command(
+                    ["alloc", "cancel", "foo", "2.pbs"],
+                    expect_fail="Allocation 2.pbs not found",
+                )
+                output = hq_env.command(["alloc", "cancel", "foo", "1.pbs"])
+                assert "Allocation 1.pbs was canceled" in output
+
+
+def test_alloc_info_all_queues(hq_env: HqEnv):
+    hq_env.start_server(args=["--autoalloc-interval", "100ms"])
+    counter = os.path.join(hq_env.work_path, "qsub-counter")
+    qsub = f"""
+import os
+
+path = {counter!r}
+count = int(open(path).read()) + 1 if os.path.exists(path) else 1
+with open(path, "w") as f:
+    f.write(str(count))
+print(f"{{count}}.pbs")
+"""
+    with hq_env.mock.mock_program("qsub", qsub):
+        with hq_env.mock.mock_program("qstat", QSTAT_QUEUED):
+            for name in ("foo", "bar"):
+                hq_env.command(["alloc", "add", "pbs", "--name", name, "--queue", "q"])
+            time.sleep(0.5)
+
+            table = hq_env.command(["alloc", "info", "--all"], as_table=True)
+            assert len(table) == 3
+            assert table.get_column_value("Queue") == ["bar", "foo"]
+            assert sorted(table.get_column_value("Id")) == ["1.pbs", "2.pbs"]
+
+            hq_env.command(
+                ["alloc", "info", "foo", "--all"],
+                expect_fail="A queue name cannot be combined with --all",
+            )
+            hq_env.command(["alloc", "info"], expect_fail="Specify a queue name")
+
+
+def test_update_queue(hq_env: HqEnv):
+    hq_env.start_server(args=["--autoalloc-interval", "100ms"])
+    counter = os.path.join(hq_env.work_path, "qsub-counter")
+    qsub = f"""
+import os
+
+path = {counter!r}
+count = int(open(path).read()) + 1 if os.path.exists(path) else 1
+with open(path, "w") as f:
+    f.write(str(count))
+print(f"{{count}}.pbs")
+"""
+    with hq_env.mock.mock_program("qsub", qsub):
+        with hq_env.mock.mock_program("qstat", QSTAT_QUEUED):
+            hq_env.command(["alloc", "add", "pbs", "--name", "foo", "--queue", "q"])
+            time.sleep(0.5)
+            table = hq_env.command(["alloc", "info", "foo"], as_table=True)
+            assert len(table) == 2
+
+            output = hq_env.command(["alloc", "update", "foo", "--workers", "3"])
+            assert "Allocation queue foo was updated" in output
+            time.sleep(0.5)
+            table = hq_env.command(["alloc", "info", "foo"], as_table=True)
+            assert table.get_column_value("Id") == ["1.pbs", "2.pbs", "3.pbs"]
+
+
+def test_update_queue_invalid(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(
+        ["alloc", "update", "foo", "--workers", "2"],
+        expect_fail="Descriptor foo not found",
+    )
+    hq_env.command(["alloc", "add", "slurm", "--name", "foo", "--partition", "p"])
+    hq_env.command(
+        ["alloc", "update", "foo"], expect_fail="Specify at least one of --workers"
+    )
+    hq_env.command(
+        ["alloc", "update", "foo", "--min-workers-per-alloc", "2"],
+        expect_fail="Minimum workers per allocation (2) has to be between 1",
+    )
+
+
+def test_add_queue_dry_run(hq_env: HqEnv):
+    hq_env.start_server()
+    output = hq_env.command(
+        [
+            "alloc",
+            "add",
+            "pbs",
+            "--name",
+            "foo",
+            "--queue",
+            "qexp",
+            "--max-workers-per-alloc",
+            "2",
+            "--dry-run",
+            "--",
+            "-A",
+            "project",
+        ]
+    )
+    assert "qsub -A project " in output
+    assert "#PBS -l select=2\n" in output
+    assert "worker start --manager pbs" in output
+
+    # The queue was not created
+    hq_env.command(["alloc", "info", "foo"], expect_fail="Descriptor foo not found")
+
+
+def test_pause_queue(hq_env: HqEnv):
+    # Allocations are only created when the queue is nudged by a submit
+    hq_env.start_server(args=["--autoalloc-interval", "1h"])
+    with hq_env.mock.mock_program("qsub", "print('1.pbs')"):
+        with hq_env.mock.mock_program("qstat", QSTAT_QUEUED):
+            hq_env.command(["alloc", "add", "pbs", "--name", "foo", "--queue", "q"])
+            output = hq_env.command(["alloc", "pause", "foo"])
+            assert "Allocation queue foo was paused" in output
+            hq_env.command(["submit", "--", "hostname"])
+            time.sleep(1.5)
+            output = hq_env.command(["alloc", "info", "foo"])
+            assert "Queue foo is paused" in output
+            assert "1.pbs" not in output
+
+            output = hq_env.command(["alloc", "resume", "foo"])
+            assert "Allocation queue foo was resumed" in output
+            hq_env.command(["submit", "--", "hostname"])
+            time.sleep(1.5)
+            table = hq_env.command(["alloc", "info", "foo"], as_table=True)
+            assert table.get_column_value("Id") == ["1.pbs"]
+
+    hq_env.command(["alloc", "pause", "bar"], expect_fail="Descriptor bar not found")
+
+
+def test_add_queue_idle_timeout(hq_env: HqEnv):
+    hq_env.start_server()
+    args = ["alloc", "add", "pbs", "--name", "foo", "--queue", "q"]
+    hq_env.command(
+        args + ["--time-limit", "1h", "--idle-timeout", "2h"],
+        expect_fail="Idle timeout 2h has to be shorter than the time limit 1h",
+    )
+    hq_env.command(
+        args + ["--time-limit", "1h", "--idle-timeout", "2h", "--dry-run"],
+        expect_fail="Idle timeout 2h has to be shorter than the time limit 1h",
+    )
+
+    output = hq_env.command(args + ["--idle-timeout", "5m", "--dry-run"])
+    assert 'worker start --manager pbs --server-dir "' in output
+    assert '--idle-timeout "5m"' in output
+
+
+def test_queue_event_log(hq_env: HqEnv):
+    hq_env.start_server(args=["--autoalloc-interval", "100ms"])
+    with hq_env.mock.mock_program("qsub", "print('1.pbs')"):
+        with hq_env.mock.mock_program("qstat", QSTAT_QUEUED):
+            hq_env.command(
+                ["alloc", "add", "pbs", "--name", "foo", "--queue", "q"]
+                + ["--event-log", "events.json"]
+            )
+            time.sleep(0.5)
+
+    with open(os.path.join(hq_env.work_path, "events.json")) as f:
+        event = json.loads(f.readline())
+    assert event["queue"] == "foo"
+    assert event["event"] == "queue-success"
+    assert event["allocation_id"] == "1.pbs"
+    assert "timestamp" in event
+
+
+def test_queue_submission_backoff(hq_env: HqEnv):
+    hq_env.start_server(args=["--autoalloc-interval", "100ms"])
+    with hq_env.mock.mock_program("qsub", "import sys; sys.exit(1)"):
+        hq_env.command(["alloc", "add", "pbs", "--name", "foo", "--queue", "q"])
+        time.sleep(0.5)
+        output = hq_env.command(["alloc", "info", "foo"])
+        assert "Queue foo: " in output
+        assert "failed submission(s) in a row, next attempt at" in output
+
+
+def test_alloc_info_json(hq_env: HqEnv):
+    hq_env.start_server(args=["--autoalloc-interval", "100ms"])
+    with hq_env.mock.mock_program("qsub", "print('1.pbs')"):
+        with hq_env.mock.mock_program("qstat", QSTAT_QUEUED):
+            hq_env.command(["alloc", "add", "pbs", "--name", "foo", "--queue", "q"])
+            time.sleep(0.5)
+
+            allocations = json.loads(hq_env.command(["alloc", "info", "foo", "--json"]))
+            assert len(allocations) == 1
+            allocation = allocations[0]
+            assert allocation["queue"] == "foo"
+            assert allocation["id"] == "1.pbs"
+            assert allocation["worker_count"] == 1
+            assert allocation["state"] == "queued"
+            assert allocation["queued_at"] is not None
+            assert allocation["started_at"] is None
+            assert not allocation["start_timeout_exceeded"]
+            assert allocation["working_dir"].startswith(hq_env.server_dir)
+
+            hq_env.command(
+                ["alloc", "info", "foo", "--json", "--watch"],
+                expect_fail="--json cannot be combined with --watch",
+            )
+
+
+def test_alloc_info_json_running(hq_env: HqEnv):
+    hq_env.start_server(args=["--autoalloc-interval", "100ms"])
+    with hq_env.mock.mock_program("qsub", "print('1.pbs')"):
+        with hq_env.mock.mock_program("qstat", QSTAT_RUNNING):
+            hq_env.command(["alloc", "add", "pbs", "--name", "foo", "--queue", "q"])
+            time.sleep(0.5)
+
+            allocations = json.loads(hq_env.command(["alloc", "info", "foo", "--json"]))
+            allocation = allocations[0]
+            assert allocation["state"] == "running"
+            assert allocation["queued_at"] is not None
+            assert allocation["started_at"] is not None
+            assert not allocation["worker_connected"]
+            assert not allocation["start_timeout_exceeded"]
@@ -4,7 +4,7 @@ import subprocess
 import pytest
 
 from .conftest import RUNNING_IN_CI, HqEnv
-from .utils import wait_for_job_state
+from .utils import wait_for_job_state, wait_for_worker_state
 
 
 def read_list(filename):
@@ -146,3 +146,47 @@ def test_job_pin(hq_env: HqEnv):
     with open("job-1/stdout.0") as f:
         hq_cpus = f.readline().rstrip()
         assert hq_cpus == f.readline().rstrip().split(" ")[5]
+
+
+def test_job_resources_string(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(["submit", "--resources", "cpus=2 scatter", "--", "hostname"])
+    hq_env.command(
+        ["submit", "--resources", "cpus=1,gpu=2", "--", "hostname"],
+        expect_fail="Unknown resource 'gpu'",
+    )
+    hq_env.command(
+        ["submit", "--resources", "cpus=2", "--cpus", "1", "--", "hostname"],
+        expect_fail="cannot be used with",
+    )
+
+    table = hq_env.command(["job", "1"], as_table=True)
+    table.check_value_row("Resources", "2 scatter")
+
+
+def test_server_default_resources(hq_env: HqEnv):
+    hq_env.start_server(args=["--default-resources", "cpus=2"])
+    table = hq_env.command(["server", "info"], as_table=True)
+    table.check_value_row("Default resources", "cpus=2 compact")
+
+    hq_env.command(["submit", "--", "hostname"])
+    hq_env.command(["submit", "--cpus", "3", "--", "hostname"])
+
+    table = hq_env.command(["job", "1"], as_table=True)
+    table.check_value_row("Resources", "2 compact")
+    table = hq_env.command(["job", "2"], as_table=True)
+    table.check_value_row("Resources", "3 compact")
+
+
+def test_submit_warn_unsatisfiable_cpus(hq_env: HqEnv):
+    hq_env.start_server()
+    output = hq_env.command(["submit", "--cpus", "4", "--", "hostname"])
+    assert "largest connected worker" not in output
+
+    hq_env.start_worker(cpus=2)
+    wait_for_worker_state(hq_env, 1, "RUNNING")
+
+    output = hq_env.command(["submit", "--cpus", "4", "--", "hostname"])
+    assert "the largest connected worker has only 2 CPUs" in output
+    output = hq_env.command(["submit", "--cpus", "2", "--", "hostname"])
+    assert "largest connected worker" not in output
@@ -60,3 +60,73 @@ def test_entries_with_newline(hq_env: HqEnv):
 
     table = hq_env.command(["job", "1"], as_table=True)
     assert table.get_row_value("State").split("\n")[-1] == "FINISHED (4)"
+
+
+def test_entries_large_submit(hq_env: HqEnv):
+    hq_env.start_server()
+
+    count = 50_000
+    with open("input", "w") as f:
+        f.write("".join(f"entry-{i}\n" for i in range(count)))
+
+    hq_env.command(["submit", "--each-line=input", "--", "bash", "-c", "echo $HQ_ENTRY"])
+
+    table = hq_env.command(["jobs"], as_table=True)
+    table.check_value_column("Tasks", 0, str(count))
+
+
+def test_stdin_per_task_file(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=2)
+
+    with open("input", "w") as f:
+        f.write("One\n---\nTwo\n---\nThree\n")
+
+    hq_env.command(
+        [
+            "submit",
+            "--array=1-3",
+            "--stdin-per-task=input",
+            "--stdin-delimiter=---\n",
+            "--",
+            "cat",
+        ]
+    )
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    for i, test in zip(range(1, 4), ["One\n", "Two\n", "Three\n"]):
+        with open(f"job-1/stdout.{i}") as f:
+            assert f.read() == test
+
+
+def test_stdin_per_task_directory(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=2)
+
+    os.mkdir("inputs")
+    for name, content in [("b", "Second"), ("a", "First")]:
+        with open(os.path.join("inputs", name), "w") as f:
+            f.write(content)
+
+    hq_env.command(["submit", "--array=0-1", "--stdin-per-task=inputs", "--", "cat"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    for i, test in enumerate(["First", "Second"]):
+        with open(f"job-1/stdout.{i}") as f:
+            assert f.read() == test
+
+
+def test_stdin_per_task_count_mismatch(hq_env: HqEnv):
+    hq_env.start_server()
+
+    with open("input", "w") as f:
+        f.write("One\nTwo\n")
+
+    hq_env.command(
+        ["submit", "--array=1-3", "--stdin-per-task=input", "--", "cat"],
+        expect_fail="Number of stdin chunks (2) does not match the number of tasks (3)",
+    )
+    hq_env.command(
+        ["submit", "--stdin-per-task=input", "--", "cat"],
+        expect_fail="can be used only together with --array or --each-line",
+    )
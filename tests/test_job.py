@@ -1,3 +1,4 @@
+import json
 import os
 import socket
 from os.path import isdir, isfile
@@ -9,6 +10,7 @@ import pytest
 
 from .conftest import HqEnv
 from .utils import wait_for_job_state, JOB_TABLE_ROWS
+from .utils.wait import wait_until
 
 
 def test_job_submit(hq_env: HqEnv):
@@ -85,6 +87,29 @@ def test_custom_name(hq_env: HqEnv, tmp_path):
     assert len(table) == 2
 
 
+def test_custom_name_template(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(
+        ["submit", "--array=1-3", "--name=render-%{JOB_ID}-%{TASK_ID}-%%", "hostname"]
+    )
+    hq_env.command(["submit", "--name=50%-%{TASKID}", "hostname"])
+    hq_env.start_worker()
+    wait_for_job_state(hq_env, [1, 2], "FINISHED")
+
+    table = hq_env.command(["job", "1"], as_table=True)
+    table.check_value_row("Name", "render-%{JOB_ID}-%{TASK_ID}-%%")
+
+    table = hq_env.command(["job", "1", "--tasks"], as_table=True)
+    offset = JOB_TABLE_ROWS
+    assert table[offset][:2] == ["Task Id", "Name"]
+    for i in range(3):
+        assert table[offset + 1 + i][:2] == [str(i + 1), f"render-1-{i + 1}-%%"]
+
+    # Unknown placeholders are kept literally
+    table = hq_env.command(["job", "2"], as_table=True)
+    table.check_value_row("Name", "50%-%{TASKID}")
+
+
 def test_custom_working_dir(hq_env: HqEnv, tmpdir):
     hq_env.start_server()
 
@@ -180,6 +205,160 @@ def test_job_output_none(hq_env: HqEnv, tmp_path):
     assert not os.path.exists(os.path.join(tmp_path, "stderr.1.0"))
 
 
+def test_job_merge_stderr(hq_env: HqEnv, tmp_path):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=1)
+    hq_env.command(
+        [
+            "submit",
+            "--stdout=out",
+            "--merge-stderr",
+            "--",
+            "bash",
+            "-c",
+            "echo 'hello'; echo 'world' >&2; echo 'end'",
+        ]
+    )
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    with open(os.path.join(tmp_path, "out")) as f:
+        assert f.read() == "hello\nworld\nend\n"
+    assert not os.path.exists(os.path.join(tmp_path, "job-1"))
+
+    table = hq_env.command(["job", "1"], as_table=True)
+    table.check_value_row("Stderr", "<Merged into stdout>")
+
+
+def test_job_attach(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=1)
+    output = hq_env.command(
+        ["submit", "--attach", "--", "bash", "-c", "echo 'hello'; echo 'world' >&2"]
+    )
+    assert "hello\n" in output
+    assert "world\n" in output
+
+
+def test_job_attach_exit_code(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=1)
+    process = hq_env.command(
+        ["submit", "--attach", "--", "bash", "-c", "echo 'hello'; exit 3"], wait=False
+    )
+    assert process.wait() == 3
+
+
+def test_job_attach_array(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(
+        ["submit", "--attach", "--array=1-2", "--", "hostname"],
+        expect_fail="Option --attach can be used only for jobs with a single task",
+    )
+
+
+def test_job_attach_log(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(
+        [
+            "submit",
+            "--array=1-3",
+            "--",
+            "bash",
+            "-c",
+            "echo task $HQ_TASK_ID; sleep 1; echo done >&2",
+        ]
+    )
+    hq_env.start_worker(cpus=2)
+    output = hq_env.command(["attach-log", "1"])
+    for task_id in range(1, 4):
+        assert f"{task_id}: task {task_id}\n" in output
+        assert f"{task_id}: done\n" in output
+
+
+def test_job_attach_log_streamed(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(
+        [
+            "submit",
+            "--log",
+            "mylog",
+            "--array=1-3",
+            "--",
+            "bash",
+            "-c",
+            "echo A${HQ_TASK_ID}; sleep 1; echo B${HQ_TASK_ID}",
+        ]
+    )
+    hq_env.start_worker(cpus=2)
+    lines = set(hq_env.command(["attach-log", "1"], as_lines=True))
+    for task_id in range(1, 4):
+        assert f"{task_id}:0> A{task_id}" in lines
+        assert f"{task_id}:0> B{task_id}" in lines
+        assert f"{task_id}: > stream closed" in lines
+
+
+def test_job_attach_log_failed_task(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=1)
+    hq_env.command(["submit", "--array=1-2", "--", "bash", "-c", "exit $HQ_TASK_ID"])
+    hq_env.command(["attach-log", "1"], expect_fail="2 task(s) of job 1 failed")
+
+
+def test_job_shared_output_warning(hq_env: HqEnv):
+    hq_env.start_server()
+    output = hq_env.command(
+        ["submit", "--array=1-3", "--stdout=out.txt", "--", "hostname"]
+    )
+    assert "All 3 tasks of the job write their stdout into the same file" in output
+
+    hq_env.command(
+        ["submit", "--strict", "--array=1-3", "--stdout=out.txt", "--", "hostname"],
+        expect_fail="All 3 tasks of the job write their stdout into the same file",
+    )
+    table = hq_env.command(["jobs"], as_table=True)
+    assert len(table) == 2
+
+
+def test_job_progress(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(["submit", "--array=1-4", "--", "hostname"])
+
+    process = hq_env.command(["progress", "1"], wait=False)
+    assert process.wait() == 2
+
+    hq_env.start_worker(cpus=1)
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    progress = json.loads(hq_env.command(["progress", "1"]))
+    assert progress == {
+        "id": 1,
+        "name": "hostname",
+        "state": "finished",
+        "tasks": 4,
+        "waiting": 0,
+        "running": 0,
+        "finished": 4,
+        "failed": 0,
+        "canceled": 0,
+    }
+
+
+def test_job_progress_failed(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=1)
+    hq_env.command(["submit", "--", "bash", "-c", "exit 1"])
+    wait_for_job_state(hq_env, 1, "FAILED")
+
+    process = hq_env.command(["progress", "last"], wait=False)
+    assert process.wait() == 3
+
+
+def test_job_progress_invalid_job(hq_env: HqEnv):
+    hq_env.start_server()
+    process = hq_env.command(["progress", "1"], wait=False)
+    assert process.wait() == 1
+
+
 def test_job_filters(hq_env: HqEnv):
     hq_env.start_server()
 
@@ -339,6 +518,57 @@ def test_cancel_all(hq_env: HqEnv):
     assert "Job 3 canceled" in r[0]
 
 
+def test_job_deferred_start(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=1)
+    hq_env.command(["submit", "--after=2s", "--", "hostname"])
+    time.sleep(1)
+    table = hq_env.command(["jobs"], as_table=True)
+    assert table[1][2] == "WAITING"
+
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+
+def test_job_deferred_start_cancel(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=1)
+    hq_env.command(["submit", "--after=1s", "--", "hostname"])
+    hq_env.command(["cancel", "1"])
+    time.sleep(1.5)
+    wait_for_job_state(hq_env, 1, "CANCELED")
+
+    hq_env.command(
+        ["submit", "--after=foo", "--", "hostname"],
+        expect_fail="Start delay has to be a duration",
+    )
+
+
+def test_forget_jobs(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=1)
+    hq_env.command(["submit", "--array=1-3", "hostname"])
+    hq_env.command(["submit", "/invalid"])
+    hq_env.command(["submit", "sleep", "100"])
+    wait_for_job_state(hq_env, [1, 2], ["FINISHED", "FAILED"])
+
+    output = hq_env.command(["forget", "3"])
+    assert "Forgetting job 3 failed; the job has not been completed yet" in output
+    output = hq_env.command(["forget", "1"])
+    assert "1 job(s) with 3 task(s) were forgotten" in output
+    assert "Job 1 not found" in hq_env.command(["job", "1"])
+
+    output = hq_env.command(["forget", "--all-finished"])
+    assert "1 job(s) with 1 task(s) were forgotten" in output
+    table = hq_env.command(["jobs"], as_table=True)
+    assert len(table) == 2
+    assert table[1][0] == "3"
+
+    hq_env.command(
+        ["forget", "1", "--all-finished"],
+        expect_fail="Job selector cannot be used together with --all-finished",
+    )
+
+
 def test_reporting_state_after_worker_lost(hq_env: HqEnv):
     hq_env.start_server()
     hq_env.start_workers(2, cpus=1)
@@ -541,6 +771,41 @@ def test_job_resubmit_all(hq_env: HqEnv):
     table.check_value_row("Tasks", "3; Ids: 2, 7, 9")
 
 
+def test_job_resubmit_keeps_submit_dir(hq_env: HqEnv):
+    hq_env.start_server()
+    submit_dir = os.path.join(hq_env.work_path, "sub")
+    os.mkdir(submit_dir)
+    hq_env.command(
+        ["submit", "--", "bash", "-c", "echo $HQ_SUBMIT_DIR"], cwd=submit_dir
+    )
+    hq_env.start_worker()
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    hq_env.command(["resubmit", "1"])
+    wait_for_job_state(hq_env, 2, "FINISHED")
+    with open(os.path.join(submit_dir, "job-2", "stdout.0")) as f:
+        assert f.read().strip() == submit_dir
+
+
+def test_job_requeue(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(["submit", "--array=1-3", "--", "sleep", "1"])
+    hq_env.start_worker(cpus=1)
+    wait_for_job_state(hq_env, 1, "RUNNING")
+
+    table = hq_env.command(["requeue", "1"], as_table=True)
+    table.check_value_row("Id", "2")
+    table.check_value_row("Tasks", "3; Ids: 1-3")
+
+    wait_for_job_state(hq_env, 1, "CANCELED")
+    wait_for_job_state(hq_env, 2, "FINISHED")
+
+
+def test_job_requeue_invalid_job(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(["requeue", "1"], expect_fail="Invalid job_id")
+
+
 def test_job_priority(hq_env: HqEnv, tmp_path):
     hq_env.start_server()
     hq_env.command(
@@ -602,6 +867,19 @@ def test_job_priority(hq_env: HqEnv, tmp_path):
     assert dates[0] < dates[3]
 
 
+def test_job_priority_range(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(["submit", "--priority", "-5", "--", "hostname"])
+    hq_env.command(
+        ["submit", "--priority", "3000000000", "--", "hostname"],
+        expect_fail="number too large to fit in target type",
+    )
+    hq_env.command(
+        ["submit", "--priority", "-3000000000", "--", "hostname"],
+        expect_fail="number too small to fit in target type",
+    )
+
+
 def test_job_tasks_table(hq_env: HqEnv):
     hq_env.start_server()
 
@@ -636,6 +914,50 @@ def test_job_wait(hq_env: HqEnv):
     assert "There are no jobs to wait for" in r
 
 
+def test_job_wait_tasks(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=4)
+    hq_env.command(
+        [
+            "submit",
+            "--array=0-2",
+            "--",
+            "bash",
+            "-c",
+            "if [ $HQ_TASK_ID = 0 ]; then sleep 100; fi; exit $(($HQ_TASK_ID == 2))",
+        ]
+    )
+    hq_env.command(["wait", "1", "--task=1"])
+    table = hq_env.command(["job", "1", "--tasks"], as_table=True)[JOB_TABLE_ROWS:]
+    table.check_value_column("State", 0, "RUNNING")
+
+    hq_env.command(
+        ["wait", "last", "--task=1-2"], expect_fail="Task(s) 2 of job 1 failed"
+    )
+    hq_env.command(
+        ["wait", "1", "--task=5"],
+        expect_fail="Job 1 does not contain all of the selected tasks",
+    )
+
+
+def test_job_metadata(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(
+        ["submit", "--metadata=stage=reduce", "--metadata", "run=4=2", "hostname"]
+    )
+    hq_env.command(["submit", "hostname"])
+
+    table = hq_env.command(["job", "1"], as_table=True)
+    table.check_value_row("Metadata", "run=4=2\nstage=reduce")
+    table = hq_env.command(["job", "2"], as_table=True)
+    table.check_value_row("Metadata", "")
+
+    hq_env.command(
+        ["submit", "--metadata=stage", "hostname"],
+        expect_fail="Metadata have to be in the form KEY=VALUE",
+    )
+
+
 def test_job_submit_wait(hq_env: HqEnv):
     hq_env.start_server()
     hq_env.start_worker()
@@ -723,3 +1045,103 @@ def test_job_timeout(hq_env: HqEnv):
     wait_for_job_state(hq_env, 3, "FINISHED")
     table = hq_env.command(["job", "3"], as_table=True)
     assert table.get_row_value("Makespan").startswith("2")
+
+
+def test_job_cpu_time_limit(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=2)
+
+    hq_env.command(
+        ["submit", "--cpu-time-limit=1s", "--", "python3", "-c", "while True: pass"]
+    )
+    # A sleeping task consumes almost no CPU time
+    hq_env.command(["submit", "--cpu-time-limit=1s", "--", "sleep", "3"])
+
+    table = hq_env.command(["job", "1"], as_table=True)
+    table.check_value_row("Task CPU time limit", "1s")
+    table.check_value_row("Task time limit", "None")
+
+    wait_for_job_state(hq_env, 1, "FAILED")
+    table = hq_env.command(["job", "1"], as_table=True)
+    assert table[JOB_TABLE_ROWS + 1][2].startswith("CPU time limit reached")
+
+    wait_for_job_state(hq_env, 2, "FINISHED")
+
+
+def test_job_cpu_time_limit_child_processes(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=1)
+
+    # The CPU time is consumed by a child process of the task
+    hq_env.command(
+        [
+            "submit",
+            "--cpu-time-limit=1s",
+            "--",
+            "bash",
+            "-c",
+            "python3 -c 'while True: pass' & echo $! > pid; wait",
+        ]
+    )
+    wait_for_job_state(hq_env, 1, "FAILED")
+    table = hq_env.command(["job", "1"], as_table=True)
+    assert table[JOB_TABLE_ROWS + 1][2].startswith("CPU time limit reached")
+
+    with open(os.path.join(hq_env.work_path, "pid")) as f:
+        pid = int(f.read())
+    wait_until(lambda: not os.path.exists(f"/proc/{pid}"))
+
+
+def test_job_cpu_time_limit_cancel_kills_group(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=1)
+
+    hq_env.command(
+        [
+            "submit",
+            "--cpu-time-limit=1h",
+            "--",
+            "bash",
+            "-c",
+            "sleep 100 & echo $! > pid; wait",
+        ]
+    )
+    pid_path = os.path.join(hq_env.work_path, "pid")
+    wait_until(lambda: os.path.isfile(pid_path) and os.path.getsize(pid_path) > 0)
+    with open(pid_path) as f:
+        pid = int(f.read())
+
+    hq_env.command(["cancel", "1"])
+    wait_for_job_state(hq_env, 1, "CANCELED")
+    wait_until(lambda: not os.path.exists(f"/proc/{pid}"))
+
+
+def test_job_task_wait_time(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(["submit", "--array=1-4", "--", "hostname"])
+    table = hq_env.command(["job", "1"], as_table=True)
+    table.check_value_row("Task wait time", "N/A")
+
+    time.sleep(1)
+    hq_env.start_worker(cpus=4)
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    wait_time = hq_env.command(["job", "1"], as_table=True).get_row_value(
+        "Task wait time"
+    )
+    assert wait_time.startswith("p50: 1s")
+    assert wait_time.endswith("(4 started task(s))")
+
+
+def test_job_task_wait_time_after_delay(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker()
+    hq_env.command(["submit", "--after=2s", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    # The start delay is not counted into the wait time
+    wait_time = hq_env.command(["job", "1"], as_table=True).get_row_value(
+        "Task wait time"
+    )
+    p50 = wait_time.split(",")[0]
+    assert p50.endswith("ms") or p50 == "p50: 0s"
@@ -2,12 +2,14 @@ import json
 import os
 import signal
 import socket
+import sqlite3
 import subprocess
+import time
 
 import pytest
 
 from .conftest import HqEnv
-from .utils import parse_table
+from .utils import parse_table, wait_for_job_state
 
 
 def test_server_host(hq_env: HqEnv):
@@ -56,8 +58,9 @@ def test_server_info(hq_env: HqEnv):
     table.check_value_row("Server directory", hq_env.server_dir)
     table.check_value_row("Host", socket.gethostname())
     table.check_value_row("Pid", str(process.pid))
+    table.check_value_row("Default resources", "cpus=1 compact")
 
-    assert len(table) == 7
+    assert len(table) == 8
 
 
 def test_server_stop(hq_env: HqEnv):
@@ -91,3 +94,105 @@ def test_delete_symlink_after_ctrl_c(hq_env: HqEnv):
     hq_env.check_process_exited(process, 0)
 
     assert not os.path.isdir(os.path.join(hq_env.server_dir, "hq-current"))
+
+
+def test_server_job_history(hq_env: HqEnv):
+    process = hq_env.start_server(args=["--job-history"])
+    hq_env.start_worker()
+    hq_env.command(["submit", "--name=history-job", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+    hq_env.command(["server", "stop"])
+    process.wait()
+    hq_env.kill_process("server")
+
+    hq_env.start_server(args=["--job-history"])
+    table = hq_env.command(["job", "1"], as_table=True)
+    table.check_value_row("Name", "history-job")
+    table.check_value_row("State", "FINISHED")
+
+
+def test_server_job_history_retention(hq_env: HqEnv):
+    process = hq_env.start_server(args=["--job-history-max-count=1"])
+    hq_env.start_worker()
+    hq_env.command(["submit", "--", "hostname"])
+    hq_env.command(["submit", "--", "hostname"])
+    wait_for_job_state(hq_env, [1, 2], "FINISHED")
+    hq_env.command(["server", "stop"])
+    process.wait()
+    hq_env.kill_process("server")
+
+    hq_env.start_server(args=["--job-history-max-count=1"])
+    assert "Job 1 not found" in hq_env.command(["job", "1"])
+    table = hq_env.command(["job", "2"], as_table=True)
+    table.check_value_row("State", "FINISHED")
+
+
+def test_server_json_log_format(hq_env: HqEnv):
+    hq_env.start_server(args=["--log-format", "json"])
+    hq_env.start_worker()
+    hq_env.command(["submit", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    with open(os.path.join(hq_env.work_path, "server.out")) as f:
+        records = [json.loads(line) for line in f if line.startswith("{")]
+    assert records
+    assert all("message" in record and "level" in record for record in records)
+    assert any(
+        record.get("job_id") == 1
+        and record.get("task_id") == 0
+        and "worker_id" in record
+        for record in records
+    )
+
+
+def test_server_snapshot_db(hq_env: HqEnv):
+    db_path = os.path.join(hq_env.work_path, "snapshots.db")
+    hq_env.start_server(args=["--snapshot-db", db_path, "--snapshot-interval", "100ms"])
+    hq_env.start_worker(cpus=2)
+    hq_env.command(["submit", "--array=1-4", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+    time.sleep(0.5)
+
+    connection = sqlite3.connect(db_path)
+    (snapshot_id,) = connection.execute("SELECT MAX(id) FROM snapshots").fetchone()
+    assert snapshot_id > 1
+    jobs = connection.execute(
+        "SELECT job_id, state, n_tasks, n_finished FROM jobs WHERE snapshot_id = ?",
+        (snapshot_id,),
+    ).fetchall()
+    assert jobs == [(1, "FINISHED", 4, 4)]
+    workers = connection.execute(
+        "SELECT worker_id, online, n_cpus FROM workers WHERE snapshot_id = ?",
+        (snapshot_id,),
+    ).fetchall()
+    assert workers == [(1, 1, 2)]
+
+
+def test_server_reject_oversized_message(hq_env: HqEnv):
+    hq_env.start_server(args=["--max-message-size", "2048"])
+
+    hq_env.command(
+        ["submit", "--", "echo", "a" * 4096],
+        expect_fail="exceeds the maximum message size (2048 bytes)",
+    )
+    # The server stays available for other clients
+    hq_env.command(["submit", "--", "hostname"])
+    table = hq_env.command(["jobs"], as_table=True)
+    assert len(table) == 2
+
+
+def test_server_survives_oversized_frame(hq_env: HqEnv):
+    hq_env.start_server()
+    access_file = os.path.join(hq_env.server_dir, "hq-current", "access.json")
+    with open(access_file) as f:
+        port = json.load(f)["server_port"]
+
+    # Announce a frame larger than the default limit
+    with socket.create_connection(("localhost", port)) as connection:
+        connection.sendall((2 ** 32 - 1).to_bytes(4, "little"))
+        connection.settimeout(5)
+        while connection.recv(1024):
+            pass
+
+    table = hq_env.command(["server", "info"], as_table=True)
+    table.check_value_row("Server directory", hq_env.server_dir)
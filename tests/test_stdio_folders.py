@@ -3,3 +3,46 @@ from .conftest import HqEnv
 from .utils import wait_for_job_state
 
 
+
+
+def test_create_output_directories(hq_env: HqEnv, tmp_path):
+    hq_env.start_server()
+    hq_env.start_worker()
+    hq_env.command(
+        [
+            "submit",
+            "--array=1-2",
+            "--stdout=out/%{JOB_ID}/%{TASK_ID}.out",
+            "--stderr=err/%{TASK_ID}/task.err",
+            "--",
+            "bash",
+            "-c",
+            "echo hello",
+        ]
+    )
+    wait_for_job_state(hq_env, 1, "FINISHED")
+    for task_id in (1, 2):
+        with open(path.join(tmp_path, "out", "1", f"{task_id}.out")) as f:
+            assert f.read() == "hello\n"
+        assert path.isfile(path.join(tmp_path, "err", str(task_id), "task.err"))
+
+
+def test_create_output_directories_error(hq_env: HqEnv, tmp_path):
+    hq_env.start_server()
+    hq_env.start_worker()
+    # A file blocks the creation of the directory
+    with open(path.join(tmp_path, "blocker"), "w") as f:
+        f.write("")
+    hq_env.command(["submit", "--stdout=blocker/out", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "FAILED")
+    output = hq_env.command(["job", "1"])
+    assert "Cannot create directory" in output
+    assert "blocker" in output
+
+
+def test_no_create_output_directories(hq_env: HqEnv, tmp_path):
+    hq_env.start_server()
+    hq_env.start_worker(args=["--no-create-output-dirs"])
+    hq_env.command(["submit", "--stdout=missing/out", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "FAILED")
+    assert not path.exists(path.join(tmp_path, "missing"))
@@ -90,6 +90,85 @@ def test_stream_overlap(hq_env: HqEnv):
     check_no_stream_connections(hq_env)
 
 
+def test_stream_merge_stderr(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(
+        [
+            "submit",
+            "--log",
+            "mylog",
+            "--merge-stderr",
+            "--",
+            "bash",
+            "-c",
+            "for i in 1 2 3; do echo out$i; echo err$i >&2; done",
+        ]
+    )
+    hq_env.start_worker()
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    result = hq_env.command(["log", "mylog", "cat", "stdout"])
+    assert result == "out1\nerr1\nout2\nerr2\nout3\nerr3\n"
+    result = hq_env.command(["log", "mylog", "show", "--channel=stderr"])
+    assert result == ""
+    check_no_stream_connections(hq_env)
+
+
+def test_stream_show_task(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(
+        [
+            "submit",
+            "--log",
+            "mylog",
+            "--array=1-3",
+            "--",
+            "bash",
+            "-c",
+            "echo A${HQ_TASK_ID}; sleep 1; echo B${HQ_TASK_ID}",
+        ]
+    )
+    hq_env.start_workers(1, cpus="3")
+    wait_for_job_state(hq_env, 1, "RUNNING")
+    time.sleep(0.3)
+
+    result = hq_env.command(
+        ["log", "mylog", "show", "--task=2", "--follow"], as_lines=True
+    )
+    assert result == ["2:0> A2", "2:0> B2", "2: > stream closed"]
+
+    wait_for_job_state(hq_env, 1, "FINISHED")
+    result = set(
+        hq_env.command(["log", "mylog", "show", "--task=1,3"], as_lines=True)
+    )
+    assert result == {
+        "1:0> A1",
+        "1:0> B1",
+        "1: > stream closed",
+        "3:0> A3",
+        "3:0> B3",
+        "3: > stream closed",
+    }
+
+    hq_env.command(
+        ["log", "mylog", "show", "--follow"],
+        expect_fail="--follow can be used only together with --task",
+    )
+
+
+def test_stream_follow_task_without_stream(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(["submit", "--log", "mylog", "--", "echo", "hello"])
+    hq_env.start_workers(1)
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    # Task 5 has never opened a stream, the command must not wait for it
+    result = hq_env.command(
+        ["log", "mylog", "show", "--task=0,5", "--follow"], as_lines=True
+    )
+    assert result == ["0:0> hello", "0: > stream closed"]
+
+
 def test_stream_big_output(hq_env: HqEnv):
     hq_env.start_server()
     hq_env.command(
@@ -411,4 +490,54 @@ def test_stream_timeout(hq_env: HqEnv):
     result = hq_env.command(["log", "mylog", "show"])
     assert result == "0:0> Start\n0: > stream closed\n"
 
-    check_no_stream_connections(hq_env)
\ No newline at end of file
+    check_no_stream_connections(hq_env)
+
+def test_stream_output_flush_line(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(
+        [
+            "submit",
+            "--log",
+            "mylog",
+            "--output-flush",
+            "line",
+            "--",
+            "bash",
+            "-c",
+            "printf ab; sleep 1; echo c; printf d",
+        ]
+    )
+    hq_env.start_workers(1)
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    lines = hq_env.command(["log", "mylog", "show"], as_lines=True)
+    assert "0:0> abc" in lines
+    result = hq_env.command(["log", "mylog", "cat", "stdout"])
+    assert result == "abc\nd"
+
+
+def test_stream_output_flush_block(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(
+        [
+            "submit",
+            "--log",
+            "mylog",
+            "--output-flush",
+            "block",
+            "--",
+            "python3",
+            "-c",
+            "print('1234567890' * 80_000)",
+        ]
+    )
+    hq_env.start_workers(1)
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    result = hq_env.command(["log", "mylog", "cat", "stdout"])
+    assert result == "1234567890" * 80_000 + "\n"
+
+
+def test_stream_output_flush_requires_log(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(["submit", "--output-flush", "line", "--", "hostname"], expect_fail="")
@@ -1,8 +1,9 @@
+import os
 import time
 from socket import gethostname
 
 from .conftest import HqEnv
-from .utils import wait_for_worker_state
+from .utils import wait_for_job_state, wait_for_worker_state
 
 
 def test_worker_list(hq_env: HqEnv):
@@ -85,6 +86,22 @@ def test_worker_stop_all(hq_env: HqEnv):
         hq_env.check_process_exited(process)
 
 
+def test_worker_stop_all_idle(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_workers(3, cpus=1)
+    wait_for_worker_state(hq_env, [1, 2, 3], "RUNNING")
+
+    hq_env.command(["submit", "sleep", "2"])
+    wait_for_job_state(hq_env, 1, "RUNNING")
+
+    r = hq_env.command(["worker", "stop", "--all-idle"])
+    assert "2 idle worker(s) stopped" in r
+
+    table = hq_env.command(["worker", "list", "--running"], as_table=True)
+    assert len(table) == 2
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+
 def test_worker_list_online_offline_state(hq_env: HqEnv):
     hq_env.start_server()
     hq_env.start_workers(2)
@@ -129,6 +146,19 @@ def test_worker_list_resources(hq_env: HqEnv):
     )
 
 
+def test_worker_list_all_resources(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus="2x3")
+
+    wait_for_worker_state(hq_env, 1, "RUNNING")
+
+    table = hq_env.command(["worker", "list", "--resources"], as_table=True)
+    assert len(table) == 2
+    table.check_value_column(
+        "Resources", 0, "2x3 cpus\nsocket 0: 0-2\nsocket 1: 3-5"
+    )
+
+
 def test_idle_timeout_server_cfg(hq_env: HqEnv):
     hq_env.start_server(args=["--idle-timeout", "1s"])
     w = hq_env.start_worker(args=["--heartbeat", "500ms"])
@@ -168,6 +198,13 @@ def test_worker_info(hq_env: HqEnv):
     table.check_value_row("Heartbeat", "10s")
     table.check_value_row("Resources", "1x10 cpus")
     table.check_value_row("Manager", "None")
+    table.check_value_row("State", "RUNNING")
+    table.check_value_row("Running tasks", "0")
+
+    hq_env.command(["submit", "--", "sleep", "2"])
+    wait_for_job_state(hq_env, 1, "RUNNING")
+    table = hq_env.command(["worker", "info", "1"], as_table=True)
+    table.check_value_row("Running tasks", "1\njob 1: 0")
 
 
 def test_worker_address(hq_env: HqEnv):
@@ -176,3 +213,36 @@ def test_worker_address(hq_env: HqEnv):
 
     output = hq_env.command(["worker", "address", "1"]).strip()
     assert output == gethostname()
+
+
+def test_worker_max_parallel_launches(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=4, args=["--max-parallel-launches=1"])
+    hq_env.command(["submit", "--array=1-8", "--", "sleep", "0.5"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    table = hq_env.command(["job", "1"], as_table=True)
+    assert table.get_row_value("State").split("\n")[-1] == "FINISHED (8)"
+
+
+def test_worker_max_parallel_launches_throttles_starts(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=4, args=["--max-parallel-launches=1"])
+    hq_env.command(
+        ["submit", "--array=0-3", "--", "bash", "-c", "date +%s.%N; sleep 1"]
+    )
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    starts = []
+    for i in range(4):
+        with open(os.path.join(hq_env.work_path, f"job-1/stdout.{i}")) as f:
+            starts.append(float(f.read()))
+    # The tasks would all start at once without the limit
+    assert max(starts) - min(starts) >= 0.5
+
+
+def test_worker_max_parallel_launches_zero(hq_env: HqEnv):
+    hq_env.start_server()
+    process = hq_env.start_worker(args=["--max-parallel-launches=0"])
+    process.wait()
+    hq_env.check_process_exited(process, expected_code=1)
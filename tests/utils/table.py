@@ -1,6 +1,6 @@
 from typing import Optional, List
 
-JOB_TABLE_ROWS = 15
+JOB_TABLE_ROWS = 18
 
 
 # TODO: create a pandas dataframe instead?